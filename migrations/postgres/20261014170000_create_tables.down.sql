@@ -0,0 +1,7 @@
+DROP TABLE IF EXISTS setpoint_history;
+DROP TABLE IF EXISTS power_history;
+DROP TABLE IF EXISTS dead_letters;
+DROP TABLE IF EXISTS schedule;
+DROP TABLE IF EXISTS heaters;
+DROP TABLE IF EXISTS heater_history;
+DROP TABLE IF EXISTS history;
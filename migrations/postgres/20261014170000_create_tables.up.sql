@@ -0,0 +1,61 @@
+CREATE TABLE IF NOT EXISTS history (
+    timestamp TIMESTAMP NOT NULL,
+    location TEXT NOT NULL,
+    temperature DOUBLE PRECISION NOT NULL,
+    humidity DOUBLE PRECISION NOT NULL,
+    battery DOUBLE PRECISION
+);
+CREATE INDEX IF NOT EXISTS history_timestamp_index ON history (timestamp);
+
+CREATE TABLE IF NOT EXISTS heater_history (
+    timestamp TIMESTAMP NOT NULL,
+    shelly_id TEXT NOT NULL,
+    is_active BOOLEAN NOT NULL
+);
+CREATE INDEX IF NOT EXISTS heater_history_timestamp_index ON heater_history (
+    timestamp
+);
+
+CREATE TABLE IF NOT EXISTS heaters (
+    position SERIAL NOT NULL,
+    id TEXT PRIMARY KEY NOT NULL,
+    name TEXT NOT NULL,
+    place TEXT NOT NULL DEFAULT 'inside'
+);
+
+INSERT INTO heaters (id, name) VALUES
+('C4402D', 'Spisebord'),
+('C431FB', 'Sofa'),
+('10DB9C', 'Soveværelse');
+
+CREATE TABLE IF NOT EXISTS schedule (
+    time_of_day TIME PRIMARY KEY NOT NULL,
+    desired_temperature DOUBLE PRECISION NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS dead_letters (
+    id BIGSERIAL PRIMARY KEY,
+    timestamp TIMESTAMP NOT NULL,
+    topic TEXT NOT NULL,
+    payload TEXT NOT NULL,
+    error TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS power_history (
+    timestamp TIMESTAMP NOT NULL,
+    shelly_id TEXT NOT NULL,
+    power DOUBLE PRECISION NOT NULL
+);
+CREATE INDEX IF NOT EXISTS power_history_timestamp_index ON power_history (
+    timestamp
+);
+
+CREATE TABLE IF NOT EXISTS setpoint_history (
+    id BIGSERIAL PRIMARY KEY,
+    timestamp TIMESTAMP NOT NULL,
+    temperature DOUBLE PRECISION NOT NULL,
+    source TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS setpoint_history_timestamp_index ON setpoint_history (
+    timestamp
+);
@@ -0,0 +1,10 @@
+CREATE TABLE IF NOT EXISTS energy_history (
+    id BIGSERIAL PRIMARY KEY,
+    timestamp TIMESTAMP NOT NULL,
+    shelly_id TEXT NOT NULL,
+    counter DOUBLE PRECISION NOT NULL,
+    energy DOUBLE PRECISION NOT NULL
+);
+CREATE INDEX IF NOT EXISTS energy_history_shelly_id_timestamp_index ON energy_history (
+    shelly_id, timestamp
+);
@@ -0,0 +1 @@
+ALTER TABLE heaters ADD COLUMN firmware TEXT NOT NULL DEFAULT 'gen1';
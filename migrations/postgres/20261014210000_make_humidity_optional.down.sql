@@ -0,0 +1,2 @@
+UPDATE history SET humidity = 0.0 WHERE humidity IS NULL;
+ALTER TABLE history ALTER COLUMN humidity SET NOT NULL;
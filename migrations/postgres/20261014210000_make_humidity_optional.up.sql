@@ -0,0 +1 @@
+ALTER TABLE history ALTER COLUMN humidity DROP NOT NULL;
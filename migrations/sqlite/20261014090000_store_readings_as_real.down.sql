@@ -0,0 +1,16 @@
+CREATE TABLE history_old (
+    timestamp DATETIME NOT NULL,
+    location TEXT NOT NULL,
+    temperature TINYINT NOT NULL,
+    humidity TINYINT NOT NULL
+);
+INSERT INTO history_old (timestamp, location, temperature, humidity)
+SELECT
+    timestamp,
+    location,
+    CAST(temperature AS INTEGER),
+    CAST(humidity AS INTEGER)
+FROM history;
+DROP TABLE history;
+ALTER TABLE history_old RENAME TO history;
+CREATE INDEX IF NOT EXISTS history_timestamp_index ON history (timestamp);
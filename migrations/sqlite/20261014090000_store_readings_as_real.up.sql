@@ -0,0 +1,16 @@
+CREATE TABLE history_new (
+    timestamp DATETIME NOT NULL,
+    location TEXT NOT NULL,
+    temperature REAL NOT NULL,
+    humidity REAL NOT NULL
+);
+INSERT INTO history_new (timestamp, location, temperature, humidity)
+SELECT
+    timestamp,
+    location,
+    temperature,
+    humidity
+FROM history;
+DROP TABLE history;
+ALTER TABLE history_new RENAME TO history;
+CREATE INDEX IF NOT EXISTS history_timestamp_index ON history (timestamp);
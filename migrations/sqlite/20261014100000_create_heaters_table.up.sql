@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS heaters (
+    id TEXT PRIMARY KEY NOT NULL,
+    name TEXT NOT NULL
+);
+
+INSERT INTO heaters (id, name) VALUES
+('C4402D', 'Spisebord'),
+('C431FB', 'Sofa'),
+('10DB9C', 'Soveværelse');
@@ -0,0 +1 @@
+ALTER TABLE heaters DROP COLUMN place;
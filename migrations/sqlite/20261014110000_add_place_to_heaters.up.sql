@@ -0,0 +1 @@
+ALTER TABLE heaters ADD COLUMN place TEXT NOT NULL DEFAULT 'inside';
@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS schedule (
+    time_of_day TIME PRIMARY KEY NOT NULL,
+    desired_temperature REAL NOT NULL
+);
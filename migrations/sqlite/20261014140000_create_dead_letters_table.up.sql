@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS dead_letters (
+    timestamp DATETIME NOT NULL,
+    topic TEXT NOT NULL,
+    payload TEXT NOT NULL,
+    error TEXT NOT NULL
+);
@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS power_history (
+    timestamp DATETIME NOT NULL,
+    shelly_id TEXT NOT NULL,
+    power REAL NOT NULL
+);
+CREATE INDEX IF NOT EXISTS power_history_timestamp_index ON power_history (
+    timestamp
+);
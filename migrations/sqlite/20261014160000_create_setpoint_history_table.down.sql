@@ -0,0 +1 @@
+DROP TABLE setpoint_history;
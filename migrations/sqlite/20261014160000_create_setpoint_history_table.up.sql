@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS setpoint_history (
+    timestamp DATETIME NOT NULL,
+    temperature REAL NOT NULL,
+    source TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS setpoint_history_timestamp_index ON setpoint_history (
+    timestamp
+);
@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS energy_history (
+    timestamp DATETIME NOT NULL,
+    shelly_id TEXT NOT NULL,
+    counter REAL NOT NULL,
+    energy REAL NOT NULL
+);
+CREATE INDEX IF NOT EXISTS energy_history_shelly_id_timestamp_index ON energy_history (
+    shelly_id, timestamp
+);
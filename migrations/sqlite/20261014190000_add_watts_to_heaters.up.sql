@@ -0,0 +1 @@
+ALTER TABLE heaters ADD COLUMN watts REAL;
@@ -0,0 +1,18 @@
+CREATE TABLE history_old (
+    timestamp DATETIME NOT NULL,
+    location TEXT NOT NULL,
+    temperature REAL NOT NULL,
+    humidity REAL NOT NULL,
+    battery REAL
+);
+INSERT INTO history_old (timestamp, location, temperature, humidity, battery)
+SELECT
+    timestamp,
+    location,
+    temperature,
+    COALESCE(humidity, 0.0),
+    battery
+FROM history;
+DROP TABLE history;
+ALTER TABLE history_old RENAME TO history;
+CREATE INDEX IF NOT EXISTS history_timestamp_index ON history (timestamp);
@@ -0,0 +1,18 @@
+CREATE TABLE history_new (
+    timestamp DATETIME NOT NULL,
+    location TEXT NOT NULL,
+    temperature REAL NOT NULL,
+    humidity REAL,
+    battery REAL
+);
+INSERT INTO history_new (timestamp, location, temperature, humidity, battery)
+SELECT
+    timestamp,
+    location,
+    temperature,
+    humidity,
+    battery
+FROM history;
+DROP TABLE history;
+ALTER TABLE history_new RENAME TO history;
+CREATE INDEX IF NOT EXISTS history_timestamp_index ON history (timestamp);
@@ -0,0 +1,1367 @@
+use std::{collections::HashMap, convert::Infallible, net::SocketAddr, sync::Arc, time::Duration};
+
+use anyhow::{Context, Result};
+use axum::{
+    body::Body,
+    extract::{Query, Request, State},
+    http::{header, StatusCode},
+    middleware::{self, Next},
+    response::{
+        sse::{self, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    routing::{get, post},
+    Json, Router,
+};
+use bytes::Bytes;
+use chrono::{DateTime, Days, Local, NaiveDate, NaiveDateTime, Utc};
+use chrono_tz::Tz;
+use futures_util::{Stream, StreamExt};
+use tokio::{
+    sync::{
+        broadcast::{self, error::RecvError},
+        mpsc::{self, WeakSender},
+        Mutex,
+    },
+    time::Instant,
+};
+
+use crate::{
+    controller::{
+        Action, ChannelMetrics, ConnectionHealth, DatabaseHealth, ErrorHistogram, Event, Histogram,
+        SharedState, StateSnapshot, INSIDE, MAX_DESIRED_TEMPERATURE, MIN_DESIRED_TEMPERATURE,
+    },
+    db::{
+        self, DailyAggregate, Database, HourlyAggregate, SetpointChange,
+        TemperatureMeasurementRecord,
+    },
+    error::HubError,
+    models::{HeaterState, SetpointSource},
+};
+
+const DEFAULT_HTTP_ADDRESS: &str = "0.0.0.0:8080";
+
+/// How recently the MQTT eventloop must have polled successfully for the hub
+/// to be considered healthy.
+const MAX_POLL_AGE: Duration = Duration::from_secs(30);
+
+const DEFAULT_HISTORY_HOURS: u32 = 24;
+const MAX_HISTORY_HOURS: u32 = 24 * 31;
+const DEFAULT_HISTORY_DAYS: u32 = 7;
+const MAX_HISTORY_DAYS: u32 = 31;
+
+/// Number of CSV rows buffered ahead of the client when exporting history.
+const EXPORT_BUFFER_ROWS: usize = 64;
+
+/// Configuration of the HTTP API. The API token is redacted from the `Debug`
+/// output, so it never ends up in traces.
+#[derive(Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct HttpConfig {
+    /// Address to serve the HTTP API on.
+    pub address: SocketAddr,
+    /// Serve the `/admin` routes, which write to the database directly. Only
+    /// meant for testing.
+    pub enable_admin: bool,
+    /// Bearer token required by the routes changing the state of the hub.
+    /// Without a token they are open to anyone reaching the API.
+    #[serde(skip_serializing)]
+    pub api_token: Option<String>,
+}
+
+impl std::fmt::Debug for HttpConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HttpConfig")
+            .field("address", &self.address)
+            .field("enable_admin", &self.enable_admin)
+            .field("api_token", &self.api_token.as_ref().map(|_| "<redacted>"))
+            .finish()
+    }
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            address: DEFAULT_HTTP_ADDRESS
+                .parse()
+                .expect("invalid default address"),
+            enable_admin: false,
+            api_token: None,
+        }
+    }
+}
+
+impl HttpConfig {
+    /// Override the configuration with the `HTTP_ADDRESS` and `HTTP_API_TOKEN`
+    /// environment variables found by `lookup`.
+    pub fn with_env_overrides(self, lookup: &impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let address = lookup("HTTP_ADDRESS")
+            .map(|address| {
+                address
+                    .parse()
+                    .context("HTTP_ADDRESS is not a valid socket address")
+            })
+            .transpose()?;
+
+        Ok(Self {
+            address: address.unwrap_or(self.address),
+            api_token: lookup("HTTP_API_TOKEN").or(self.api_token),
+            ..self
+        })
+    }
+}
+
+/// State shared by the HTTP handlers.
+#[derive(Debug, Clone)]
+pub struct AppState {
+    db: Arc<Mutex<Database>>,
+    mqtt_health: ConnectionHealth,
+    db_health: DatabaseHealth,
+    channel_metrics: ChannelMetrics,
+    /// Sender of actions to the executor, which is gone when shutting down.
+    actions: WeakSender<Action>,
+    controller_state: SharedState,
+    /// Events of the actions handled by the executor.
+    events: broadcast::Sender<Event>,
+    temperature_error: ErrorHistogram,
+    /// Timezone the days start in, or the timezone of the system when not
+    /// set.
+    timezone: Option<Tz>,
+    started_at: Instant,
+}
+
+impl AppState {
+    pub fn new(
+        db: Arc<Mutex<Database>>,
+        mqtt_health: ConnectionHealth,
+        db_health: DatabaseHealth,
+        channel_metrics: ChannelMetrics,
+        actions: WeakSender<Action>,
+        controller_state: SharedState,
+        events: broadcast::Sender<Event>,
+    ) -> Self {
+        Self {
+            db,
+            mqtt_health,
+            db_health,
+            channel_metrics,
+            actions,
+            controller_state,
+            events,
+            temperature_error: ErrorHistogram::default(),
+            timezone: None,
+            started_at: Instant::now(),
+        }
+    }
+
+    /// Report the temperature error observed by the executor in `/metrics`.
+    pub fn with_temperature_error(self, temperature_error: ErrorHistogram) -> Self {
+        Self {
+            temperature_error,
+            ..self
+        }
+    }
+
+    /// Split the history into days in `timezone`, instead of in the timezone
+    /// of the system.
+    pub fn with_timezone(self, timezone: Option<Tz>) -> Self {
+        Self { timezone, ..self }
+    }
+
+    /// The date of today in the timezone of the days.
+    fn today(&self) -> NaiveDate {
+        match self.timezone {
+            Some(timezone) => Utc::now().with_timezone(&timezone).date_naive(),
+            None => Local::now().date_naive(),
+        }
+    }
+
+    /// The time in UTC at which `date` starts in the timezone of the days.
+    fn start_of_day(&self, date: NaiveDate) -> NaiveDateTime {
+        match self.timezone {
+            Some(timezone) => db::start_of_day(date, &timezone),
+            None => db::start_of_day(date, &Local),
+        }
+    }
+}
+
+/// Create the router with all the routes of the HTTP API, including the
+/// admin routes when they are enabled. The routes changing the state of the
+/// hub require the API token, if one is configured.
+pub fn router(state: AppState, config: &HttpConfig) -> Router {
+    let mutating = Router::new()
+        .route("/desired-temperature", post(set_desired_temperature))
+        .route("/reevaluate", post(reevaluate));
+    let mutating = if config.enable_admin {
+        mutating.route("/admin/reading", post(insert_reading))
+    } else {
+        mutating
+    };
+    let mutating = match &config.api_token {
+        Some(token) => mutating.route_layer(middleware::from_fn_with_state(
+            Arc::<str>::from(token.as_str()),
+            require_token,
+        )),
+        None => mutating,
+    };
+
+    Router::new()
+        .route("/health", get(health))
+        .route("/status", get(status))
+        .route("/history", get(history))
+        .route("/history/hourly", get(hourly_history))
+        .route("/history/daily", get(daily_history))
+        .route("/setpoint-history", get(setpoint_history))
+        .route("/export/history.csv", get(export_history))
+        .route("/metrics", get(metrics))
+        .route("/state", get(controller_state))
+        .route("/heaters", get(heaters))
+        .route("/events", get(events))
+        .merge(mutating)
+        .with_state(state)
+}
+
+/// Reject requests without an `Authorization: Bearer <token>` header with the
+/// API token.
+async fn require_token(State(token): State<Arc<str>>, request: Request, next: Next) -> Response {
+    let authorized = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|given| constant_time_eq(given.as_bytes(), token.as_bytes()));
+    if !authorized {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+
+    next.run(request).await
+}
+
+/// Compare two byte strings in time independent of where they differ, so the
+/// token cannot be guessed from how long a comparison takes.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Serve the HTTP API on the given address until completion.
+pub async fn serve(config: HttpConfig, state: AppState) -> Result<()> {
+    let address = config.address;
+    let listener = tokio::net::TcpListener::bind(address)
+        .await
+        .with_context(|| format!("Failed to bind HTTP server to {address}"))?;
+    tracing::info!(%address, "Serving HTTP API");
+
+    if config.enable_admin {
+        tracing::warn!("Serving the admin routes of the HTTP API");
+    }
+    if config.api_token.is_none() {
+        tracing::warn!("No API token is configured, anyone can change the state of the hub");
+    }
+    axum::serve(listener, router(state, &config))
+        .await
+        .context("HTTP server failed")
+}
+
+/// Liveness probe. Healthy when the MQTT eventloop has polled successfully
+/// recently and the database can be reached.
+#[tracing::instrument(skip(state))]
+async fn health(State(state): State<AppState>) -> StatusCode {
+    let mqtt_healthy = state.mqtt_health.polled_within(MAX_POLL_AGE);
+    let db_healthy = match state.db.lock().await.ping().await {
+        Ok(()) => true,
+        Err(e) => {
+            tracing::warn!(error = %e, "Database is unreachable");
+            false
+        }
+    };
+
+    if mqtt_healthy && db_healthy {
+        StatusCode::OK
+    } else {
+        tracing::warn!(mqtt_healthy, db_healthy, "Hub is unhealthy");
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
+
+/// Overview of the hub for operators.
+#[derive(Debug, PartialEq, serde::Serialize)]
+struct Status {
+    uptime_secs: u64,
+    mqtt_connected: bool,
+    /// When the executor last wrote to the database.
+    last_db_write: Option<DateTime<Utc>>,
+    enabled: bool,
+    desired_temperature: Option<f64>,
+    /// The inside temperature.
+    current_temperature: Option<f64>,
+    heater_states: HashMap<String, HeaterState>,
+}
+
+/// Summarize the state of the connections and the controller.
+#[tracing::instrument(skip(state))]
+async fn status(State(state): State<AppState>) -> Json<Status> {
+    let snapshot = state.controller_state.get();
+    Json(Status {
+        uptime_secs: state.started_at.elapsed().as_secs(),
+        mqtt_connected: state.mqtt_health.polled_within(MAX_POLL_AGE),
+        last_db_write: state.db_health.last_write(),
+        enabled: snapshot.enabled,
+        desired_temperature: snapshot.desired_temperature,
+        current_temperature: snapshot.temperatures.get(INSIDE).copied(),
+        heater_states: snapshot.heater_states,
+    })
+}
+
+/// Counters of the actions the controller could not hand to the executor, of
+/// the executor being held up by the database, and of how often each heater
+/// has cycled and how much energy it has used today. The temperature error
+/// shows how far from the desired temperatures the heaters are kept.
+#[derive(Debug, PartialEq, serde::Serialize)]
+struct Metrics {
+    dropped_actions: u64,
+    slow_sends: u64,
+    slow_db_lock_waits: u64,
+    slow_db_operations: u64,
+    /// The number of times each heater turned off since midnight, keyed by
+    /// heater id.
+    heater_cycles_today: HashMap<String, u64>,
+    /// The energy in kWh each heater with a rated power has used since
+    /// midnight, estimated from its runtime.
+    estimated_energy_today_kwh: HashMap<String, f64>,
+    /// How far in °C the temperatures governing the heaters were above their
+    /// desired temperatures when evaluated.
+    temperature_error: Histogram,
+}
+
+#[tracing::instrument(skip(state))]
+async fn metrics(State(state): State<AppState>) -> Result<Json<Metrics>, ApiError> {
+    let midnight = state.start_of_day(state.today());
+    let db = state.db.lock().await;
+    let mut heater_cycles_today = HashMap::new();
+    let mut estimated_energy_today_kwh = HashMap::new();
+    for heater in db.get_heaters().await? {
+        let cycles = db.count_heater_cycles(heater.id(), midnight).await?;
+        heater_cycles_today.insert(heater.id().clone(), cycles);
+        if let Some(energy) = db.estimate_energy(heater.id(), midnight).await? {
+            estimated_energy_today_kwh.insert(heater.id().clone(), energy);
+        }
+    }
+
+    Ok(Json(Metrics {
+        dropped_actions: state.channel_metrics.dropped_actions(),
+        slow_sends: state.channel_metrics.slow_sends(),
+        slow_db_lock_waits: state.db_health.slow_lock_waits(),
+        slow_db_operations: state.db_health.slow_operations(),
+        heater_cycles_today,
+        estimated_energy_today_kwh,
+        temperature_error: state.temperature_error.snapshot(),
+    }))
+}
+
+/// The state of the controller as of the last handled action.
+#[tracing::instrument(skip(state))]
+async fn controller_state(State(state): State<AppState>) -> Json<StateSnapshot> {
+    Json(state.controller_state.get())
+}
+
+/// A configured heater with the state it was last commanded to, which is
+/// unknown until the relay reported or was sent one.
+#[derive(Debug, PartialEq, serde::Serialize)]
+struct HeaterSummary {
+    id: String,
+    name: String,
+    state: Option<HeaterState>,
+}
+
+/// List the configured heaters with their latest known state.
+#[tracing::instrument(skip(state))]
+async fn heaters(State(state): State<AppState>) -> Result<Json<Vec<HeaterSummary>>, ApiError> {
+    let heaters = state.db.lock().await.get_heaters().await?;
+    let snapshot = state.controller_state.get();
+
+    Ok(Json(
+        heaters
+            .into_iter()
+            .map(|heater| HeaterSummary {
+                state: snapshot.heater_states.get(heater.id()).copied(),
+                id: heater.id().clone(),
+                name: heater.name().clone(),
+            })
+            .collect(),
+    ))
+}
+
+/// Stream the events of the actions handled by the executor as server-sent
+/// events, as they happen.
+#[tracing::instrument(skip(state))]
+async fn events(
+    State(state): State<AppState>,
+) -> Sse<impl Stream<Item = Result<sse::Event, Infallible>>> {
+    let stream = futures_util::stream::unfold(state.events.subscribe(), |mut rx| async move {
+        loop {
+            match rx.recv().await {
+                Ok(event) => match sse::Event::default().json_data(&event) {
+                    Ok(data) => return Some((Ok(data), rx)),
+                    Err(e) => tracing::error!(error = %e, ?event, "Failed to serialize event"),
+                },
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::warn!(skipped, "Client of the event stream fell behind");
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct HistoryQuery {
+    hours: Option<u32>,
+}
+
+impl HistoryQuery {
+    /// How far back to look, rejecting hours out of range.
+    fn duration(&self) -> Result<Duration, ApiError> {
+        let hours = self.hours.unwrap_or(DEFAULT_HISTORY_HOURS);
+        if !(1..=MAX_HISTORY_HOURS).contains(&hours) {
+            return Err(ApiError::BadRequest(format!(
+                "hours must be between 1 and {MAX_HISTORY_HOURS}"
+            )));
+        }
+        Ok(Duration::from_secs(u64::from(hours) * 60 * 60))
+    }
+}
+
+/// Get the temperature history of the last `hours`, defaulting to 24 hours.
+#[tracing::instrument(skip(state))]
+async fn history(
+    State(state): State<AppState>,
+    Query(query): Query<HistoryQuery>,
+) -> Result<Json<Vec<TemperatureMeasurementRecord>>, ApiError> {
+    let history = state
+        .db
+        .lock()
+        .await
+        .get_history_since(query.duration()?)
+        .await?;
+
+    Ok(Json(history))
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct HourlyHistoryQuery {
+    location: Option<String>,
+    hours: Option<u32>,
+}
+
+/// Get the average temperature and humidity per hour of a location, inside by
+/// default, within the last `hours`, defaulting to 24 hours. Charts need far
+/// fewer points than with every reading.
+#[tracing::instrument(skip(state))]
+async fn hourly_history(
+    State(state): State<AppState>,
+    Query(query): Query<HourlyHistoryQuery>,
+) -> Result<Json<Vec<HourlyAggregate>>, ApiError> {
+    let duration = HistoryQuery { hours: query.hours }.duration()?;
+    let since = Utc::now().naive_utc()
+        - chrono::Duration::from_std(duration).context("History duration is too long")?;
+    let location = query.location.as_deref().unwrap_or(INSIDE);
+    let history = state
+        .db
+        .lock()
+        .await
+        .get_hourly_averages(location, since)
+        .await?;
+
+    Ok(Json(history))
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct DailyHistoryQuery {
+    location: Option<String>,
+    days: Option<u32>,
+}
+
+/// Get the average temperature and humidity per day of a location, inside by
+/// default, for the last `days` including today, defaulting to a week.
+#[tracing::instrument(skip(state))]
+async fn daily_history(
+    State(state): State<AppState>,
+    Query(query): Query<DailyHistoryQuery>,
+) -> Result<Json<Vec<DailyAggregate>>, ApiError> {
+    let days = query.days.unwrap_or(DEFAULT_HISTORY_DAYS);
+    if !(1..=MAX_HISTORY_DAYS).contains(&days) {
+        return Err(ApiError::BadRequest(format!(
+            "days must be between 1 and {MAX_HISTORY_DAYS}"
+        )));
+    }
+    let location = query.location.as_deref().unwrap_or(INSIDE);
+    let since = state.today() - Days::new(u64::from(days - 1));
+    let db = state.db.lock().await;
+    let history = match state.timezone {
+        Some(timezone) => db.get_daily_averages(location, since, &timezone).await?,
+        None => db.get_daily_averages(location, since, &Local).await?,
+    };
+
+    Ok(Json(history))
+}
+
+/// Get the changes of the desired temperature within the last `hours`,
+/// defaulting to 24 hours.
+#[tracing::instrument(skip(state))]
+async fn setpoint_history(
+    State(state): State<AppState>,
+    Query(query): Query<HistoryQuery>,
+) -> Result<Json<Vec<SetpointChange>>, ApiError> {
+    let history = state
+        .db
+        .lock()
+        .await
+        .get_setpoint_history_since(query.duration()?)
+        .await?;
+
+    Ok(Json(history))
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct DesiredTemperature {
+    temperature: f64,
+}
+
+/// Set the desired temperature, like publishing to `temperature/set`.
+#[tracing::instrument(skip(state))]
+async fn set_desired_temperature(
+    State(state): State<AppState>,
+    Json(body): Json<DesiredTemperature>,
+) -> Result<StatusCode, ApiError> {
+    if !(MIN_DESIRED_TEMPERATURE..=MAX_DESIRED_TEMPERATURE).contains(&body.temperature) {
+        return Err(ApiError::BadRequest(format!(
+            "temperature must be between {MIN_DESIRED_TEMPERATURE} and {MAX_DESIRED_TEMPERATURE}"
+        )));
+    }
+
+    let Some(actions) = state.actions.upgrade() else {
+        return Err(ApiError::Unavailable);
+    };
+    actions
+        .send(Action::SetDesiredTemperature(
+            body.temperature,
+            SetpointSource::Http,
+        ))
+        .await
+        .map_err(|_| ApiError::Unavailable)?;
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// A synthetic reading inserted over the admin API. Without a timestamp the
+/// current time is used, and without a humidity none is stored.
+#[derive(Debug, serde::Deserialize)]
+struct AdminReading {
+    location: String,
+    temperature: f64,
+    humidity: Option<f64>,
+    timestamp: Option<NaiveDateTime>,
+}
+
+/// Insert a reading directly into the database, bypassing the control, e.g.
+/// to test dashboards without a sensor.
+#[tracing::instrument(skip(state))]
+async fn insert_reading(
+    State(state): State<AppState>,
+    Json(body): Json<AdminReading>,
+) -> Result<StatusCode, ApiError> {
+    if body.location.is_empty() {
+        return Err(ApiError::BadRequest(
+            "location must not be empty".to_string(),
+        ));
+    }
+
+    state
+        .db
+        .lock()
+        .await
+        .insert_reading(
+            &body.location,
+            body.temperature,
+            body.humidity,
+            None,
+            body.timestamp,
+        )
+        .await?;
+
+    Ok(StatusCode::CREATED)
+}
+
+/// Make the controller reassess the heaters without waiting for a new
+/// reading, like publishing to `hub/reevaluate`.
+#[tracing::instrument(skip(state))]
+async fn reevaluate(State(state): State<AppState>) -> Result<StatusCode, ApiError> {
+    let Some(actions) = state.actions.upgrade() else {
+        return Err(ApiError::Unavailable);
+    };
+    actions
+        .send(Action::Reevaluate)
+        .await
+        .map_err(|_| ApiError::Unavailable)?;
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct ExportQuery {
+    from: Option<NaiveDateTime>,
+    to: Option<NaiveDateTime>,
+}
+
+/// A row of the exported history.
+#[derive(Debug, serde::Serialize)]
+struct HistoryCsvRow<'a> {
+    timestamp: &'a NaiveDateTime,
+    location: &'a str,
+    temperature: f64,
+    /// Left empty for readings without a humidity.
+    humidity: Option<f64>,
+}
+
+/// Export the readings taken from `from` until `to` as CSV, defaulting to all
+/// readings until now. The rows are streamed from the database as they are
+/// written to the client.
+#[tracing::instrument(skip(state))]
+async fn export_history(
+    State(state): State<AppState>,
+    Query(query): Query<ExportQuery>,
+) -> Result<Response, ApiError> {
+    let (from, to) = export_range(query.from, query.to)?;
+
+    let db = state.db.lock().await.clone();
+    let (tx, rx) = mpsc::channel::<Result<Bytes, std::io::Error>>(EXPORT_BUFFER_ROWS);
+    tokio::spawn(async move {
+        if tx.send(Ok(csv_header())).await.is_err() {
+            return;
+        }
+        let mut records = db.stream_history_between(from.as_ref(), &to);
+        while let Some(record) = records.next().await {
+            let row = record
+                .context("Failed to read history")
+                .and_then(|record| csv_row(&record));
+            let failed = row.is_err();
+            if let Err(e) = &row {
+                tracing::error!(error = %e, "Failed to export history");
+            }
+            // Stop when the client has gone away, or the body ends with an error.
+            if tx.send(row.map_err(std::io::Error::other)).await.is_err() || failed {
+                break;
+            }
+        }
+    });
+
+    let body = Body::from_stream(futures_util::stream::unfold(rx, |mut rx| async {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    }));
+    Ok(([(header::CONTENT_TYPE, "text/csv")], body).into_response())
+}
+
+/// The range of readings to export, from the oldest reading without `from`
+/// and until now without `to`.
+pub fn export_range(
+    from: Option<NaiveDateTime>,
+    to: Option<NaiveDateTime>,
+) -> Result<(Option<NaiveDateTime>, NaiveDateTime), HubError> {
+    let to = to.unwrap_or_else(|| Utc::now().naive_utc());
+    if from.is_some_and(|from| from >= to) {
+        return Err(HubError::validation("from must be before to"));
+    }
+
+    Ok((from, to))
+}
+
+/// The header line of the exported history.
+pub fn csv_header() -> Bytes {
+    Bytes::from_static(b"timestamp,location,temperature,humidity\n")
+}
+
+/// Format a reading as a line of CSV.
+pub fn csv_row(record: &TemperatureMeasurementRecord) -> Result<Bytes> {
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(Vec::new());
+    writer.serialize(HistoryCsvRow {
+        timestamp: record.timestamp(),
+        location: record.location(),
+        temperature: *record.temperature(),
+        humidity: *record.humidity(),
+    })?;
+    let row = writer.into_inner().context("Failed to write CSV row")?;
+    Ok(Bytes::from(row))
+}
+
+/// Errors returned by the HTTP handlers.
+#[derive(Debug)]
+enum ApiError {
+    BadRequest(String),
+    /// The hub is shutting down and no longer accepts actions.
+    Unavailable,
+    Internal(anyhow::Error),
+}
+
+/// Parse and validation failures are the caller's fault, anything else is
+/// an internal error.
+impl From<anyhow::Error> for ApiError {
+    fn from(error: anyhow::Error) -> Self {
+        match error.downcast_ref::<HubError>() {
+            Some(HubError::Parse(message) | HubError::Validation(message)) => {
+                Self::BadRequest(message.clone())
+            }
+            _ => Self::Internal(error),
+        }
+    }
+}
+
+impl From<HubError> for ApiError {
+    fn from(error: HubError) -> Self {
+        match error {
+            HubError::Parse(message) | HubError::Validation(message) => Self::BadRequest(message),
+            error => Self::Internal(error.into()),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        match self {
+            Self::BadRequest(message) => (StatusCode::BAD_REQUEST, message).into_response(),
+            Self::Unavailable => StatusCode::SERVICE_UNAVAILABLE.into_response(),
+            Self::Internal(error) => {
+                tracing::error!(error.cause_chain = ?error, error.message = %error, "Request failed");
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use axum::http::Method;
+    use chrono::NaiveDate;
+    use sqlx::SqlitePool;
+    use tokio::sync::mpsc::{channel, Receiver, Sender};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    const TOKEN: &str = "s3cret";
+
+    async fn state(pool: SqlitePool) -> AppState {
+        let (state, _, _) = state_with_actions(pool).await;
+        state
+    }
+
+    /// Create the state like `state`, which also returns the channel of
+    /// actions. The state can only send actions while the sender is alive.
+    async fn state_with_actions(pool: SqlitePool) -> (AppState, Sender<Action>, Receiver<Action>) {
+        let db = Database::new(pool).await.unwrap();
+        let (tx, rx) = channel(10);
+        let state = AppState::new(
+            Arc::new(Mutex::new(db)),
+            ConnectionHealth::default(),
+            DatabaseHealth::default(),
+            ChannelMetrics::default(),
+            tx.downgrade(),
+            SharedState::default(),
+            broadcast::channel(10).0,
+        );
+        (state, tx, rx)
+    }
+
+    /// Send a request without a body through a router requiring `TOKEN`,
+    /// authorized with `token` if given.
+    async fn request(
+        state: AppState,
+        method: Method,
+        path: &str,
+        token: Option<&str>,
+    ) -> StatusCode {
+        let config = HttpConfig {
+            api_token: Some(TOKEN.to_string()),
+            ..Default::default()
+        };
+        let mut request = axum::http::Request::builder().method(method).uri(path);
+        if let Some(token) = token {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {token}"));
+        }
+        router(state, &config)
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status()
+    }
+
+    #[sqlx::test]
+    fn mutating_route_rejects_missing_or_wrong_token(pool: SqlitePool) {
+        // Arrange
+        let (state, _tx, mut rx) = state_with_actions(pool).await;
+
+        // Act
+        let missing = request(state.clone(), Method::POST, "/reevaluate", None).await;
+        let wrong = request(state, Method::POST, "/reevaluate", Some("guess")).await;
+
+        // Assert
+        assert_eq!(missing, StatusCode::UNAUTHORIZED);
+        assert_eq!(wrong, StatusCode::UNAUTHORIZED);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[sqlx::test]
+    fn mutating_route_accepts_valid_token(pool: SqlitePool) {
+        // Arrange
+        let (state, _tx, mut rx) = state_with_actions(pool).await;
+
+        // Act
+        let status = request(state, Method::POST, "/reevaluate", Some(TOKEN)).await;
+
+        // Assert
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert!(matches!(rx.try_recv(), Ok(Action::Reevaluate)));
+    }
+
+    #[sqlx::test]
+    fn health_does_not_require_token(pool: SqlitePool) {
+        let state = state(pool).await;
+        state.mqtt_health.record_success();
+
+        let status = request(state, Method::GET, "/health", None).await;
+
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[sqlx::test]
+    fn health_ok_when_mqtt_polled_recently(pool: SqlitePool) {
+        // Arrange
+        let state = state(pool).await;
+        state.mqtt_health.record_success();
+
+        // Act
+        let status = health(State(state)).await;
+
+        // Assert
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[sqlx::test]
+    fn health_unavailable_when_mqtt_never_polled(pool: SqlitePool) {
+        // Arrange
+        let state = state(pool).await;
+
+        // Act
+        let status = health(State(state)).await;
+
+        // Assert
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[sqlx::test]
+    fn metrics_start_at_zero(pool: SqlitePool) {
+        let Json(metrics) = metrics(State(state(pool).await)).await.unwrap();
+
+        assert_eq!(
+            metrics,
+            Metrics {
+                dropped_actions: 0,
+                slow_sends: 0,
+                slow_db_lock_waits: 0,
+                slow_db_operations: 0,
+                heater_cycles_today: HashMap::from(
+                    ["C4402D", "C431FB", "10DB9C"].map(|id| (id.to_string(), 0))
+                ),
+                estimated_energy_today_kwh: HashMap::new(),
+                temperature_error: ErrorHistogram::default().snapshot(),
+            }
+        );
+    }
+
+    #[sqlx::test]
+    fn metrics_count_heater_cycles_today(pool: SqlitePool) {
+        // Arrange
+        let state = state(pool).await;
+        {
+            let db = state.db.lock().await;
+            for heater_state in [HeaterState::On, HeaterState::Off, HeaterState::On] {
+                db.insert_heater_state("C4402D", heater_state)
+                    .await
+                    .unwrap();
+            }
+        }
+
+        // Act
+        let Json(metrics) = metrics(State(state)).await.unwrap();
+
+        // Assert
+        assert_eq!(metrics.heater_cycles_today.get("C4402D"), Some(&1));
+        assert_eq!(metrics.heater_cycles_today.get("C431FB"), Some(&0));
+        assert!(metrics.estimated_energy_today_kwh.is_empty());
+    }
+
+    #[sqlx::test]
+    fn insert_reading_persists_reading(pool: SqlitePool) {
+        // Arrange
+        let state = state(pool).await;
+        let timestamp = NaiveDate::from_ymd_opt(2026, 10, 14)
+            .unwrap()
+            .and_hms_opt(12, 0, 0)
+            .unwrap();
+        let body = AdminReading {
+            location: "garage".to_string(),
+            temperature: 12.5,
+            humidity: Some(70.0),
+            timestamp: Some(timestamp),
+        };
+
+        // Act
+        let status = insert_reading(State(state.clone()), Json(body))
+            .await
+            .unwrap();
+
+        // Assert
+        assert_eq!(status, StatusCode::CREATED);
+        let reading = state
+            .db
+            .lock()
+            .await
+            .get_latest_reading("garage")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(*reading.timestamp(), timestamp);
+        assert_eq!(*reading.temperature(), 12.5);
+        assert_eq!(*reading.humidity(), Some(70.0));
+    }
+
+    #[sqlx::test]
+    fn insert_reading_rejects_empty_location(pool: SqlitePool) {
+        let body = AdminReading {
+            location: String::new(),
+            temperature: 12.5,
+            humidity: Some(70.0),
+            timestamp: None,
+        };
+
+        let result = insert_reading(State(state(pool).await), Json(body)).await;
+
+        assert!(matches!(result, Err(ApiError::BadRequest(_))));
+    }
+
+    #[sqlx::test]
+    fn set_desired_temperature_queues_action(pool: SqlitePool) {
+        // Arrange
+        let (state, _tx, mut rx) = state_with_actions(pool).await;
+
+        // Act
+        let status =
+            set_desired_temperature(State(state), Json(DesiredTemperature { temperature: 21.5 }))
+                .await
+                .unwrap();
+
+        // Assert
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(Action::SetDesiredTemperature(temperature, SetpointSource::Http))
+                if temperature == 21.5
+        ));
+    }
+
+    #[sqlx::test]
+    fn reevaluate_queues_action(pool: SqlitePool) {
+        // Arrange
+        let (state, _tx, mut rx) = state_with_actions(pool).await;
+
+        // Act
+        let status = reevaluate(State(state)).await.unwrap();
+
+        // Assert
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert!(matches!(rx.try_recv(), Ok(Action::Reevaluate)));
+    }
+
+    #[sqlx::test]
+    fn set_desired_temperature_rejects_out_of_range(pool: SqlitePool) {
+        let (state, _tx, mut rx) = state_with_actions(pool).await;
+
+        for temperature in [MIN_DESIRED_TEMPERATURE - 0.5, MAX_DESIRED_TEMPERATURE + 0.5] {
+            let result = set_desired_temperature(
+                State(state.clone()),
+                Json(DesiredTemperature { temperature }),
+            )
+            .await;
+
+            assert!(matches!(result, Err(ApiError::BadRequest(_))));
+        }
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[sqlx::test]
+    fn set_desired_temperature_unavailable_when_shutting_down(pool: SqlitePool) {
+        let state = state(pool).await;
+
+        let result =
+            set_desired_temperature(State(state), Json(DesiredTemperature { temperature: 21.5 }))
+                .await;
+
+        assert!(matches!(result, Err(ApiError::Unavailable)));
+    }
+
+    #[sqlx::test]
+    fn status_summarizes_hub(pool: SqlitePool) {
+        // Arrange
+        let mut state = state(pool).await;
+        state.mqtt_health.record_success();
+        state.controller_state = SharedState::new(StateSnapshot {
+            enabled: true,
+            desired_temperature: Some(21.0),
+            temperatures: HashMap::from([(INSIDE.to_string(), 20.5)]),
+            heater_states: HashMap::from([("C4402D".to_string(), HeaterState::On)]),
+            ..StateSnapshot::default()
+        });
+
+        // Act
+        let Json(status) = status(State(state)).await;
+
+        // Assert
+        assert_eq!(
+            serde_json::to_value(status).unwrap(),
+            serde_json::json!({
+                "uptime_secs": 0,
+                "mqtt_connected": true,
+                "last_db_write": null,
+                "enabled": true,
+                "desired_temperature": 21.0,
+                "current_temperature": 20.5,
+                "heater_states": { "C4402D": "on" },
+            })
+        );
+    }
+
+    #[sqlx::test]
+    fn controller_state_starts_empty(pool: SqlitePool) {
+        let Json(snapshot) = controller_state(State(state(pool).await)).await;
+
+        assert_eq!(snapshot, StateSnapshot::default());
+    }
+
+    #[sqlx::test]
+    fn heaters_lists_configured_heaters_with_state(pool: SqlitePool) {
+        // Arrange
+        let mut state = state(pool).await;
+        state.controller_state = SharedState::new(StateSnapshot {
+            heater_states: HashMap::from([
+                ("C4402D".to_string(), HeaterState::On),
+                ("C431FB".to_string(), HeaterState::Off),
+            ]),
+            ..StateSnapshot::default()
+        });
+
+        // Act
+        let Json(heaters) = heaters(State(state)).await.unwrap();
+
+        // Assert
+        let states: Vec<_> = heaters
+            .iter()
+            .map(|heater| (heater.id.as_str(), heater.state))
+            .collect();
+        assert_eq!(
+            states,
+            vec![
+                ("C4402D", Some(HeaterState::On)),
+                ("C431FB", Some(HeaterState::Off)),
+                ("10DB9C", None),
+            ]
+        );
+        assert!(heaters.iter().all(|heater| !heater.name.is_empty()));
+    }
+
+    #[sqlx::test]
+    fn export_history_streams_csv(pool: SqlitePool) {
+        // Arrange
+        let state = state(pool).await;
+        let timestamp =
+            NaiveDateTime::parse_from_str("2024-01-05 07:30:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        state
+            .db
+            .lock()
+            .await
+            .insert_reading("inside", 21.4, Some(58.3), None, Some(timestamp))
+            .await
+            .unwrap();
+        state
+            .db
+            .lock()
+            .await
+            .insert_reading(
+                "garage",
+                12.5,
+                None,
+                None,
+                Some(timestamp + chrono::Duration::minutes(5)),
+            )
+            .await
+            .unwrap();
+
+        // Act
+        let response = export_history(
+            State(state),
+            Query(ExportQuery {
+                from: None,
+                to: None,
+            }),
+        )
+        .await
+        .unwrap();
+
+        // Assert
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/csv");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(body.to_vec()).unwrap(),
+            "timestamp,location,temperature,humidity\n\
+             2024-01-05T07:30:00,inside,21.4,58.3\n\
+             2024-01-05T07:35:00,garage,12.5,\n"
+        );
+    }
+
+    #[sqlx::test]
+    fn export_history_without_from_is_unbounded(pool: SqlitePool) {
+        // Arrange
+        let state = state(pool).await;
+        let oldest = NaiveDate::from_ymd_opt(1970, 1, 1)
+            .unwrap()
+            .and_hms_opt(0, 0, 0)
+            .unwrap();
+        state
+            .db
+            .lock()
+            .await
+            .insert_reading("inside", 21.4, Some(58.3), None, Some(oldest))
+            .await
+            .unwrap();
+
+        // Act
+        let response = export_history(
+            State(state),
+            Query(ExportQuery {
+                from: None,
+                to: Some(oldest + chrono::Duration::days(1)),
+            }),
+        )
+        .await
+        .unwrap();
+
+        // Assert
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(body.to_vec()).unwrap(),
+            "timestamp,location,temperature,humidity\n1970-01-01T00:00:00,inside,21.4,58.3\n"
+        );
+    }
+
+    #[test]
+    fn export_range_defaults_to_everything_until_now() {
+        let before = Utc::now().naive_utc();
+
+        let (from, to) = export_range(None, None).unwrap();
+
+        assert_eq!(from, None);
+        assert!(to >= before);
+        assert!(matches!(
+            export_range(Some(to), Some(to)),
+            Err(HubError::Validation(_))
+        ));
+    }
+
+    #[sqlx::test]
+    fn export_history_rejects_empty_range(pool: SqlitePool) {
+        let now = Utc::now().naive_utc();
+
+        let result = export_history(
+            State(state(pool).await),
+            Query(ExportQuery {
+                from: Some(now),
+                to: Some(now),
+            }),
+        )
+        .await;
+
+        assert!(matches!(result, Err(ApiError::BadRequest(_))));
+    }
+
+    #[sqlx::test]
+    fn history_rejects_out_of_range_hours(pool: SqlitePool) {
+        for hours in [0, MAX_HISTORY_HOURS + 1] {
+            let result = history(
+                State(state(pool.clone()).await),
+                Query(HistoryQuery { hours: Some(hours) }),
+            )
+            .await;
+
+            assert!(matches!(result, Err(ApiError::BadRequest(_))));
+        }
+    }
+
+    #[sqlx::test]
+    fn history_returns_recent_readings(pool: SqlitePool) {
+        // Arrange
+        let state = state(pool).await;
+        state
+            .db
+            .lock()
+            .await
+            .insert_reading("inside", 21.4, Some(58.3), None, None)
+            .await
+            .unwrap();
+
+        // Act
+        let Json(history) = history(State(state), Query(HistoryQuery { hours: None }))
+            .await
+            .unwrap();
+
+        // Assert
+        assert_eq!(history.len(), 1);
+    }
+
+    #[sqlx::test]
+    fn hourly_history_averages_recent_readings(pool: SqlitePool) {
+        // Arrange
+        let state = state(pool).await;
+        {
+            let db = state.db.lock().await;
+            for temperature in [21.0, 22.0] {
+                db.insert_reading("inside", temperature, Some(50.0), None, None)
+                    .await
+                    .unwrap();
+            }
+        }
+
+        // Act
+        let Json(history) = hourly_history(
+            State(state),
+            Query(HourlyHistoryQuery {
+                location: None,
+                hours: None,
+            }),
+        )
+        .await
+        .unwrap();
+
+        // Assert
+        assert_eq!(history.iter().map(|hour| hour.readings).sum::<u64>(), 2);
+    }
+
+    #[sqlx::test]
+    fn daily_history_rejects_out_of_range_days(pool: SqlitePool) {
+        for days in [0, MAX_HISTORY_DAYS + 1] {
+            let result = daily_history(
+                State(state(pool.clone()).await),
+                Query(DailyHistoryQuery {
+                    location: None,
+                    days: Some(days),
+                }),
+            )
+            .await;
+
+            assert!(matches!(result, Err(ApiError::BadRequest(_))));
+        }
+    }
+
+    #[sqlx::test]
+    fn daily_history_includes_today_in_timezone(pool: SqlitePool) {
+        // Arrange
+        let state = state(pool)
+            .await
+            .with_timezone(Some(chrono_tz::America::New_York));
+        state
+            .db
+            .lock()
+            .await
+            .insert_reading("inside", 21.4, Some(58.3), None, None)
+            .await
+            .unwrap();
+
+        // Act
+        let Json(history) = daily_history(
+            State(state.clone()),
+            Query(DailyHistoryQuery {
+                location: None,
+                days: Some(1),
+            }),
+        )
+        .await
+        .unwrap();
+
+        // Assert
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].date, state.today());
+    }
+
+    #[sqlx::test]
+    fn events_streams_handled_actions(pool: SqlitePool) {
+        // Arrange
+        let state = state(pool).await;
+        let sender = state.events.clone();
+        let mut body = events(State(state))
+            .await
+            .into_response()
+            .into_body()
+            .into_data_stream();
+
+        // Act
+        sender.send(Event::Enabled { enabled: true }).unwrap();
+        let chunk = body.next().await.unwrap().unwrap();
+
+        // Assert
+        assert_eq!(
+            chunk,
+            Bytes::from_static(b"data: {\"type\":\"enabled\",\"enabled\":true}\n\n")
+        );
+    }
+
+    #[sqlx::test]
+    fn health_unavailable_when_database_is_closed(pool: SqlitePool) {
+        // Arrange
+        let state = state(pool.clone()).await;
+        state.mqtt_health.record_success();
+        pool.close().await;
+
+        // Act
+        let status = health(State(state)).await;
+
+        // Assert
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[test]
+    fn hub_errors_map_to_status_codes() {
+        let bad_request = ApiError::from(anyhow::Error::from(HubError::validation("too hot")));
+        let internal = ApiError::from(anyhow::anyhow!("disk full"));
+
+        assert!(matches!(bad_request, ApiError::BadRequest(message) if message == "too hot"));
+        assert!(matches!(internal, ApiError::Internal(_)));
+    }
+
+    #[test]
+    fn http_config_debug_redacts_api_token() {
+        let config = HttpConfig {
+            api_token: Some("secret".to_string()),
+            ..Default::default()
+        };
+
+        let debug = format!("{config:?}");
+
+        assert!(!debug.contains("secret"));
+        assert!(debug.contains("<redacted>"));
+    }
+}
@@ -0,0 +1,180 @@
+use std::path::PathBuf;
+
+use chrono::NaiveDateTime;
+use clap::{Parser, Subcommand};
+
+#[cfg(feature = "simulation")]
+use crate::simulation;
+
+/// Hub monitoring and controlling the temperature of the house.
+#[derive(Debug, Clone, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// TOML file to read the configuration from. A missing file is not an
+    /// error.
+    #[arg(
+        long,
+        global = true,
+        env = "CONFIG_PATH",
+        default_value = "config.toml"
+    )]
+    pub config: PathBuf,
+    /// Reload the thresholds and heaters when the configuration file changes.
+    #[arg(long, global = true, env = "WATCH_CONFIG")]
+    pub watch_config: bool,
+    /// Log the commands to the heaters instead of publishing them.
+    #[arg(long, global = true, env = "DRY_RUN")]
+    pub dry_run: bool,
+    /// Do not apply the database migrations, for a schema that is managed
+    /// externally.
+    #[arg(long, global = true, env = "SKIP_MIGRATIONS")]
+    pub skip_migrations: bool,
+}
+
+impl Cli {
+    /// The command to run, which is the full hub when none is given.
+    pub fn command(&self) -> Command {
+        self.command.clone().unwrap_or(Command::Run)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Subcommand)]
+pub enum Command {
+    /// Run the hub.
+    Run,
+    /// Apply the database migrations and exit.
+    Migrate,
+    /// Prune the history older than the retention, vacuum the database, and
+    /// exit.
+    Maintenance,
+    /// Export the history of readings to a CSV file and exit.
+    Export {
+        /// File to write the readings to.
+        #[arg(long, short)]
+        output: PathBuf,
+        /// Export readings from this time, like `2024-01-05T07:30:00`.
+        #[arg(long)]
+        from: Option<NaiveDateTime>,
+        /// Export readings until this time, defaulting to now.
+        #[arg(long)]
+        to: Option<NaiveDateTime>,
+    },
+    /// Replay the readings of a CSV file, like one written by `export`,
+    /// through the control, log the decisions about the heaters, and exit.
+    #[cfg(feature = "simulation")]
+    Simulate {
+        /// File with the columns `timestamp,location,temperature,humidity`.
+        file: PathBuf,
+        /// How many times faster than recorded the readings are replayed.
+        #[arg(long, default_value_t = simulation::DEFAULT_SPEED)]
+        speed: f64,
+    },
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse_cli(args: &[&str]) -> Result<Cli, clap::Error> {
+        Cli::try_parse_from(std::iter::once("paletten-cloud-hub").chain(args.iter().copied()))
+    }
+
+    fn parse(args: &[&str]) -> Result<Command, clap::Error> {
+        parse_cli(args).map(|cli| cli.command())
+    }
+
+    #[test]
+    fn runs_hub_without_subcommand() {
+        assert_eq!(parse(&[]).unwrap(), Command::Run);
+    }
+
+    #[test]
+    fn parses_run() {
+        assert_eq!(parse(&["run"]).unwrap(), Command::Run);
+    }
+
+    #[test]
+    fn parses_migrate() {
+        assert_eq!(parse(&["migrate"]).unwrap(), Command::Migrate);
+    }
+
+    #[test]
+    fn parses_maintenance() {
+        assert_eq!(parse(&["maintenance"]).unwrap(), Command::Maintenance);
+    }
+
+    #[test]
+    fn parses_export() {
+        assert_eq!(
+            parse(&[
+                "export",
+                "--output",
+                "history.csv",
+                "--from",
+                "2024-01-05T07:30:00"
+            ])
+            .unwrap(),
+            Command::Export {
+                output: PathBuf::from("history.csv"),
+                from: Some(NaiveDateTime::parse_from_str("2024-01-05 07:30:00", "%F %T").unwrap()),
+                to: None,
+            }
+        );
+    }
+
+    #[cfg(feature = "simulation")]
+    #[test]
+    fn parses_simulate() {
+        assert_eq!(
+            parse(&["simulate", "history.csv", "--speed", "600"]).unwrap(),
+            Command::Simulate {
+                file: PathBuf::from("history.csv"),
+                speed: 600.0,
+            }
+        );
+        assert_eq!(
+            parse(&["simulate", "history.csv"]).unwrap(),
+            Command::Simulate {
+                file: PathBuf::from("history.csv"),
+                speed: simulation::DEFAULT_SPEED,
+            }
+        );
+    }
+
+    #[test]
+    fn export_requires_output() {
+        assert!(parse(&["export"]).is_err());
+    }
+
+    #[test]
+    fn parses_dry_run_before_and_after_subcommand() {
+        assert!(!parse_cli(&[]).unwrap().dry_run);
+        assert!(parse_cli(&["--dry-run"]).unwrap().dry_run);
+        assert!(parse_cli(&["run", "--dry-run"]).unwrap().dry_run);
+    }
+
+    #[test]
+    fn parses_skip_migrations() {
+        assert!(!parse_cli(&[]).unwrap().skip_migrations);
+        assert!(parse_cli(&["--skip-migrations"]).unwrap().skip_migrations);
+    }
+
+    #[test]
+    fn parses_config_path() {
+        assert_eq!(parse_cli(&[]).unwrap().config, PathBuf::from("config.toml"));
+        assert_eq!(
+            parse_cli(&["--config", "/etc/paletten/hub.toml", "migrate"])
+                .unwrap()
+                .config,
+            PathBuf::from("/etc/paletten/hub.toml")
+        );
+        assert!(parse_cli(&["--watch-config"]).unwrap().watch_config);
+    }
+
+    #[test]
+    fn rejects_unknown_subcommand() {
+        assert!(parse(&["serve"]).is_err());
+    }
+}
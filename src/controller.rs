@@ -1,71 +1,487 @@
-use std::{str::FromStr, sync::Arc, time::Duration};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    future::Future,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 use anyhow::{anyhow, Context, Result};
 use bytes::Bytes;
-use lazy_static::lazy_static;
-use rumqttc::v5::{
-    mqttbytes::{
-        v5::{Filter, Packet, Publish},
-        QoS::{self, ExactlyOnce},
+use chrono::{DateTime, Local, NaiveTime, Utc};
+use rumqttc::{
+    v5::{
+        mqttbytes::{
+            qos,
+            v5::{Filter, LastWill, Packet, Publish, SubAck, SubscribeReasonCode},
+            valid_filter,
+            QoS::{self, ExactlyOnce},
+        },
+        AsyncClient,
+        Event::{Incoming, Outgoing},
+        EventLoop, MqttOptions,
     },
-    AsyncClient,
-    Event::{Incoming, Outgoing},
-    EventLoop, MqttOptions,
+    TlsConfiguration, Transport,
 };
-use tokio::sync::{
-    mpsc::{channel, Receiver, Sender},
-    Mutex,
+use tokio::{
+    sync::{
+        broadcast,
+        mpsc::{channel, error::TrySendError, Receiver, Sender, WeakSender},
+        watch, Mutex, MutexGuard,
+    },
+    time::{Instant, MissedTickBehavior},
 };
 
 use crate::{
-    db::Database,
-    models::{Heater, HeaterState, Measurement},
+    config::Config,
+    db::{Database, NewReading},
+    discovery::{self, AVAILABILITY_TOPIC},
+    error::HubError,
+    models::{
+        Calibration, Heater, HeaterAlert, HeaterState, HeaterStatus, Measurement,
+        MeasurementBounds, Mode, SetpointSource, TargetState, TemperatureAlert,
+    },
+    pid::{PidController, TimeProportional},
+    schedule::Schedule,
 };
 
+mod router;
+
+use router::{MeasurementTopic, Router};
+
 #[cfg(debug_assertions)]
-const MQTT_ID: &str = "paletten-cloud-hub-dev";
+const DEFAULT_MQTT_ID: &str = "paletten-cloud-hub-dev";
 #[cfg(not(debug_assertions))]
-const MQTT_ID: &str = "paletten-cloud-hub";
+const DEFAULT_MQTT_ID: &str = "paletten-cloud-hub";
 
-const MQTT_HOST: &str = "mqtt.oliverflecke.me";
-const MQTT_PORT: u16 = 1883;
+const DEFAULT_MQTT_HOST: &str = "mqtt.oliverflecke.me";
+const DEFAULT_MQTT_PORT: u16 = 1883;
+const DEFAULT_MQTT_KEEP_ALIVE_SECS: u64 = 5;
+const DEFAULT_MQTT_CONNECTION_TIMEOUT_SECS: u64 = 10;
+/// Default number of consecutive failed polls after which the next broker is
+/// tried.
+const DEFAULT_MQTT_FAILOVER_AFTER: u32 = 3;
+/// The shortest keep-alive accepted by the MQTT client.
+const MIN_MQTT_KEEP_ALIVE_SECS: u64 = 5;
 
-lazy_static! {
-    static ref HEATERS: Vec<Heater> = vec![
-        Heater::new("C4402D".to_string(), "Spisebord".to_string()),
-        Heater::new("C431FB".to_string(), "Sofa".to_string()),
-        Heater::new("10DB9C".to_string(), "Soveværelse".to_string()),
-    ];
-}
+/// The measurement place used for readings published on `temperature/inside`.
+pub const INSIDE: &str = "inside";
+
+/// Range of desired temperatures in °C accepted over HTTP, which a boost
+/// cannot push the desired temperature out of either.
+pub const MIN_DESIRED_TEMPERATURE: f64 = 5.0;
+pub const MAX_DESIRED_TEMPERATURE: f64 = 30.0;
+
+/// The measurement place used for readings of the outside temperature.
+const OUTSIDE: &str = "outside";
+
+/// The places measurements are received from.
+const MEASUREMENT_PLACES: [&str; 2] = [INSIDE, OUTSIDE];
+
+/// How often the schedule is checked for a new active entry.
+const SCHEDULE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often the inside sensor is checked for having gone stale.
+const STALENESS_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Number of buffered readings that triggers a write to the database.
+const READING_BATCH_SIZE: usize = 50;
+
+/// Maximum time a reading is buffered before being written to the database.
+const READING_FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Battery level in percent below which a warning is logged for a sensor.
+const LOW_BATTERY_THRESHOLD: f64 = 20.0;
+
+/// Default minimum time a heater relay stays in a state before it is switched
+/// again.
+const DEFAULT_MIN_DWELL: Duration = Duration::from_secs(120);
+
+/// The topics the hub listens to by default.
+const DEFAULT_SUBSCRIPTIONS: [&str; 11] = [
+    "temperature/+",
+    "temperature/inside/fahrenheit",
+    "temperature/set/+",
+    "measurement/#",
+    "shellies/+/relay/0",
+    "+/status/switch:0",
+    "shellies/+/relay/0/power",
+    "shellies/+/relay/0/energy",
+    "heater/+/override",
+    "hub/reevaluate",
+    "hub/config/get",
+];
+
+/// The QoS levels of the subscriptions that differ from `ExactlyOnce` by
+/// default. Telemetry is received at most once, as it is sent frequently and a
+/// lost message is superseded by the next one.
+const DEFAULT_SUBSCRIPTION_QOS: [(&str, u8); 4] = [
+    ("temperature/inside/fahrenheit", 0),
+    ("measurement/#", 0),
+    ("shellies/+/relay/0/power", 0),
+    ("shellies/+/relay/0/energy", 0),
+];
+
+/// Default number of actions buffered between the controller and the
+/// executor.
+const DEFAULT_ACTION_CHANNEL_CAPACITY: usize = 10;
+
+/// Default time a send to the executor may block before it is reported as
+/// slow.
+const DEFAULT_SLOW_SEND_THRESHOLD: Duration = Duration::from_millis(500);
+
+/// Default time waiting for the database lock, or a write, may take before a
+/// warning is logged.
+const DEFAULT_SLOW_DB_THRESHOLD: Duration = Duration::from_millis(500);
+
+/// Number of events buffered for each client of the live stream, after which
+/// a slow client misses the oldest.
+const EVENT_CAPACITY: usize = 64;
+
+const INITIAL_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
 
 type MqttHandler = (AsyncClient, EventLoop);
 type AsyncDatabase = Arc<Mutex<Database>>;
 
-/// Create a mqtt handler with the default broker and configuration.
-pub fn create_mqtt_handler() -> MqttHandler {
-    let mut mqtt_options = MqttOptions::new(MQTT_ID, MQTT_HOST, MQTT_PORT);
-    mqtt_options.set_keep_alive(Duration::from_secs(5));
+/// Configuration of the connection to the MQTT broker.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    pub use_tls: bool,
+    /// CA certificate used to verify the broker. The platform roots are used
+    /// when this is not set.
+    pub ca_path: Option<PathBuf>,
+    #[serde(skip_serializing)]
+    credentials: Option<Credentials>,
+    /// Topic filters to subscribe to.
+    pub subscriptions: Vec<String>,
+    /// Template of the topics of measurements in addition to
+    /// `measurement/<place>`, like `home/{place}/climate`, with an optional
+    /// `{sensor}` level. Its topics are subscribed to as well. Topics also
+    /// matching one of the fixed routes, like `temperature/+`, are routed as
+    /// those instead.
+    pub measurement_topic: Option<String>,
+    /// QoS level 0, 1, or 2 of the subscriptions, keyed by topic filter
+    /// without the prefix. Subscriptions not listed use QoS 2.
+    pub subscription_qos: HashMap<String, u8>,
+    /// Prefix of the topics of the subscriptions, the commands to the relays,
+    /// and the topics announced by discovery, e.g. `apartment1/`, so several
+    /// hubs can share a broker.
+    pub topic_prefix: String,
+    /// Seconds without other traffic after which the broker is pinged. The
+    /// eventloop fails a poll when a ping is not answered before the next one
+    /// is due, so a longer keep-alive tolerates a slower link at the cost of
+    /// noticing a lost connection later. Must be at least 5 seconds.
+    pub keep_alive_secs: u64,
+    /// Seconds a poll of the eventloop waits for the connection to the broker
+    /// to be established, before it fails and the reconnect is backed off.
+    pub connection_timeout_secs: u64,
+    /// Brokers tried in turn after `host`, when the current one keeps failing.
+    pub fallback_brokers: Vec<BrokerEndpoint>,
+    /// Number of consecutive failed polls after which the next broker is
+    /// tried. Must be at least 1.
+    pub failover_after: u32,
+}
+
+/// Address of an MQTT broker.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct BrokerEndpoint {
+    pub host: String,
+    #[serde(default = "default_mqtt_port")]
+    pub port: u16,
+}
+
+fn default_mqtt_port() -> u16 {
+    DEFAULT_MQTT_PORT
+}
+
+/// Username and password used to authenticate with the broker. The password
+/// is redacted from the `Debug` output so it never ends up in traces.
+#[derive(Clone, PartialEq, Eq, serde::Deserialize)]
+struct Credentials {
+    username: String,
+    password: String,
+}
+
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .field("password", &"<redacted>")
+            .finish()
+    }
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        Self {
+            host: DEFAULT_MQTT_HOST.to_string(),
+            port: DEFAULT_MQTT_PORT,
+            client_id: DEFAULT_MQTT_ID.to_string(),
+            use_tls: false,
+            ca_path: None,
+            credentials: None,
+            subscriptions: DEFAULT_SUBSCRIPTIONS.map(String::from).to_vec(),
+            measurement_topic: None,
+            subscription_qos: DEFAULT_SUBSCRIPTION_QOS
+                .into_iter()
+                .map(|(topic, level)| (topic.to_string(), level))
+                .collect(),
+            topic_prefix: String::new(),
+            keep_alive_secs: DEFAULT_MQTT_KEEP_ALIVE_SECS,
+            connection_timeout_secs: DEFAULT_MQTT_CONNECTION_TIMEOUT_SECS,
+            fallback_brokers: Vec::new(),
+            failover_after: DEFAULT_MQTT_FAILOVER_AFTER,
+        }
+    }
+}
+
+impl MqttConfig {
+    /// Override the configuration with the `MQTT_HOST`, `MQTT_PORT`,
+    /// `MQTT_CLIENT_ID`, `MQTT_USE_TLS`, `MQTT_CA_PATH`, `MQTT_USERNAME`,
+    /// `MQTT_PASSWORD`, `MQTT_SUBSCRIPTIONS`, `MQTT_TOPIC_PREFIX`,
+    /// `MQTT_KEEP_ALIVE_SECS`, and `MQTT_CONNECTION_TIMEOUT_SECS` environment
+    /// variables found by `lookup`. `MQTT_SUBSCRIPTIONS` is a comma separated
+    /// list of topic filters.
+    pub fn with_env_overrides(self, lookup: &impl Fn(&str) -> Option<String>) -> Result<Self> {
+        self.measurement_topic()?;
+        let port = lookup("MQTT_PORT")
+            .map(|port| port.parse::<u16>().context("MQTT_PORT is not a valid port"))
+            .transpose()?;
+        let use_tls = lookup("MQTT_USE_TLS")
+            .map(|use_tls| {
+                use_tls
+                    .parse::<bool>()
+                    .context("MQTT_USE_TLS is not a boolean")
+            })
+            .transpose()?;
+        let keep_alive_secs = lookup("MQTT_KEEP_ALIVE_SECS")
+            .map(|secs| {
+                secs.parse::<u64>()
+                    .context("MQTT_KEEP_ALIVE_SECS is not a number of seconds")
+            })
+            .transpose()?;
+        let connection_timeout_secs = lookup("MQTT_CONNECTION_TIMEOUT_SECS")
+            .map(|secs| {
+                secs.parse::<u64>()
+                    .context("MQTT_CONNECTION_TIMEOUT_SECS is not a number of seconds")
+            })
+            .transpose()?;
+        let credentials = match (lookup("MQTT_USERNAME"), lookup("MQTT_PASSWORD")) {
+            (Some(username), Some(password)) => Some(Credentials { username, password }),
+            (None, None) => self.credentials,
+            _ => {
+                tracing::warn!(
+                    "Only one of MQTT_USERNAME and MQTT_PASSWORD is set, connecting anonymously"
+                );
+                None
+            }
+        };
+        let subscriptions = lookup("MQTT_SUBSCRIPTIONS")
+            .map(|value| parse_subscriptions(&value))
+            .unwrap_or(self.subscriptions);
+        let topic_prefix = lookup("MQTT_TOPIC_PREFIX").unwrap_or(self.topic_prefix);
+        if topic_prefix.contains(['+', '#']) {
+            return Err(anyhow!(
+                "MQTT_TOPIC_PREFIX must not contain wildcards: '{topic_prefix}'"
+            ));
+        }
+        validate_subscriptions(&subscriptions)?;
+
+        Ok(Self {
+            host: lookup("MQTT_HOST").unwrap_or(self.host),
+            port: port.unwrap_or(self.port),
+            client_id: lookup("MQTT_CLIENT_ID").unwrap_or(self.client_id),
+            use_tls: use_tls.unwrap_or(self.use_tls),
+            ca_path: lookup("MQTT_CA_PATH").map(PathBuf::from).or(self.ca_path),
+            credentials,
+            subscriptions,
+            subscription_qos: self.subscription_qos,
+            topic_prefix,
+            keep_alive_secs: keep_alive_secs.unwrap_or(self.keep_alive_secs),
+            connection_timeout_secs: connection_timeout_secs
+                .unwrap_or(self.connection_timeout_secs),
+            ..self
+        })
+    }
+
+    /// The brokers to connect to, starting with `host` followed by the
+    /// fallback brokers.
+    fn brokers(&self) -> Vec<BrokerEndpoint> {
+        let primary = BrokerEndpoint {
+            host: self.host.clone(),
+            port: self.port,
+        };
+        std::iter::once(primary)
+            .chain(self.fallback_brokers.iter().cloned())
+            .collect()
+    }
+
+    /// The compiled template of the additional measurement topics, if any.
+    pub fn measurement_topic(&self) -> Result<Option<MeasurementTopic>> {
+        self.measurement_topic
+            .as_deref()
+            .map(MeasurementTopic::new)
+            .transpose()
+    }
+
+    /// The filters of the topics to subscribe to, with their configured QoS,
+    /// including the topics of the measurement template that are not
+    /// subscribed to already.
+    pub fn filters(&self) -> Vec<Filter> {
+        let template = self
+            .measurement_topic()
+            .ok()
+            .flatten()
+            .map(|template| template.filter())
+            .filter(|filter| !self.subscriptions.contains(filter));
+        self.subscriptions
+            .iter()
+            .chain(template.as_ref())
+            .map(|topic| {
+                let qos = self
+                    .subscription_qos
+                    .get(topic)
+                    .and_then(|level| qos(*level))
+                    .unwrap_or(ExactlyOnce);
+                Filter::new(format!("{}{topic}", self.topic_prefix), qos)
+            })
+            .collect()
+    }
+}
+
+/// Parse a comma separated list of topic filters.
+fn parse_subscriptions(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|topic| !topic.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Fail if any of the topic filters to subscribe to is invalid.
+fn validate_subscriptions(subscriptions: &[String]) -> Result<()> {
+    match subscriptions.iter().find(|topic| !valid_filter(topic)) {
+        Some(topic) => Err(anyhow!(
+            "Subscriptions contain an invalid topic filter: '{topic}'"
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Strip `prefix` from `topic`, or `None` if the topic does not start with it.
+fn strip_topic_prefix(prefix: &str, topic: Bytes) -> Option<Bytes> {
+    topic
+        .starts_with(prefix.as_bytes())
+        .then(|| topic.slice(prefix.len()..))
+}
+
+/// Create a mqtt handler connecting to the broker described by `config`.
+pub fn create_mqtt_handler(config: &MqttConfig) -> Result<MqttHandler> {
+    let mqtt_options = create_mqtt_options(config)?;
 
-    AsyncClient::new(mqtt_options, 10)
+    Ok(AsyncClient::new(mqtt_options, 10))
+}
+
+fn create_mqtt_options(config: &MqttConfig) -> Result<MqttOptions> {
+    if config.keep_alive_secs < MIN_MQTT_KEEP_ALIVE_SECS {
+        return Err(anyhow!(
+            "The MQTT keep-alive must be at least {MIN_MQTT_KEEP_ALIVE_SECS} seconds"
+        ));
+    }
+    if config.failover_after == 0 {
+        return Err(anyhow!(
+            "The MQTT failover must be after at least 1 failure"
+        ));
+    }
+    let mut mqtt_options = MqttOptions::new(&config.client_id, &config.host, config.port);
+    mqtt_options.set_keep_alive(Duration::from_secs(config.keep_alive_secs));
+    mqtt_options.set_connection_timeout(config.connection_timeout_secs);
+    // The broker publishes this when the connection is lost without the hub
+    // disconnecting, so subscribers know the retained status topics are stale.
+    mqtt_options.set_last_will(LastWill::new(
+        AVAILABILITY_TOPIC,
+        "offline",
+        QoS::AtLeastOnce,
+        true,
+        None,
+    ));
+
+    if let Some(Credentials { username, password }) = &config.credentials {
+        mqtt_options.set_credentials(username, password);
+    }
+
+    if config.use_tls {
+        let transport = match &config.ca_path {
+            Some(ca_path) => {
+                let ca = std::fs::read(ca_path).with_context(|| {
+                    format!("Failed to read CA certificate from {}", ca_path.display())
+                })?;
+                Transport::tls_with_config(TlsConfiguration::Simple {
+                    ca,
+                    alpn: None,
+                    client_auth: None,
+                })
+            }
+            None => Transport::tls_with_default_config(),
+        };
+        mqtt_options.set_transport(transport);
+    }
+
+    Ok(mqtt_options)
 }
 
 pub async fn create(
     mqtt_client: AsyncClient,
     mqtt_eventloop: EventLoop,
     db: AsyncDatabase,
+    mqtt_config: &MqttConfig,
+    control_config: &ControlConfig,
 ) -> Result<(Controller, Executor)> {
-    let (tx, rx) = channel::<Action>(10);
+    control_config.validate()?;
+    if let Some(topic) = mqtt_config
+        .subscription_qos
+        .iter()
+        .find_map(|(topic, level)| qos(*level).is_none().then_some(topic))
+    {
+        return Err(anyhow!(
+            "The QoS of subscription '{topic}' must be 0, 1, or 2"
+        ));
+    }
+    let measurement_topic = mqtt_config.measurement_topic()?;
+    let (tx, rx) = channel::<Action>(control_config.action_channel_capacity);
+    let subscriptions = mqtt_config.filters();
+    tracing::info!(?subscriptions, "Subscribing to topics");
     mqtt_client
-        .subscribe_many([
-            Filter::new("temperature/+", ExactlyOnce),
-            Filter::new("measurement/+", ExactlyOnce),
-            Filter::new("shellies/+/relay/0", ExactlyOnce),
-        ])
+        .subscribe_many(subscriptions.clone())
         .await
         .context("Failed to subscribe to topics")?;
 
-    let controller = Controller::new(mqtt_eventloop, tx);
-    let executor = Executor::new(mqtt_client, db, rx);
+    let heaters = db.lock().await.get_heaters().await?;
+    tracing::info!(?heaters, "Loaded heaters");
+    let schedule = db.lock().await.get_schedule().await?;
+    tracing::info!(?schedule, "Loaded schedule");
+
+    let mut controller = Controller::new(mqtt_eventloop, mqtt_client.clone(), tx, subscriptions);
+    controller.slow_send_threshold = Duration::from_millis(control_config.slow_send_threshold_ms);
+    controller.router = Router::new(
+        control_config.measurement_bounds,
+        control_config.calibration.clone(),
+    )
+    .with_measurement_topic(measurement_topic);
+    controller.topic_prefix = mqtt_config.topic_prefix.clone();
+    controller.failover = Failover::new(mqtt_config)?;
+    let mut executor = Executor::new(mqtt_client, db, rx, heaters, schedule, control_config);
+    executor.topic_prefix = mqtt_config.topic_prefix.clone();
+    executor.restore_state().await?;
 
     Ok((controller, executor))
 }
@@ -73,141 +489,274 @@ pub async fn create(
 /// An action recevied from the controller.
 #[derive(Debug, Clone)]
 pub enum Action {
-    SetDesiredTemperature(f64),
+    SetDesiredTemperature(f64, SetpointSource),
+    SetHeaterDesiredTemperature(String, f64),
     SetInsideTemperature(f64),
     EnableController(bool),
+    SetMode(Mode),
     RegisterMeasurement(String, Measurement),
     RegisterHeaterStateChange(String, HeaterState),
+    /// Instantaneous power in watts drawn by a heater.
+    RegisterHeaterPower(String, f64),
+    /// Reading of the energy counter of a heater in kWh.
+    RegisterHeaterEnergy(String, f64),
+    /// A message that could not be parsed, with its topic, raw payload, and
+    /// the reason it failed.
+    RegisterDeadLetter(String, Bytes, String),
+    /// Force a heater to a state for a while, regardless of the temperature.
+    OverrideHeater {
+        id: String,
+        state: HeaterState,
+        duration: Duration,
+    },
+    /// Reassess the heaters with the current state, e.g. after the schedule
+    /// or configuration was changed.
+    Reevaluate,
+    /// Raise the desired temperatures by `delta` for a while, after which they
+    /// revert.
+    Boost {
+        delta: f64,
+        duration: Duration,
+    },
+    /// Respond with the effective configuration on `hub/config`.
+    PublishConfig,
+    /// Apply the thresholds and heaters of a changed configuration file, and
+    /// reload the schedule.
+    ReloadConfig(Box<Config>),
+}
+
+impl Action {
+    /// Whether the action may be dropped when the executor falls behind, as
+    /// it is periodic telemetry that is superseded by the next message.
+    fn is_low_priority(&self) -> bool {
+        matches!(
+            self,
+            Action::RegisterMeasurement(..)
+                | Action::RegisterHeaterPower(..)
+                | Action::RegisterHeaterEnergy(..)
+        )
+    }
 }
 
 /// Struct to listen and adjust heater state based on a desired state.
 pub struct Controller {
     eventloop: EventLoop,
-    state: State,
-    tx: Sender<Action>,
+    mqtt_client: AsyncClient,
+    /// Sender for the actions, which is dropped when shutting down.
+    tx: Option<Sender<Action>>,
+    /// Handle to the sender for other producers of actions, which does not
+    /// keep the `Executor` running once `tx` is dropped.
+    weak_tx: WeakSender<Action>,
+    health: ConnectionHealth,
+    metrics: ChannelMetrics,
+    /// Time a send to the executor may block before it is reported as slow.
+    slow_send_threshold: Duration,
+    router: Router,
+    /// Topic filters renewed after reconnecting.
+    subscriptions: Vec<Filter>,
+    /// Prefix stripped from the topics of incoming messages.
+    topic_prefix: String,
+    failover: Failover,
 }
 
 impl Controller {
-    pub fn new(eventloop: EventLoop, tx: Sender<Action>) -> Self {
+    pub fn new(
+        eventloop: EventLoop,
+        mqtt_client: AsyncClient,
+        tx: Sender<Action>,
+        subscriptions: Vec<Filter>,
+    ) -> Self {
         Self {
             eventloop,
-            state: State::default(),
-            tx,
+            mqtt_client,
+            weak_tx: tx.downgrade(),
+            tx: Some(tx),
+            health: ConnectionHealth::default(),
+            metrics: ChannelMetrics::default(),
+            slow_send_threshold: DEFAULT_SLOW_SEND_THRESHOLD,
+            router: Router::default(),
+            subscriptions,
+            topic_prefix: String::new(),
+            failover: Failover::default(),
         }
     }
 
+    /// Get a handle to the health of the connection to the MQTT broker.
+    pub fn health(&self) -> ConnectionHealth {
+        self.health.clone()
+    }
+
+    /// Get a handle to send actions to the `Executor` from outside of MQTT.
+    pub fn actions(&self) -> WeakSender<Action> {
+        self.weak_tx.clone()
+    }
+
+    /// Get a handle to the metrics of the channel to the `Executor`.
+    pub fn metrics(&self) -> ChannelMetrics {
+        self.metrics.clone()
+    }
+
     /// Execute the controllers loop until completion. Run as a `Future` that
     /// must be polled. Best used with `tokio::spawn`.
-    pub async fn run_until_completion(mut self) -> Result<()> {
+    ///
+    /// Failed polls are retried with an exponential backoff, and the
+    /// subscriptions are renewed once the connection is reestablished.
+    ///
+    /// When `shutdown` is signalled, no more actions are produced, but the
+    /// eventloop keeps being polled so the `Executor` can finish its work,
+    /// until the client disconnects from the broker.
+    pub async fn run_until_completion(mut self, mut shutdown: watch::Receiver<bool>) -> Result<()> {
+        let mut backoff = Backoff::default();
+        let mut reconnecting = false;
         loop {
-            match self.eventloop.poll().await {
+            let result = tokio::select! {
+                result = self.eventloop.poll() => result,
+                _ = shutdown.changed(), if self.tx.is_some() => {
+                    tracing::info!("Shutting down, no longer producing actions");
+                    self.tx = None;
+                    continue;
+                }
+            };
+
+            match result {
                 Ok(notification) => {
+                    backoff.reset();
+                    self.failover.record_success();
+                    self.health.record_success();
                     match notification {
-                        Incoming(incoming) => match self.handle_incoming_message(incoming).await {
-                            Ok(Some(action)) => {
-                                if let Err(e) = self.tx.send(action).await {
-                                    tracing::error!(error = %e, "Failed to send action");
-                                }
+                        Incoming(Packet::ConnAck(_)) => {
+                            if reconnecting {
+                                reconnecting = false;
+                                self.resubscribe();
                             }
+                            self.publish_availability();
+                        }
+                        Incoming(Packet::SubAck(ack)) => self.log_subscriptions(&ack),
+                        Incoming(incoming) => match self.handle_incoming_message(incoming).await {
+                            Ok(Some(action)) => self.send_action(action).await,
                             Ok(None) => {}
                             Err(e) => {
                                 tracing::error!(error = %e, "Error when handling incomming message");
                             }
                         },
 
-                        // Do nothing for outgoing requests
+                        Outgoing(rumqttc::Outgoing::Disconnect) => {
+                            tracing::info!("Disconnected from MQTT broker");
+                            return Ok(());
+                        }
+
+                        // Do nothing for other outgoing requests
                         Outgoing(_) => {}
                     };
                 }
-                Err(e) => tracing::error!(error = %e),
+                Err(e) => {
+                    let delay = backoff.next_delay();
+                    tracing::error!(error = %e, ?delay, "Failed to poll MQTT eventloop");
+                    if let Some(options) = self.failover.record_failure() {
+                        let (host, port) = options.broker_address();
+                        tracing::warn!(host, port, "Failing over to the next MQTT broker");
+                        self.eventloop.options = options.clone();
+                    }
+                    reconnecting = true;
+                    tokio::time::sleep(delay).await;
+                }
             }
         }
     }
 
-    /// Handle incoming message.
-    #[tracing::instrument(skip(self, message))]
-    async fn handle_incoming_message(&mut self, message: Packet) -> Result<Option<Action>> {
-        if let Packet::Publish(Publish { topic, payload, .. }) = message {
-            match topic.as_ref() {
-                b"temperature/set" => {
-                    let desired_temperature = parse_float_payload(&payload)?;
-                    Ok(Some(Action::SetDesiredTemperature(desired_temperature)))
-                }
-                b"temperature/inside" => {
-                    let temperature = parse_float_payload(&payload)?;
-                    Ok(Some(Action::SetInsideTemperature(temperature)))
-                }
-                b"temperature/auto" if payload.as_ref() == b"true" => {
-                    tracing::info!(state = ?self.state, "Temperature control enabled");
-                    self.state.enabled = true;
-                    Ok(Some(Action::EnableController(true)))
-                }
-                b"temperature/auto" if payload.as_ref() == b"false" => {
-                    tracing::info!(state = ?self.state, "Temperature control disabled");
-                    self.state.enabled = false;
-                    Ok(Some(Action::EnableController(false)))
-                }
-                _ if topic.as_ref().starts_with(b"measurement/") => {
-                    let (place, measurement) = self
-                        .parse_measurement(topic.as_ref(), payload.as_ref())
-                        .await?;
-                    Ok(Some(Action::RegisterMeasurement(place, measurement)))
-                }
-                _ if topic.as_ref().starts_with(b"shellies/") => {
-                    let (heater_id, state) = self
-                        .parse_heater_state_change_message(topic.as_ref(), payload.as_ref())
-                        .await?;
-                    Ok(Some(Action::RegisterHeaterStateChange(heater_id, state)))
+    /// Send an action to the `Executor`, unless shutting down.
+    ///
+    /// Low priority actions are dropped when the channel is full, so a stalled
+    /// executor does not stop the eventloop from being polled. Other actions
+    /// wait for room in the channel, which is reported when it takes longer
+    /// than the slow send threshold.
+    async fn send_action(&mut self, action: Action) {
+        let Some(tx) = &self.tx else {
+            tracing::debug!(?action, "Shutting down, dropping action");
+            return;
+        };
+
+        if action.is_low_priority() {
+            match tx.try_send(action) {
+                Ok(()) => {}
+                Err(TrySendError::Full(action)) => {
+                    self.metrics.dropped_actions.fetch_add(1, Ordering::Relaxed);
+                    tracing::warn!(?action, "Action channel is full, dropping action");
                 }
+                Err(e) => tracing::error!(error = %e, "Failed to send action"),
+            }
+            return;
+        }
 
-                _ => Ok(None),
+        let send = tx.send(action);
+        tokio::pin!(send);
+        let result = match tokio::time::timeout(self.slow_send_threshold, &mut send).await {
+            Ok(result) => result,
+            Err(_) => {
+                self.metrics.slow_sends.fetch_add(1, Ordering::Relaxed);
+                tracing::warn!(
+                    threshold = ?self.slow_send_threshold,
+                    "Action channel is full, waiting for the executor"
+                );
+                send.await
             }
-        } else {
-            tracing::trace!(incoming = ?message, "Unhandled incoming message");
-            Ok(None)
+        };
+        if let Err(e) = result {
+            tracing::error!(error = %e, "Failed to send action");
         }
     }
 
-    /// Handle receiving a measurement reading.
-    #[tracing::instrument(skip(self, topic, payload))]
-    async fn parse_measurement(
-        &mut self,
-        topic: &[u8],
-        payload: &[u8],
-    ) -> Result<(String, Measurement)> {
-        let re = regex::bytes::Regex::new(r#"measurement/(?<place>inside|outside)"#)
-            .expect("invalid regex");
-        let place = re
-            .captures(topic.as_ref())
-            .and_then(|m| m.name("place"))
-            .and_then(|x| std::str::from_utf8(x.as_bytes()).ok())
-            .ok_or_else(|| anyhow!("Received measurement from unknown place: '{:?}'", topic))?;
-
-        let measurement = serde_json::from_slice::<Measurement>(payload)
-            .map_err(|e| anyhow!("Failed to deserialize payload: {payload:?}. {e:?}"))?;
-
-        Ok((place.to_string(), measurement))
-    }
-
-    /// Handle messages published about state changes to heaters.
-    #[tracing::instrument(skip(self, topic, payload))]
-    async fn parse_heater_state_change_message(
-        &mut self,
-        topic: &[u8],
-        payload: &[u8],
-    ) -> Result<(String, HeaterState)> {
-        let re = regex::bytes::Regex::new(r#"shellies/shelly1-(?<id>[A-F0-9]{6})/relay/0"#)
-            .expect("invalid regex");
-        let heater_id = re
-            .captures(topic.as_ref())
-            .and_then(|m| m.name("id"))
-            .and_then(|x| std::str::from_utf8(x.as_bytes()).ok())
-            .ok_or_else(|| anyhow!("Received state from unknown heater: '{:?}'", topic))?;
-        let state = std::str::from_utf8(payload)
-            .context("payload is not utf8")
-            .and_then(|s| HeaterState::from_str(s).context("payload is not a valid state"))?;
+    /// Renew the subscriptions after a reconnect, as they are lost with a
+    /// fresh session. This does not wait for the request to be queued, as the
+    /// eventloop is not polled while this runs.
+    fn resubscribe(&self) {
+        tracing::info!("Reconnected to MQTT broker, renewing subscriptions");
+        if let Err(e) = self
+            .mqtt_client
+            .try_subscribe_many(self.subscriptions.clone())
+        {
+            tracing::error!(error = %e, "Failed to renew subscriptions");
+        }
+    }
+
+    /// Announce that the hub is online on the retained availability topic,
+    /// replacing the last will from a previous connection. Like
+    /// `resubscribe`, this does not wait for the request to be queued.
+    fn publish_availability(&self) {
+        if let Err(e) =
+            self.mqtt_client
+                .try_publish(AVAILABILITY_TOPIC, QoS::AtLeastOnce, true, "online")
+        {
+            tracing::error!(error = %e, "Failed to publish availability");
+        }
+    }
+
+    /// Log the outcome of subscribing to each of the topics. The codes in the
+    /// acknowledgement are in the same order as the requested filters.
+    fn log_subscriptions(&self, ack: &SubAck) {
+        for (filter, code) in self.subscriptions.iter().zip(&ack.return_codes) {
+            match code {
+                SubscribeReasonCode::Success(qos) => {
+                    tracing::info!(topic = filter.path, ?qos, "Subscribed to topic")
+                }
+                code => tracing::error!(topic = filter.path, ?code, "Failed to subscribe to topic"),
+            }
+        }
+    }
 
-        Ok((heater_id.to_string(), state))
+    /// Handle incoming message by translating it to an action. The control
+    /// state is only kept by the `Executor`.
+    #[tracing::instrument(skip(self, message))]
+    async fn handle_incoming_message(&mut self, message: Packet) -> Result<Option<Action>> {
+        if let Packet::Publish(Publish { topic, payload, .. }) = message {
+            let Some(topic) = strip_topic_prefix(&self.topic_prefix, topic) else {
+                return Ok(None);
+            };
+            Ok(self.router.route(&topic, payload)?)
+        } else {
+            tracing::trace!(incoming = ?message, "Unhandled incoming message");
+            Ok(None)
+        }
     }
 }
 
@@ -218,28 +767,338 @@ pub struct Executor {
     mqtt_client: AsyncClient,
     rx: Receiver<Action>,
     db: Arc<Mutex<Database>>,
+    heaters: Vec<Heater>,
+    dwell: RelayDwell,
+    /// Commands the relays have not confirmed yet.
+    confirmations: PendingConfirmations,
+    schedule: Schedule,
+    /// Start of the schedule entry that was last applied. A new entry only
+    /// overrides a manually set desired temperature once it becomes active.
+    applied_schedule_entry: Option<NaiveTime>,
+    /// Readings waiting to be written to the database in a single batch.
+    pending_readings: Vec<NewReading>,
+    /// When the pending readings must be written at the latest.
+    flush_deadline: Option<Instant>,
+    strategy: ControlStrategy,
+    pid_window: Duration,
+    /// The PID regulators of the heaters, keyed by heater id.
+    regulators: HashMap<String, TimeProportional>,
+    snapshot: SharedState,
+    relay_state_timeout: Duration,
+    /// When the manual overrides of heaters expire, keyed by heater id.
+    overrides: HashMap<String, Instant>,
+    command_qos: QoS,
+    /// The last state each relay reported, keyed by heater id. Only changes
+    /// of these are recorded, as retained states are received again after a
+    /// reconnect.
+    reported_states: HashMap<String, HeaterState>,
+    dry_run: bool,
+    /// Prefix of the topics of the heater commands.
+    topic_prefix: String,
+    min_reading_interval: Duration,
+    /// When a reading was last stored, keyed by location.
+    last_stored_readings: HashMap<String, Instant>,
+    /// Events of the handled actions, for the live stream of the HTTP API.
+    events: broadcast::Sender<Event>,
+    default_desired_temperature: f64,
+    db_health: DatabaseHealth,
+    temperature_error: ErrorHistogram,
+    /// When the active boost reverts, if any.
+    boost_expiry: Option<Instant>,
+    /// How often the full state is republished.
+    state_publish_interval: Duration,
+    /// The effective configuration with secrets redacted, published on
+    /// request.
+    config: serde_json::Value,
+    /// Time waiting for the database lock, or a write, may take before a
+    /// warning is logged.
+    slow_db_threshold: Duration,
+}
+
+/// Kinds of messages published by the executor, which differ in how they are
+/// delivered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MessageKind {
+    /// Commands to the relays, which are not retained, so a relay reconnecting
+    /// later does not act on an outdated command.
+    Command,
+    /// The latest status of the hub, retained for new subscribers.
+    Status,
+    /// Notifications of events, which are only of interest when they happen.
+    Alert,
 }
 
 impl Executor {
-    pub fn new(mqtt_client: AsyncClient, db: Arc<Mutex<Database>>, rx: Receiver<Action>) -> Self {
+    pub fn new(
+        mqtt_client: AsyncClient,
+        db: Arc<Mutex<Database>>,
+        rx: Receiver<Action>,
+        heaters: Vec<Heater>,
+        schedule: Schedule,
+        config: &ControlConfig,
+    ) -> Self {
         Self {
-            state: State::default(),
+            state: State::new(config),
             mqtt_client,
             db,
             rx,
+            heaters,
+            dwell: RelayDwell::new(Duration::from_secs(config.min_dwell_secs)),
+            confirmations: PendingConfirmations::new(config),
+            schedule,
+            applied_schedule_entry: None,
+            pending_readings: Vec::new(),
+            flush_deadline: None,
+            strategy: config.strategy,
+            pid_window: Duration::from_secs(config.pid_window_secs),
+            regulators: HashMap::new(),
+            snapshot: SharedState::default(),
+            relay_state_timeout: Duration::from_secs(config.relay_state_timeout_secs),
+            overrides: HashMap::new(),
+            command_qos: qos(config.command_qos).unwrap_or(QoS::AtLeastOnce),
+            reported_states: HashMap::new(),
+            dry_run: config.dry_run,
+            topic_prefix: String::new(),
+            min_reading_interval: Duration::from_secs(config.min_reading_interval_secs),
+            last_stored_readings: HashMap::new(),
+            events: broadcast::channel(EVENT_CAPACITY).0,
+            default_desired_temperature: config.default_desired_temperature,
+            db_health: DatabaseHealth::default(),
+            temperature_error: ErrorHistogram::default(),
+            boost_expiry: None,
+            state_publish_interval: Duration::from_secs(config.state_publish_interval_secs),
+            config: serde_json::Value::Null,
+            slow_db_threshold: Duration::from_millis(config.slow_db_threshold_ms),
+        }
+    }
+
+    /// Apply the thresholds of `config` and add or update its heaters. The
+    /// heaters and schedule are then reloaded from the database. Settings
+    /// used to set up connections and channels only apply after a restart.
+    async fn reload_config(&mut self, config: &Config) -> Result<()> {
+        let control = &config.control;
+        self.state.apply_thresholds(control);
+        self.dwell.min_dwell = Duration::from_secs(control.min_dwell_secs);
+        self.relay_state_timeout = Duration::from_secs(control.relay_state_timeout_secs);
+        self.confirmations.timeout = PendingConfirmations::new(control).timeout;
+        self.min_reading_interval = Duration::from_secs(control.min_reading_interval_secs);
+        self.default_desired_temperature = control.default_desired_temperature;
+        self.slow_db_threshold = Duration::from_millis(control.slow_db_threshold_ms);
+        let (heaters, schedule) = {
+            let db = self.lock_db().await;
+            for heater in config.heaters.iter() {
+                db.upsert_heater(heater).await?;
+            }
+            (db.get_heaters().await?, db.get_schedule().await?)
+        };
+        self.heaters = heaters;
+        self.schedule = schedule;
+        self.config = config.redacted()?;
+        tracing::info!(
+            ?control,
+            heaters = ?self.heaters,
+            schedule = ?self.schedule,
+            "Reloaded configuration"
+        );
+
+        Ok(())
+    }
+
+    /// The QoS and retain flag used to publish a kind of message.
+    fn publish_flags(&self, kind: MessageKind) -> (QoS, bool) {
+        match kind {
+            MessageKind::Command => (self.command_qos, false),
+            MessageKind::Status => (QoS::AtLeastOnce, true),
+            MessageKind::Alert => (QoS::AtLeastOnce, false),
+        }
+    }
+
+    /// Publish a message with the QoS and retain flag of its kind.
+    async fn publish(
+        &self,
+        kind: MessageKind,
+        topic: impl Into<String>,
+        payload: impl Into<Bytes>,
+    ) -> Result<(), HubError> {
+        let (qos, retain) = self.publish_flags(kind);
+        self.mqtt_client
+            .publish(topic, qos, retain, payload)
+            .await
+            .map_err(HubError::from)
+    }
+
+    /// Seed the state with the latest inside reading and desired temperature
+    /// stored in the database, so heating decisions can be made right after a
+    /// restart instead of waiting for new messages. Without a stored desired
+    /// temperature, the configured default is used. A stored desired
+    /// temperature set after the active schedule entry started is kept rather
+    /// than overwritten by that entry.
+    pub async fn restore_state(&mut self) -> Result<()> {
+        let db = self.db.lock().await;
+        if let Some(reading) = db.get_latest_reading(INSIDE).await? {
+            let age = (Utc::now().naive_utc() - *reading.timestamp())
+                .to_std()
+                .unwrap_or_default();
+            tracing::info!(
+                temperature = reading.temperature(),
+                ?age,
+                "Restored inside temperature"
+            );
+            self.state
+                .restore_inside_temperature(*reading.temperature(), age);
+        }
+        if let Some(setpoint) = db.get_latest_setpoint().await? {
+            tracing::info!(
+                temperature = setpoint.temperature(),
+                "Restored desired temperature"
+            );
+            self.state.desired_temperature = Some(*setpoint.temperature());
+            // A setpoint changed after the active schedule entry started
+            // overrides it, so the entry must not be applied again.
+            let now = Local::now();
+            if let Some(entry) = self.schedule.active_entry(now.time()) {
+                let started = entry.last_start(&now).map(|start| start.naive_utc());
+                if started.is_some_and(|start| *setpoint.timestamp() > start) {
+                    self.applied_schedule_entry = Some(*entry.time_of_day());
+                }
+            }
+        } else {
+            tracing::info!(
+                temperature = self.default_desired_temperature,
+                "Using default desired temperature"
+            );
+            self.state.desired_temperature = Some(self.default_desired_temperature);
         }
+
+        Ok(())
+    }
+
+    /// Get a read-only handle to the state of the executor, which is updated
+    /// after each action is handled.
+    pub fn snapshot(&self) -> SharedState {
+        self.snapshot.clone()
+    }
+
+    /// Get a handle to when the executor last wrote to the database.
+    pub fn db_health(&self) -> DatabaseHealth {
+        self.db_health.clone()
+    }
+
+    /// Get a handle to the histogram of how far the heaters are from their
+    /// desired temperatures when evaluated.
+    pub fn temperature_error(&self) -> ErrorHistogram {
+        self.temperature_error.clone()
+    }
+
+    /// Get a sender of the events of the handled actions, which clients can
+    /// subscribe to.
+    pub fn events(&self) -> broadcast::Sender<Event> {
+        self.events.clone()
     }
 
-    /// Run the executor until completion.
-    pub async fn run_until_completion(mut self) -> Result<()> {
-        while let Some(action) = self.rx.recv().await {
-            tracing::debug!("Received action: {action:?}");
+    /// Run the executor until completion, which is when every sender of
+    /// actions has been dropped and all buffered actions have been handled.
+    /// The senders being dropped before `shutdown` is signalled, like when
+    /// the controller panicked, is an error, so the hub is restarted rather
+    /// than left running without actions.
+    pub async fn run_until_completion(mut self, shutdown: watch::Receiver<bool>) -> Result<()> {
+        if let Err(e) = self.publish_discovery().await {
+            tracing::error!(error = %e, "Failed to publish discovery configs");
+        }
+        for action in self.await_relay_states(self.relay_state_timeout).await {
             if let Err(e) = self.handle_action(&action).await {
                 tracing::error!(error = %e, action = ?action, "Failed to handle action");
             }
         }
+        self.snapshot.update(&self.state);
 
-        Ok(())
+        let mut schedule_interval = tokio::time::interval(SCHEDULE_INTERVAL);
+        schedule_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        let mut staleness_interval = tokio::time::interval(STALENESS_CHECK_INTERVAL);
+        staleness_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        let mut state_publish_interval = tokio::time::interval_at(
+            Instant::now() + self.state_publish_interval,
+            self.state_publish_interval,
+        );
+        state_publish_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        loop {
+            let regulator_switch = self.next_regulator_switch();
+            tokio::select! {
+                action = self.rx.recv() => {
+                    let Some(action) = action else {
+                        break;
+                    };
+                    tracing::debug!("Received action: {action:?}");
+                    if let Err(e) = self.handle_action(&action).await {
+                        tracing::error!(error = %e, action = ?action, "Failed to handle action");
+                    }
+                }
+                _ = sleep_until(self.dwell.next_deadline()) => {
+                    if let Err(e) = self.apply_deferred_changes().await {
+                        tracing::error!(error = %e, "Failed to apply deferred heater changes");
+                    }
+                }
+                _ = schedule_interval.tick() => {
+                    if let Err(e) = self.apply_schedule(Local::now().time()).await {
+                        tracing::error!(error = %e, "Failed to apply schedule");
+                    }
+                }
+                _ = sleep_until(self.flush_deadline) => {
+                    if let Err(e) = self.flush_readings().await {
+                        tracing::error!(error = %e, "Failed to write buffered readings");
+                    }
+                }
+                _ = sleep_until(self.overrides.values().min().copied()) => {
+                    if let Err(e) = self.expire_overrides(Instant::now()).await {
+                        tracing::error!(error = %e, "Failed to expire heater overrides");
+                    }
+                }
+                _ = sleep_until(self.confirmations.next_deadline()) => {
+                    if let Err(e) = self.check_confirmations(Instant::now()).await {
+                        tracing::error!(error = %e, "Failed to alert about unconfirmed heater commands");
+                    }
+                }
+                _ = sleep_until(self.boost_expiry) => {
+                    if let Err(e) = self.expire_boost(Instant::now()).await {
+                        tracing::error!(error = %e, "Failed to expire boost");
+                    }
+                }
+                _ = sleep_until(regulator_switch) => {
+                    if let Err(e) = self.check_temperature().await {
+                        tracing::error!(error = %e, "Failed to switch regulated heaters");
+                    }
+                }
+                _ = staleness_interval.tick() => {
+                    if let Err(e) = self.check_staleness(Instant::now()).await {
+                        tracing::error!(error = %e, "Failed to check inside sensor staleness");
+                    }
+                }
+                _ = state_publish_interval.tick() => {
+                    if let Err(e) = self.publish_full_state().await {
+                        tracing::error!(error = %e, "Failed to republish the full state");
+                    }
+                }
+            }
+            self.snapshot.update(&self.state);
+        }
+
+        if let Err(e) = self.flush_readings().await {
+            tracing::error!(error = %e, "Failed to write buffered readings");
+        }
+        if !*shutdown.borrow() {
+            tracing::error!("The action channel closed without shutting down");
+            return Err(anyhow!("The action channel closed unexpectedly"));
+        }
+
+        tracing::info!("No more actions to handle, disconnecting from MQTT broker");
+        // The last will is not published on a clean disconnect.
+        self.publish(MessageKind::Status, AVAILABILITY_TOPIC, "offline")
+            .await
+            .context("Failed to publish availability")?;
+        self.mqtt_client
+            .disconnect()
+            .await
+            .context("Failed to disconnect from MQTT broker")
     }
 
     /// Handle an action received through the subscribed channel.
@@ -247,12 +1106,17 @@ impl Executor {
     async fn handle_action(&mut self, action: &Action) -> Result<()> {
         use Action::*;
         match action {
-            SetDesiredTemperature(temp) => {
-                self.state.desired_temperature = Some(*temp);
+            SetDesiredTemperature(temp, source) => {
+                self.set_desired_temperature(*temp, *source).await?;
+            }
+            SetHeaterDesiredTemperature(heater_id, temp) => {
+                self.state
+                    .heater_desired_temperatures
+                    .insert(heater_id.clone(), *temp);
                 self.check_temperature().await?;
             }
             SetInsideTemperature(temp) => {
-                self.state.current_temperature = Some(*temp);
+                self.state.record_temperature(INSIDE, *temp);
                 self.check_temperature().await?;
             }
             EnableController(enabled) => {
@@ -260,95 +1124,4348 @@ impl Executor {
                 self.state.enabled = *enabled;
                 self.check_temperature().await?;
             }
+            SetMode(mode) => {
+                tracing::info!(%mode, "Mode changed");
+                self.state.mode = *mode;
+                self.publish_mode().await?;
+                self.check_temperature().await?;
+            }
             RegisterMeasurement(place, measurement) => {
-                self.db
-                    .lock()
-                    .await
-                    .insert_reading(
-                        place,
-                        *measurement.temperature() as i64,
-                        *measurement.humidity() as i64,
-                    )
+                if self.should_store_reading(place, Instant::now()) {
+                    self.buffer_reading(NewReading {
+                        location: place.clone(),
+                        temperature: *measurement.temperature(),
+                        humidity: *measurement.humidity(),
+                        battery: *measurement.battery(),
+                        timestamp: measurement.timestamp().map(|t| t.naive_utc()),
+                    })
                     .await?;
+                } else {
+                    tracing::trace!(place, "Reading arrived too soon to be stored");
+                }
+                if let Some(battery) = measurement
+                    .battery()
+                    .filter(|battery| *battery < LOW_BATTERY_THRESHOLD)
+                {
+                    tracing::warn!(place, battery, "Sensor battery is low");
+                }
+                self.state
+                    .record_temperature(place, *measurement.temperature());
+                if self.is_control_place(measurement_place(place)) {
+                    self.check_temperature().await?;
+                } else {
+                    tracing::trace!(place, "Reading is not used by the control");
+                }
             }
             RegisterHeaterStateChange(heater_id, state) => {
-                self.db
-                    .lock()
-                    .await
-                    .insert_heater_state(heater_id, *state)
+                self.confirmations.confirm(heater_id, *state);
+                self.state.heater_states.insert(heater_id.clone(), *state);
+                if self.reported_states.insert(heater_id.clone(), *state) == Some(*state) {
+                    tracing::trace!(heater_id, ?state, "Relay reported an unchanged state");
+                } else {
+                    self.timed_write(
+                        "insert_heater_state",
+                        self.lock_db().await.insert_heater_state(heater_id, *state),
+                    )
                     .await?;
+                }
+            }
+            RegisterHeaterPower(heater_id, power) => {
+                self.timed_write(
+                    "insert_heater_power",
+                    self.lock_db().await.insert_heater_power(heater_id, *power),
+                )
+                .await?;
+            }
+            RegisterHeaterEnergy(heater_id, energy) => {
+                self.timed_write(
+                    "insert_heater_energy",
+                    self.lock_db()
+                        .await
+                        .insert_heater_energy(heater_id, *energy),
+                )
+                .await?;
+            }
+            RegisterDeadLetter(topic, payload, error) => {
+                self.timed_write(
+                    "insert_dead_letter",
+                    self.lock_db()
+                        .await
+                        .insert_dead_letter(topic, payload, error),
+                )
+                .await?;
+            }
+            OverrideHeater {
+                id,
+                state,
+                duration,
+            } => {
+                self.override_heater(id, *state, *duration).await?;
+            }
+            Reevaluate => {
+                tracing::info!(state = ?self.state, "Reevaluating heaters");
+                self.check_temperature().await?;
+            }
+            Boost { delta, duration } => {
+                tracing::info!(delta, ?duration, "Boosting desired temperature");
+                self.state.boost = *delta;
+                self.boost_expiry = Some(Instant::now() + *duration);
+                self.publish_setpoint().await?;
+                self.check_temperature().await?;
+            }
+            PublishConfig => {
+                let payload =
+                    serde_json::to_vec(&self.config).context("Failed to serialize config")?;
+                self.publish(MessageKind::Alert, "hub/config", payload)
+                    .await
+                    .context("Failed to publish config")?;
+            }
+            ReloadConfig(config) => {
+                self.reload_config(config).await?;
+                self.check_temperature().await?;
             }
         }
 
+        if let Some(event) = Event::from_action(action) {
+            // Sending only fails when nobody is listening.
+            let _ = self.events.send(event);
+        }
+
         Ok(())
     }
 
-    /// Set the heaters to either on or off.
-    #[tracing::instrument(skip(self))]
-    async fn set_heaters_state(&self, state: HeaterState) -> Result<()> {
-        for heater in HEATERS.iter() {
-            self.mqtt_client
-                .publish(
-                    format!("shellies/shelly1-{}/relay/0/command", heater.id()),
-                    QoS::AtLeastOnce,
-                    true,
-                    state.to_string(),
-                )
-                .await
-                .context("Failed to publish to MQTT")?;
-        }
+    /// Whether the temperature of `place` affects the heaters, as it governs
+    /// one of them or is used by the control. Readings of other places are
+    /// only stored.
+    fn is_control_place(&self, place: &str) -> bool {
+        MEASUREMENT_PLACES.contains(&place) || self.heaters.iter().any(|h| h.place() == place)
+    }
+
+    /// Force a heater to `state` until `duration` has elapsed, during which
+    /// the temperature control leaves it alone. The safety ceiling still
+    /// turns it off.
+    async fn override_heater(
+        &mut self,
+        heater_id: &str,
+        state: HeaterState,
+        duration: Duration,
+    ) -> Result<()> {
+        let Some(heater) = self.heaters.iter().find(|h| h.id() == heater_id) else {
+            return Err(anyhow!("Cannot override unknown heater '{heater_id}'"));
+        };
+        tracing::info!(heater_id, %state, ?duration, "Overriding heater");
+
+        let now = Instant::now();
+        self.set_heater_state(heater, state)
+            .await
+            .context("Failed to set heater state")?;
+        self.confirmations.expect(heater_id, state, now);
+        self.dwell.force(heater_id, now);
+        self.state
+            .heater_states
+            .insert(heater_id.to_string(), state);
+        self.overrides.insert(heater_id.to_string(), now + duration);
 
         Ok(())
     }
 
-    /// Check the current temperature against the desired temperature and
-    /// update the heaters as needed.
-    #[tracing::instrument(skip(self), fields(state = ?self.state))]
-    async fn check_temperature(&self) -> Result<()> {
-        if !self.state.enabled {
-            tracing::info!(state = ?self.state, "Controller is disabled");
+    /// Return the heaters whose override has expired at `now` to the
+    /// temperature control.
+    #[tracing::instrument(skip(self))]
+    async fn expire_overrides(&mut self, now: Instant) -> Result<()> {
+        let count = self.overrides.len();
+        self.overrides.retain(|heater_id, expiry| {
+            let active = *expiry > now;
+            if !active {
+                tracing::info!(heater_id, "Heater override expired");
+            }
+            active
+        });
+        if self.overrides.len() == count {
             return Ok(());
         }
 
-        let Some(heater_state) = self.state.get_heater_state() else {
-            tracing::warn!(state = ?self.state, "Missing desired or current temperature");
-            return Ok(());
-        };
-
-        self.set_heaters_state(heater_state)
-            .await
-            .context("Failed to set heater state")
+        self.check_temperature().await
     }
-}
 
-/// Represents the state of the heating system, including whether the automated
-/// temperature control is enabled or not.
-#[derive(Debug, Default)]
-struct State {
-    enabled: bool,
-    desired_temperature: Option<f64>,
-    current_temperature: Option<f64>,
-}
+    /// Revert the desired temperatures once the boost has expired at `now`.
+    #[tracing::instrument(skip(self))]
+    async fn expire_boost(&mut self, now: Instant) -> Result<()> {
+        match self.boost_expiry {
+            Some(expiry) if expiry <= now => {}
+            _ => return Ok(()),
+        }
+        tracing::info!("Boost expired");
+        self.boost_expiry = None;
+        self.state.boost = 0.0;
+        self.publish_setpoint().await?;
 
-impl State {
-    pub fn get_heater_state(&self) -> Option<HeaterState> {
-        self.desired_temperature
-            .zip(self.current_temperature)
-            .map(|(desired, current)| {
-                if desired > current {
-                    HeaterState::On
-                } else {
-                    HeaterState::Off
-                }
-            })
+        self.check_temperature().await
     }
-}
 
-/// Parse `Bytes` which represents the string representation of a float.
-fn parse_float_payload(payload: &Bytes) -> Result<f64> {
-    payload
-        .escape_ascii()
-        .to_string()
-        .parse::<f64>()
-        .context("Failed to parse temperature to float")
+    /// Ask the relays to report their state, and wait up to `timeout` for all
+    /// of them to do so. Heaters that do not report in time are marked as
+    /// unknown. Other actions received meanwhile are returned, so they can be
+    /// handled once the state of the relays is known.
+    #[tracing::instrument(skip(self))]
+    async fn await_relay_states(&mut self, timeout: Duration) -> Vec<Action> {
+        for heater in self.heaters.iter() {
+            if let Err(e) = self.request_relay_state(heater).await {
+                tracing::error!(error = %e, heater_id = heater.id(), "Failed to request relay state");
+            }
+        }
+
+        let deadline = Instant::now() + timeout;
+        let mut deferred = Vec::new();
+        while !self.relay_states_known() {
+            tokio::select! {
+                action = self.rx.recv() => match action {
+                    Some(action @ Action::RegisterHeaterStateChange(..)) => {
+                        if let Err(e) = self.handle_action(&action).await {
+                            tracing::error!(error = %e, action = ?action, "Failed to handle action");
+                        }
+                    }
+                    Some(action) => deferred.push(action),
+                    None => break,
+                },
+                _ = tokio::time::sleep_until(deadline) => break,
+            }
+        }
+
+        for heater in self.heaters.iter() {
+            if !self.state.heater_states.contains_key(heater.id()) {
+                tracing::warn!(heater_id = heater.id(), "Relay did not report its state");
+                self.state
+                    .heater_states
+                    .insert(heater.id().clone(), HeaterState::Unknown);
+            }
+        }
+
+        deferred
+    }
+
+    /// Whether every heater has reported the state of its relay.
+    fn relay_states_known(&self) -> bool {
+        self.heaters
+            .iter()
+            .all(|heater| self.state.heater_states.contains_key(heater.id()))
+    }
+
+    /// Ask a Shelly relay to publish its current state.
+    async fn request_relay_state(&self, heater: &Heater) -> Result<()> {
+        let (topic, payload) = heater.firmware().state_request(heater.id());
+        self.publish(
+            MessageKind::Command,
+            format!("{}{topic}", self.topic_prefix),
+            payload,
+        )
+        .await
+        .context("Failed to publish to MQTT")
+    }
+
+    /// Whether a reading of `place` arriving `now` is stored, which it is not
+    /// when the previous stored reading is more recent than the minimum
+    /// interval.
+    fn should_store_reading(&mut self, place: &str, now: Instant) -> bool {
+        match self.last_stored_readings.get(place) {
+            Some(last) if now.duration_since(*last) < self.min_reading_interval => false,
+            _ => {
+                self.last_stored_readings.insert(place.to_string(), now);
+                true
+            }
+        }
+    }
+
+    /// Buffer a reading to be written to the database, writing the buffer
+    /// once it is full.
+    async fn buffer_reading(&mut self, reading: NewReading) -> Result<()> {
+        self.pending_readings.push(reading);
+        self.flush_deadline
+            .get_or_insert_with(|| Instant::now() + READING_FLUSH_INTERVAL);
+        if self.pending_readings.len() >= READING_BATCH_SIZE {
+            self.flush_readings().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Write all buffered readings to the database in a single transaction.
+    /// When the write fails, the readings are kept and retried later.
+    #[tracing::instrument(skip(self), fields(count = self.pending_readings.len()))]
+    async fn flush_readings(&mut self) -> Result<()> {
+        self.flush_deadline = None;
+        if self.pending_readings.is_empty() {
+            return Ok(());
+        }
+
+        let result = self
+            .timed_write(
+                "insert_readings_batch",
+                self.lock_db()
+                    .await
+                    .insert_readings_batch(&self.pending_readings),
+            )
+            .await;
+        if let Err(e) = result {
+            // Keep the readings to write them with the next flush instead.
+            self.flush_deadline = Some(Instant::now() + READING_FLUSH_INTERVAL);
+            return Err(e.into());
+        }
+        self.pending_readings.clear();
+
+        Ok(())
+    }
+
+    /// Lock the database, warning when waiting for the lock takes longer than
+    /// the slow database threshold, e.g. because a long query holds it.
+    async fn lock_db(&self) -> MutexGuard<'_, Database> {
+        let started = Instant::now();
+        let db = self.db.lock().await;
+        let waited = started.elapsed();
+        if waited > self.slow_db_threshold {
+            self.db_health
+                .slow_lock_waits
+                .fetch_add(1, Ordering::Relaxed);
+            tracing::warn!(
+                ?waited,
+                threshold = ?self.slow_db_threshold,
+                "Waited long for the database lock"
+            );
+        }
+        db
+    }
+
+    /// Run a write to the database, warning when it takes longer than the
+    /// slow database threshold, and record it when it succeeds.
+    async fn timed_write<T>(
+        &self,
+        operation: &'static str,
+        write: impl Future<Output = Result<T, HubError>>,
+    ) -> Result<T, HubError> {
+        let started = Instant::now();
+        let result = write.await;
+        let elapsed = started.elapsed();
+        if elapsed > self.slow_db_threshold {
+            self.db_health
+                .slow_operations
+                .fetch_add(1, Ordering::Relaxed);
+            tracing::warn!(
+                operation,
+                ?elapsed,
+                threshold = ?self.slow_db_threshold,
+                "Database write was slow"
+            );
+        }
+        if result.is_ok() {
+            self.db_health.record_write();
+        }
+        result
+    }
+
+    /// Set a heater to either on or off. In a dry run the command is only
+    /// logged.
+    #[tracing::instrument(skip(self))]
+    async fn set_heater_state(&self, heater: &Heater, state: HeaterState) -> Result<()> {
+        if self.dry_run {
+            tracing::info!(heater_id = heater.id(), %state, "Dry run, not publishing heater command");
+            return Ok(());
+        }
+        let firmware = heater.firmware();
+        self.publish(
+            MessageKind::Command,
+            format!(
+                "{}{}",
+                self.topic_prefix,
+                firmware.command_topic(heater.id())
+            ),
+            firmware.command_payload(state),
+        )
+        .await
+        .context("Failed to publish to MQTT")
+    }
+
+    /// Publish the state the controller wants a heater to be in to the
+    /// retained `hub/status/heater/<id>` topic.
+    #[tracing::instrument(skip(self))]
+    async fn publish_heater_status(&self, heater: &Heater, state: TargetState) -> Result<()> {
+        let status = HeaterStatus {
+            state,
+            desired_temperature: self.state.desired_temperature_for(heater.id()),
+            current_temperature: self.state.temperatures.get(heater.place()).copied(),
+        };
+        let payload = serde_json::to_vec(&status).context("Failed to serialize heater status")?;
+
+        self.publish(
+            MessageKind::Status,
+            format!("hub/status/heater/{}", heater.id()),
+            payload,
+        )
+        .await
+        .context("Failed to publish heater status")
+    }
+
+    /// Publish the Home Assistant discovery configs of the heaters and the
+    /// measurement places.
+    #[tracing::instrument(skip(self))]
+    async fn publish_discovery(&self) -> Result<()> {
+        let configs = self
+            .heaters
+            .iter()
+            .map(|heater| discovery::heater_config(heater, &self.topic_prefix))
+            .chain(
+                MEASUREMENT_PLACES
+                    .into_iter()
+                    .map(|place| discovery::sensor_config(place, &self.topic_prefix)),
+            );
+        for (topic, config) in configs {
+            let payload =
+                serde_json::to_vec(&config).context("Failed to serialize discovery config")?;
+            self.publish(MessageKind::Status, topic, payload)
+                .await
+                .context("Failed to publish discovery config")?;
+        }
+
+        Ok(())
+    }
+
+    /// Publish the active mode to the retained `hub/status/mode` topic.
+    #[tracing::instrument(skip(self))]
+    async fn publish_mode(&self) -> Result<()> {
+        self.publish(
+            MessageKind::Status,
+            "hub/status/mode",
+            self.state.mode.to_string(),
+        )
+        .await
+        .context("Failed to publish mode")
+    }
+
+    /// Publish the desired temperature, including an active boost, to the
+    /// retained `hub/status/setpoint` topic.
+    #[tracing::instrument(skip(self))]
+    async fn publish_setpoint(&self) -> Result<()> {
+        let Some(desired) = self.state.desired_temperature else {
+            return Ok(());
+        };
+        self.publish(
+            MessageKind::Status,
+            "hub/status/setpoint",
+            self.state.boosted(desired).to_string(),
+        )
+        .await
+        .context("Failed to publish setpoint")
+    }
+
+    /// Use `config` as the effective configuration published on
+    /// `hub/config`, which must not contain any secrets.
+    pub fn with_config(mut self, config: serde_json::Value) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Republish the mode, the setpoint, and the status of every heater, so
+    /// subscribers that missed an update catch up without a restart.
+    #[tracing::instrument(skip(self))]
+    async fn publish_full_state(&self) -> Result<()> {
+        self.publish_mode().await?;
+        self.publish_setpoint().await?;
+        for heater in self.heaters.iter() {
+            let target = if self.state.enabled {
+                TargetState::from_heater_state(self.state.heater_state(heater.id()))
+            } else {
+                Some(TargetState::Idle)
+            };
+            if let Some(target) = target {
+                self.publish_heater_status(heater, target).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The next time a PID regulated heater should be switched, if any.
+    fn next_regulator_switch(&self) -> Option<Instant> {
+        let now = Instant::now();
+        self.regulators
+            .values()
+            .filter_map(|regulator| regulator.next_switch(now))
+            .filter(|switch| *switch > now)
+            .min()
+    }
+
+    /// Check the current temperature against the desired temperature of each
+    /// heater and update the heaters as needed.
+    #[tracing::instrument(skip(self), fields(state = ?self.state))]
+    async fn check_temperature(&mut self) -> Result<()> {
+        if let Some(alert) = self.state.update_over_temperature(&self.heaters) {
+            self.publish_alert("temperature", &alert).await?;
+        }
+        if self.state.over_temperature {
+            return self.force_heaters_off().await;
+        }
+
+        if !self.state.enabled {
+            tracing::info!(state = ?self.state, "Controller is disabled");
+        }
+
+        let now = Instant::now();
+        for heater in self.heaters.iter() {
+            if self.overrides.contains_key(heater.id()) {
+                tracing::debug!(heater_id = heater.id(), "Heater is overridden");
+                continue;
+            }
+            if self.state.enabled {
+                if let Some(error) = self.state.temperature_error(heater) {
+                    self.temperature_error.observe(error);
+                }
+            }
+            let target = match self.strategy {
+                ControlStrategy::OnOff => self.state.target_state(heater),
+                ControlStrategy::Pid { kp, ki, kd } => {
+                    let window = self.pid_window;
+                    let regulator =
+                        self.regulators
+                            .entry(heater.id().clone())
+                            .or_insert_with(|| {
+                                TimeProportional::new(PidController::new(kp, ki, kd), window)
+                            });
+                    self.state.target_state_with(heater, |_, desired, current| {
+                        regulator.state(desired - current, now)
+                    })
+                }
+            };
+            let Some(target) = target else {
+                continue;
+            };
+            self.publish_heater_status(heater, target).await?;
+            let Some(heater_state) = target.heater_state() else {
+                continue;
+            };
+
+            let current = self.state.heater_state(heater.id());
+            if !self
+                .dwell
+                .request(heater.id(), current, heater_state, Instant::now())
+            {
+                tracing::debug!(
+                    heater_id = heater.id(),
+                    state = %heater_state,
+                    "Minimum dwell time not elapsed, deferring heater change"
+                );
+                continue;
+            }
+
+            self.set_heater_state(heater, heater_state)
+                .await
+                .context("Failed to set heater state")?;
+            self.confirmations.expect(heater.id(), heater_state, now);
+            self.state
+                .heater_states
+                .insert(heater.id().clone(), heater_state);
+        }
+
+        Ok(())
+    }
+
+    /// Turn off the heaters that are not already off immediately, bypassing
+    /// the minimum dwell time.
+    #[tracing::instrument(skip(self))]
+    async fn force_heaters_off(&mut self) -> Result<()> {
+        for heater in self.heaters.iter() {
+            if self.state.heater_state(heater.id()) == HeaterState::Off {
+                continue;
+            }
+            tracing::warn!(
+                heater_id = heater.id(),
+                "Temperature above safety ceiling, forcing heater off"
+            );
+            self.publish_heater_status(heater, TargetState::Off).await?;
+            self.set_heater_state(heater, HeaterState::Off)
+                .await
+                .context("Failed to set heater state")?;
+            let now = Instant::now();
+            self.confirmations
+                .expect(heater.id(), HeaterState::Off, now);
+            self.dwell.force(heater.id(), now);
+            self.state
+                .heater_states
+                .insert(heater.id().clone(), HeaterState::Off);
+        }
+
+        Ok(())
+    }
+
+    /// Publish an alert to the `hub/alert/<kind>` topic.
+    #[tracing::instrument(skip(self, alert))]
+    async fn publish_alert(&self, kind: &str, alert: &impl serde::Serialize) -> Result<()> {
+        let payload = serde_json::to_vec(alert).context("Failed to serialize alert")?;
+
+        self.publish(MessageKind::Alert, format!("hub/alert/{kind}"), payload)
+            .await
+            .context("Failed to publish alert")
+    }
+
+    /// Set the desired temperature from the schedule entry active at `time`,
+    /// if it is not the entry that was applied last.
+    #[tracing::instrument(skip(self))]
+    async fn apply_schedule(&mut self, time: NaiveTime) -> Result<()> {
+        let Some(entry) = self.schedule.active_entry(time) else {
+            return Ok(());
+        };
+        if self.applied_schedule_entry == Some(*entry.time_of_day()) {
+            return Ok(());
+        }
+
+        tracing::info!(?entry, "Applying schedule entry");
+        self.applied_schedule_entry = Some(*entry.time_of_day());
+        let desired_temperature = *entry.desired_temperature();
+        self.set_desired_temperature(desired_temperature, SetpointSource::Schedule)
+            .await
+    }
+
+    /// Set the desired temperature for heaters without a specific target,
+    /// recording where the change came from when it differs from the current.
+    async fn set_desired_temperature(&mut self, temp: f64, source: SetpointSource) -> Result<()> {
+        if self.state.desired_temperature != Some(temp) {
+            self.timed_write(
+                "insert_setpoint_change",
+                self.lock_db().await.insert_setpoint_change(temp, source),
+            )
+            .await?;
+        }
+        self.state.desired_temperature = Some(temp);
+        self.check_temperature().await
+    }
+
+    /// Turn off the heaters governed by the inside sensor if it has not
+    /// reported a reading within the staleness threshold at `now`.
+    #[tracing::instrument(skip(self))]
+    async fn check_staleness(&mut self, now: Instant) -> Result<()> {
+        if !self.state.update_staleness(now) {
+            return Ok(());
+        }
+
+        tracing::warn!(
+            stale_after = ?self.state.stale_after,
+            "Inside sensor is stale, turning heaters off"
+        );
+        self.check_temperature().await
+    }
+
+    /// Apply the heater changes that were deferred because of the minimum
+    /// dwell time and are now due.
+    #[tracing::instrument(skip(self))]
+    async fn apply_deferred_changes(&mut self) -> Result<()> {
+        let now = Instant::now();
+        for (heater_id, heater_state) in self.dwell.take_due(now) {
+            let Some(heater) = self.heaters.iter().find(|h| h.id() == &heater_id) else {
+                continue;
+            };
+            tracing::info!(heater_id, state = %heater_state, "Applying deferred heater change");
+
+            self.set_heater_state(heater, heater_state)
+                .await
+                .context("Failed to set heater state")?;
+            self.confirmations.expect(&heater_id, heater_state, now);
+            self.state.heater_states.insert(heater_id, heater_state);
+        }
+
+        Ok(())
+    }
+
+    /// Alert about the heater commands the relays have not confirmed within
+    /// the timeout at `now`.
+    #[tracing::instrument(skip(self))]
+    async fn check_confirmations(&mut self, now: Instant) -> Result<()> {
+        let Some(timeout) = self.confirmations.timeout else {
+            return Ok(());
+        };
+        for (heater_id, state) in self.confirmations.take_expired(now) {
+            tracing::error!(heater_id, %state, ?timeout, "Relay did not confirm heater command");
+            let alert = HeaterAlert {
+                heater_id,
+                state,
+                timeout_secs: timeout.as_secs(),
+            };
+            self.publish_alert("heater", &alert).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Default width of the hysteresis band around the desired temperature in °C.
+const DEFAULT_HYSTERESIS: f64 = 0.5;
+
+/// Default temperature in °C below which heaters are forced on, even when the
+/// automated temperature control is disabled.
+const DEFAULT_FROST_PROTECTION_TEMPERATURE: f64 = 5.0;
+
+/// Default temperature in °C above which all heaters are forced off.
+const DEFAULT_MAX_TEMPERATURE: f64 = 30.0;
+
+/// Default number of inside readings averaged, where 1 disables smoothing.
+const DEFAULT_SMOOTHING_WINDOW: usize = 1;
+
+/// Default time without an inside reading after which the inside temperature
+/// is considered unknown.
+const DEFAULT_STALE_AFTER: Duration = Duration::from_secs(15 * 60);
+
+/// Default offset in °C applied to the desired temperatures in away mode.
+const DEFAULT_AWAY_OFFSET: f64 = -4.0;
+
+/// Default offset in °C applied to the desired temperatures in eco mode.
+const DEFAULT_ECO_OFFSET: f64 = -2.0;
+
+/// Default time to wait for the relays to report their state on startup.
+const DEFAULT_RELAY_STATE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Default time a relay has to confirm the state it was commanded to.
+const DEFAULT_COMMAND_CONFIRMATION_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_COMMAND_QOS: u8 = 1;
+const DEFAULT_MIN_READING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Default desired temperature in °C used on startup when none was stored.
+const DEFAULT_DESIRED_TEMPERATURE: f64 = 20.0;
+
+/// Default time between republishing the full state.
+const DEFAULT_STATE_PUBLISH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Default length of the time-proportional window used by the PID strategy.
+const DEFAULT_PID_WINDOW: Duration = Duration::from_secs(600);
+
+/// Linear curve raising the desired temperature when it is cold outside, to
+/// counter the larger heat loss.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct WeatherCompensation {
+    /// Increase in °C of the desired temperature per °C the outside
+    /// temperature is below the reference.
+    pub slope: f64,
+    /// Outside temperature in °C at and above which nothing is added.
+    pub reference_outside_temperature: f64,
+    /// Maximum increase in °C of the desired temperature.
+    pub max_offset: f64,
+}
+
+impl WeatherCompensation {
+    /// The increase of the desired temperature at the `outside` temperature.
+    fn offset(&self, outside: f64) -> f64 {
+        (self.slope * (self.reference_outside_temperature - outside)).clamp(0.0, self.max_offset)
+    }
+}
+
+/// How the heaters are switched to reach the desired temperature.
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ControlStrategy {
+    /// Switch on below and off above the hysteresis band.
+    #[default]
+    OnOff,
+    /// Run a PID loop per heater and switch the relay on for a share of each
+    /// window proportional to its output.
+    Pid { kp: f64, ki: f64, kd: f64 },
+}
+
+/// Settings of the temperature control.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct ControlConfig {
+    /// Width of the hysteresis band around the desired temperature in °C.
+    pub hysteresis: f64,
+    /// Minimum time in seconds a heater relay stays in a state.
+    pub min_dwell_secs: u64,
+    /// Temperature in °C below which heaters are forced on.
+    pub frost_protection_temperature: f64,
+    /// Temperature in °C above which all heaters are forced off.
+    pub max_temperature: f64,
+    /// Offset in °C applied to the desired temperatures in away mode.
+    pub away_offset: f64,
+    /// Offset in °C applied to the desired temperatures in eco mode.
+    pub eco_offset: f64,
+    /// Compensation of the desired temperatures for the outside temperature,
+    /// which is disabled when not set.
+    pub weather_compensation: Option<WeatherCompensation>,
+    /// Number of inside readings averaged, where 1 disables smoothing.
+    pub smoothing_window: usize,
+    /// Time in seconds without an inside reading after which it is stale.
+    pub stale_after_secs: u64,
+    /// Strategy used to switch the heaters.
+    pub strategy: ControlStrategy,
+    /// Length in seconds of the window a PID output is spread over.
+    pub pid_window_secs: u64,
+    /// Number of actions buffered between the controller and the executor.
+    pub action_channel_capacity: usize,
+    /// Time in milliseconds a send to the executor may block before a
+    /// warning is logged.
+    pub slow_send_threshold_ms: u64,
+    /// Time in milliseconds waiting for the database lock, or a write to the
+    /// database, may take before a warning is logged.
+    pub slow_db_threshold_ms: u64,
+    /// Range of readings accepted from the sensors.
+    pub measurement_bounds: MeasurementBounds,
+    /// Offsets added to the measurements of a location, keyed by location,
+    /// before they are used by the control and stored.
+    pub calibration: HashMap<String, Calibration>,
+    /// Time in seconds to wait for the relays to report their state on
+    /// startup, before the first decision is made.
+    pub relay_state_timeout_secs: u64,
+    /// Time in seconds a relay has to confirm the state it was commanded to,
+    /// before an alert is published. 0 disables the check.
+    pub command_confirmation_timeout_secs: u64,
+    /// QoS level 0, 1, or 2 of the commands sent to the relays.
+    pub command_qos: u8,
+    /// Log the commands to the relays instead of publishing them.
+    pub dry_run: bool,
+    /// Minimum time in seconds between readings stored for a location.
+    /// Readings arriving sooner are still used by the control, but not
+    /// stored.
+    pub min_reading_interval_secs: u64,
+    /// Desired temperature in °C used on startup when none is stored in the
+    /// database.
+    pub default_desired_temperature: f64,
+    /// Time in seconds between republishing the full state to the retained
+    /// status topics.
+    pub state_publish_interval_secs: u64,
+}
+
+impl Default for ControlConfig {
+    fn default() -> Self {
+        Self {
+            hysteresis: DEFAULT_HYSTERESIS,
+            min_dwell_secs: DEFAULT_MIN_DWELL.as_secs(),
+            frost_protection_temperature: DEFAULT_FROST_PROTECTION_TEMPERATURE,
+            max_temperature: DEFAULT_MAX_TEMPERATURE,
+            away_offset: DEFAULT_AWAY_OFFSET,
+            eco_offset: DEFAULT_ECO_OFFSET,
+            weather_compensation: None,
+            smoothing_window: DEFAULT_SMOOTHING_WINDOW,
+            stale_after_secs: DEFAULT_STALE_AFTER.as_secs(),
+            strategy: ControlStrategy::default(),
+            pid_window_secs: DEFAULT_PID_WINDOW.as_secs(),
+            action_channel_capacity: DEFAULT_ACTION_CHANNEL_CAPACITY,
+            slow_send_threshold_ms: DEFAULT_SLOW_SEND_THRESHOLD.as_millis() as u64,
+            slow_db_threshold_ms: DEFAULT_SLOW_DB_THRESHOLD.as_millis() as u64,
+            measurement_bounds: MeasurementBounds::default(),
+            calibration: HashMap::new(),
+            relay_state_timeout_secs: DEFAULT_RELAY_STATE_TIMEOUT.as_secs(),
+            command_confirmation_timeout_secs: DEFAULT_COMMAND_CONFIRMATION_TIMEOUT.as_secs(),
+            command_qos: DEFAULT_COMMAND_QOS,
+            dry_run: false,
+            min_reading_interval_secs: DEFAULT_MIN_READING_INTERVAL.as_secs(),
+            default_desired_temperature: DEFAULT_DESIRED_TEMPERATURE,
+            state_publish_interval_secs: DEFAULT_STATE_PUBLISH_INTERVAL.as_secs(),
+        }
+    }
+}
+
+impl ControlConfig {
+    /// Fail if a value is outside of the range the executor can work with.
+    pub fn validate(&self) -> Result<()> {
+        if self.action_channel_capacity == 0 {
+            return Err(anyhow!("The action channel capacity must be at least 1"));
+        }
+        if qos(self.command_qos).is_none() {
+            return Err(anyhow!("The command QoS must be 0, 1, or 2"));
+        }
+        if self.state_publish_interval_secs == 0 {
+            return Err(anyhow!(
+                "The state publish interval must be at least 1 second"
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Represents the state of the heating system, including whether the automated
+/// temperature control is enabled or not.
+#[derive(Debug)]
+struct State {
+    enabled: bool,
+    /// Desired temperature for heaters without a specific target.
+    desired_temperature: Option<f64>,
+    /// Desired temperatures for specific heaters, keyed by heater id.
+    heater_desired_temperatures: HashMap<String, f64>,
+    /// The latest temperature reading, keyed by measurement place. For the
+    /// inside place this is the average of the smoothing window.
+    temperatures: HashMap<String, f64>,
+    /// The latest reading of each sensor and when it was received, keyed by
+    /// its location.
+    sensor_temperatures: HashMap<String, (f64, Instant)>,
+    /// Number of inside readings averaged to smooth out sensor noise.
+    smoothing_window: usize,
+    /// The most recent raw inside readings, at most `smoothing_window` long.
+    inside_readings: VecDeque<f64>,
+    /// When the last inside reading was received.
+    inside_updated_at: Option<Instant>,
+    /// Time without an inside reading after which it is considered stale.
+    stale_after: Duration,
+    /// Whether the inside reading is stale, in which case the heaters governed
+    /// by it are turned off.
+    inside_stale: bool,
+    /// Width of the band around the desired temperature within which the
+    /// heaters are left in their current state.
+    hysteresis: f64,
+    /// The last state each heater was commanded to, keyed by heater id.
+    heater_states: HashMap<String, HeaterState>,
+    /// Temperature below which heaters are forced on to prevent frost.
+    frost_protection_temperature: f64,
+    /// Heaters currently forced on by frost protection.
+    frost_protected: HashSet<String>,
+    /// Safety ceiling above which all heaters are forced off.
+    max_temperature: f64,
+    /// Whether the safety ceiling is currently exceeded.
+    over_temperature: bool,
+    /// The active operating mode.
+    mode: Mode,
+    /// Offset applied to the desired temperatures in away mode.
+    away_offset: f64,
+    /// Offset applied to the desired temperatures in eco mode.
+    eco_offset: f64,
+    weather_compensation: Option<WeatherCompensation>,
+    /// Offset applied to the desired temperatures while a boost is active.
+    boost: f64,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self::new(&ControlConfig::default())
+    }
+}
+
+/// Outcome of evaluating frost protection for a heater.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FrostProtection {
+    Engaged,
+    Released,
+    Inactive,
+}
+
+impl State {
+    fn new(config: &ControlConfig) -> Self {
+        Self {
+            enabled: false,
+            desired_temperature: None,
+            heater_desired_temperatures: HashMap::new(),
+            temperatures: HashMap::new(),
+            sensor_temperatures: HashMap::new(),
+            smoothing_window: config.smoothing_window,
+            inside_readings: VecDeque::new(),
+            inside_updated_at: None,
+            stale_after: Duration::from_secs(config.stale_after_secs),
+            inside_stale: false,
+            hysteresis: config.hysteresis,
+            heater_states: HashMap::new(),
+            frost_protection_temperature: config.frost_protection_temperature,
+            frost_protected: HashSet::new(),
+            max_temperature: config.max_temperature,
+            over_temperature: false,
+            mode: Mode::default(),
+            away_offset: config.away_offset,
+            eco_offset: config.eco_offset,
+            weather_compensation: config.weather_compensation,
+            boost: 0.0,
+        }
+    }
+
+    /// Use the thresholds of `config` from now on, keeping the readings and
+    /// decisions made so far.
+    fn apply_thresholds(&mut self, config: &ControlConfig) {
+        self.stale_after = Duration::from_secs(config.stale_after_secs);
+        self.hysteresis = config.hysteresis;
+        self.frost_protection_temperature = config.frost_protection_temperature;
+        self.max_temperature = config.max_temperature;
+        self.away_offset = config.away_offset;
+        self.eco_offset = config.eco_offset;
+        self.weather_compensation = config.weather_compensation;
+    }
+
+    /// Record a temperature reading from a location, which is either a place
+    /// or a sensor at a place like `outside/north`. The temperature of a place
+    /// is the average of the latest readings of its sensors, and inside that
+    /// is further averaged over the smoothing window before being used.
+    fn record_temperature(&mut self, location: &str, temperature: f64) {
+        self.record_temperature_at(location, temperature, Instant::now());
+    }
+
+    /// Record a temperature reading received at `now` like
+    /// `record_temperature`. Sensors that have not reported within the
+    /// staleness threshold are dropped, so they do not skew the average.
+    fn record_temperature_at(&mut self, location: &str, temperature: f64, now: Instant) {
+        let place = measurement_place(location);
+        self.sensor_temperatures
+            .insert(location.to_string(), (temperature, now));
+        let stale_after = self.stale_after;
+        self.sensor_temperatures.retain(|_, (_, received_at)| {
+            now.saturating_duration_since(*received_at) <= stale_after
+        });
+        let readings: Vec<f64> = self
+            .sensor_temperatures
+            .iter()
+            .filter(|(sensor, _)| measurement_place(sensor) == place)
+            .map(|(_, (temperature, _))| *temperature)
+            .collect();
+        let temperature = readings.iter().sum::<f64>() / readings.len() as f64;
+
+        if place != INSIDE {
+            self.temperatures.insert(place.to_string(), temperature);
+            return;
+        }
+        self.record_inside_temperature(temperature, now);
+    }
+
+    /// Add the average of the inside sensors received at `now` to the
+    /// smoothing window.
+    fn record_inside_temperature(&mut self, temperature: f64, now: Instant) {
+        if self.inside_stale {
+            tracing::info!("Inside sensor reporting again");
+        }
+        self.inside_stale = false;
+        self.inside_updated_at = Some(now);
+        self.inside_readings.push_back(temperature);
+        while self.inside_readings.len() > self.smoothing_window.max(1) {
+            self.inside_readings.pop_front();
+        }
+        let average = self.inside_readings.iter().sum::<f64>() / self.inside_readings.len() as f64;
+        self.temperatures.insert(INSIDE.to_string(), average);
+    }
+
+    /// Seed the inside temperature with a reading taken `age` ago, which
+    /// becomes stale like any other reading once it is too old. It is not
+    /// recorded as a sensor, so it does not take part in averaging the
+    /// readings of the sensors reporting after the restart.
+    fn restore_inside_temperature(&mut self, temperature: f64, age: Duration) {
+        let now = Instant::now();
+        self.record_inside_temperature(temperature, now.checked_sub(age).unwrap_or(now));
+    }
+
+    /// Mark the inside reading as stale if none has been received within the
+    /// staleness threshold at `now`. Returns whether it became stale.
+    fn update_staleness(&mut self, now: Instant) -> bool {
+        let Some(updated_at) = self.inside_updated_at else {
+            return false;
+        };
+        if self.inside_stale || now.saturating_duration_since(updated_at) <= self.stale_after {
+            return false;
+        }
+
+        self.inside_stale = true;
+        true
+    }
+
+    /// Decide the state a heater should be in. Heaters governed by a stale
+    /// inside reading are turned off. Frost protection takes precedence over
+    /// the rest, otherwise heaters are left idle when the controller is
+    /// disabled. Returns `None` when no decision can be made.
+    fn target_state(&mut self, heater: &Heater) -> Option<TargetState> {
+        self.target_state_with(heater, |state, _, _| state.get_heater_state(heater))
+    }
+
+    /// Decide the state a heater should be in like `target_state`, but with
+    /// `regulate` deciding the state from the desired and current temperature
+    /// when the heater is regulated.
+    fn target_state_with(
+        &mut self,
+        heater: &Heater,
+        regulate: impl FnOnce(&Self, f64, f64) -> HeaterState,
+    ) -> Option<TargetState> {
+        if self.inside_stale && heater.place() == INSIDE {
+            return Some(TargetState::Off);
+        }
+
+        match self.update_frost_protection(heater) {
+            FrostProtection::Engaged => return Some(TargetState::On),
+            // Turn off the heater frost protection turned on, as nothing else will.
+            FrostProtection::Released if !self.enabled => return Some(TargetState::Off),
+            FrostProtection::Released | FrostProtection::Inactive => {}
+        }
+
+        if !self.enabled {
+            return Some(TargetState::Idle);
+        }
+        let Some(desired) = self.desired_temperature_for(heater.id()) else {
+            tracing::warn!(heater_id = heater.id(), "Missing desired temperature");
+            return None;
+        };
+        let Some(current) = self.temperatures.get(heater.place()).copied() else {
+            tracing::warn!(
+                heater_id = heater.id(),
+                place = heater.place(),
+                "Missing current temperature"
+            );
+            return None;
+        };
+
+        let target = TargetState::from_heater_state(regulate(self, desired, current));
+        if target.is_none() {
+            tracing::debug!(
+                heater_id = heater.id(),
+                "Within hysteresis band and heater state is unknown, keeping heater unchanged"
+            );
+        }
+        target
+    }
+
+    /// Engage frost protection for a heater when the temperature of its place
+    /// drops below the floor. It is released again once the temperature is
+    /// above the floor by the hysteresis band.
+    fn update_frost_protection(&mut self, heater: &Heater) -> FrostProtection {
+        let Some(current) = self.temperatures.get(heater.place()).copied() else {
+            return FrostProtection::Inactive;
+        };
+        let floor = self.frost_protection_temperature;
+
+        if self.frost_protected.contains(heater.id()) {
+            if current < floor + self.hysteresis {
+                FrostProtection::Engaged
+            } else {
+                tracing::info!(
+                    heater_id = heater.id(),
+                    temperature = current,
+                    floor,
+                    "Frost protection released"
+                );
+                self.frost_protected.remove(heater.id());
+                FrostProtection::Released
+            }
+        } else if current < floor {
+            tracing::warn!(
+                heater_id = heater.id(),
+                temperature = current,
+                floor,
+                "Frost protection engaged, forcing heater on"
+            );
+            self.frost_protected.insert(heater.id().clone());
+            FrostProtection::Engaged
+        } else {
+            FrostProtection::Inactive
+        }
+    }
+
+    /// Check the temperatures of the places governing `heaters` against the
+    /// safety ceiling. The ceiling is released once the hottest place is below
+    /// it by the hysteresis band. Returns an alert whenever the state changes.
+    fn update_over_temperature(&mut self, heaters: &[Heater]) -> Option<TemperatureAlert> {
+        let (place, temperature) = heaters
+            .iter()
+            .filter_map(|heater| {
+                self.temperatures
+                    .get_key_value(heater.place())
+                    .map(|(place, temperature)| (place.clone(), *temperature))
+            })
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))?;
+
+        let triggered = !self.over_temperature && temperature > self.max_temperature;
+        let released =
+            self.over_temperature && temperature <= self.max_temperature - self.hysteresis;
+        if !triggered && !released {
+            return None;
+        }
+
+        self.over_temperature = triggered;
+        if triggered {
+            tracing::error!(place, temperature, "Temperature above safety ceiling");
+        } else {
+            tracing::info!(place, temperature, "Temperature back below safety ceiling");
+        }
+
+        Some(TemperatureAlert {
+            active: triggered,
+            place,
+            temperature,
+            ceiling: self.max_temperature,
+        })
+    }
+
+    /// Get the effective desired temperature of a heater, falling back to the
+    /// global desired temperature if the heater has no specific target. The
+    /// offset of the active mode and the weather compensation are applied to
+    /// the target.
+    pub fn desired_temperature_for(&self, heater_id: &str) -> Option<f64> {
+        self.heater_desired_temperatures
+            .get(heater_id)
+            .copied()
+            .or(self.desired_temperature)
+            .map(|desired| self.boosted(desired) + self.mode_offset() + self.weather_offset())
+    }
+
+    /// Apply the active boost to `desired`, keeping the boosted temperature
+    /// within the accepted range of desired temperatures.
+    fn boosted(&self, desired: f64) -> f64 {
+        if self.boost == 0.0 {
+            return desired;
+        }
+        (desired + self.boost).clamp(MIN_DESIRED_TEMPERATURE, MAX_DESIRED_TEMPERATURE)
+    }
+
+    /// Offset applied to the desired temperatures for the outside temperature,
+    /// which is zero without compensation or an outside reading.
+    fn weather_offset(&self) -> f64 {
+        self.weather_compensation
+            .zip(self.temperatures.get(OUTSIDE).copied())
+            .map_or(0.0, |(compensation, outside)| compensation.offset(outside))
+    }
+
+    /// Offset applied to the desired temperatures in the active mode.
+    fn mode_offset(&self) -> f64 {
+        match self.mode {
+            Mode::Home => 0.0,
+            Mode::Away => self.away_offset,
+            Mode::Eco => self.eco_offset,
+        }
+    }
+
+    /// The last state a heater was commanded to, which is unknown until the
+    /// first decision has been made.
+    fn heater_state(&self, heater_id: &str) -> HeaterState {
+        self.heater_states
+            .get(heater_id)
+            .copied()
+            .unwrap_or(HeaterState::Unknown)
+    }
+
+    /// How far the temperature of the place governing a heater is above its
+    /// desired temperature, if both are known.
+    fn temperature_error(&self, heater: &Heater) -> Option<f64> {
+        let desired = self.desired_temperature_for(heater.id())?;
+        let current = self.temperatures.get(heater.place())?;
+
+        Some(current - desired)
+    }
+
+    /// Compute the state a heater should be in, based on the temperature of
+    /// the place governing it. Within the hysteresis band the previous state
+    /// is kept. The state is unknown when the temperatures are missing, or
+    /// no decision has been made yet within the band.
+    pub fn get_heater_state(&self, heater: &Heater) -> HeaterState {
+        let Some((desired, current)) = self
+            .desired_temperature_for(heater.id())
+            .zip(self.temperatures.get(heater.place()).copied())
+        else {
+            return HeaterState::Unknown;
+        };
+        let half_band = self.hysteresis / 2.0;
+
+        if current < desired - half_band {
+            HeaterState::On
+        } else if current > desired + half_band {
+            HeaterState::Off
+        } else {
+            self.heater_state(heater.id())
+        }
+    }
+}
+
+/// Tracks when each heater relay last changed state, so it is not switched
+/// again before the minimum dwell time has elapsed. Changes requested within
+/// the window are kept as pending until they are due.
+#[derive(Debug)]
+struct RelayDwell {
+    min_dwell: Duration,
+    last_change: HashMap<String, Instant>,
+    pending: HashMap<String, HeaterState>,
+}
+
+impl RelayDwell {
+    fn new(min_dwell: Duration) -> Self {
+        Self {
+            min_dwell,
+            last_change: HashMap::new(),
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Request to switch a heater from its `current` state to `state`.
+    /// Returns whether the change may be applied now. Otherwise it is kept as
+    /// pending, replacing any earlier pending change for the heater.
+    fn request(
+        &mut self,
+        heater_id: &str,
+        current: HeaterState,
+        state: HeaterState,
+        now: Instant,
+    ) -> bool {
+        if current == state {
+            self.pending.remove(heater_id);
+            return true;
+        }
+
+        if self.is_dwelling(heater_id, now) {
+            self.pending.insert(heater_id.to_string(), state);
+            false
+        } else {
+            self.pending.remove(heater_id);
+            self.last_change.insert(heater_id.to_string(), now);
+            true
+        }
+    }
+
+    /// Whether the heater changed state less than the minimum dwell time ago.
+    fn is_dwelling(&self, heater_id: &str, now: Instant) -> bool {
+        self.last_change
+            .get(heater_id)
+            .is_some_and(|changed| now.duration_since(*changed) < self.min_dwell)
+    }
+
+    /// Record a change that was applied regardless of the dwell time.
+    fn force(&mut self, heater_id: &str, now: Instant) {
+        self.pending.remove(heater_id);
+        self.last_change.insert(heater_id.to_string(), now);
+    }
+
+    /// The earliest time a pending change becomes due.
+    fn next_deadline(&self) -> Option<Instant> {
+        self.pending
+            .keys()
+            .filter_map(|heater_id| self.last_change.get(heater_id))
+            .min()
+            .map(|changed| *changed + self.min_dwell)
+    }
+
+    /// Remove and return the pending changes that are due at `now`.
+    fn take_due(&mut self, now: Instant) -> Vec<(String, HeaterState)> {
+        let due: Vec<String> = self
+            .pending
+            .keys()
+            .filter(|heater_id| !self.is_dwelling(heater_id, now))
+            .cloned()
+            .collect();
+
+        due.into_iter()
+            .filter_map(|heater_id| {
+                let state = self.pending.remove(&heater_id)?;
+                self.last_change.insert(heater_id.clone(), now);
+                Some((heater_id, state))
+            })
+            .collect()
+    }
+}
+
+/// Tracks the heater commands whose state the relays have not reported back
+/// yet, so a relay that failed to switch is noticed.
+#[derive(Debug)]
+struct PendingConfirmations {
+    /// Time a relay has to confirm a command, or none when the check is
+    /// disabled.
+    timeout: Option<Duration>,
+    /// The commanded state and when it must be confirmed, keyed by heater id.
+    pending: HashMap<String, (HeaterState, Instant)>,
+}
+
+impl PendingConfirmations {
+    /// The check is disabled in a dry run, as no commands are published.
+    fn new(config: &ControlConfig) -> Self {
+        let timeout = (!config.dry_run && config.command_confirmation_timeout_secs > 0)
+            .then(|| Duration::from_secs(config.command_confirmation_timeout_secs));
+        Self {
+            timeout,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Expect the relay of a heater to report `state` after being commanded
+    /// to it at `now`, replacing any earlier command to another state. A
+    /// repeated command keeps the deadline of the first, so resending it does
+    /// not postpone the alert.
+    fn expect(&mut self, heater_id: &str, state: HeaterState, now: Instant) {
+        let Some(timeout) = self.timeout else {
+            return;
+        };
+        if self
+            .pending
+            .get(heater_id)
+            .is_some_and(|(expected, _)| *expected == state)
+        {
+            return;
+        }
+        self.pending
+            .insert(heater_id.to_string(), (state, now + timeout));
+    }
+
+    /// Record the state a relay reported, which confirms a command to it.
+    fn confirm(&mut self, heater_id: &str, state: HeaterState) {
+        if self
+            .pending
+            .get(heater_id)
+            .is_some_and(|(expected, _)| *expected == state)
+        {
+            self.pending.remove(heater_id);
+        }
+    }
+
+    /// The earliest time a command must be confirmed.
+    fn next_deadline(&self) -> Option<Instant> {
+        self.pending.values().map(|(_, deadline)| *deadline).min()
+    }
+
+    /// Remove and return the commands that were not confirmed by `now`.
+    fn take_expired(&mut self, now: Instant) -> Vec<(String, HeaterState)> {
+        let expired: Vec<String> = self
+            .pending
+            .iter()
+            .filter(|(_, (_, deadline))| *deadline <= now)
+            .map(|(heater_id, _)| heater_id.clone())
+            .collect();
+
+        expired
+            .into_iter()
+            .filter_map(|heater_id| {
+                let (state, _) = self.pending.remove(&heater_id)?;
+                Some((heater_id, state))
+            })
+            .collect()
+    }
+}
+
+/// The place of a measurement location, which is the part before the sensor
+/// name, if any.
+fn measurement_place(location: &str) -> &str {
+    location.split('/').next().unwrap_or(location)
+}
+
+/// Sleep until `deadline`, or forever if there is none.
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Tracks when the MQTT eventloop last polled successfully.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionHealth {
+    last_success: Arc<std::sync::Mutex<Option<Instant>>>,
+}
+
+impl ConnectionHealth {
+    /// Record that the eventloop polled successfully just now.
+    pub fn record_success(&self) {
+        *self.last_success.lock().expect("health lock poisoned") = Some(Instant::now());
+    }
+
+    /// Whether the eventloop has polled successfully within `max_age`.
+    pub fn polled_within(&self, max_age: Duration) -> bool {
+        self.last_success
+            .lock()
+            .expect("health lock poisoned")
+            .is_some_and(|last_success| last_success.elapsed() <= max_age)
+    }
+}
+
+/// Tracks when the `Executor` last wrote to the database successfully, and
+/// how often it was held up by the database.
+#[derive(Debug, Clone, Default)]
+pub struct DatabaseHealth {
+    last_write: Arc<std::sync::Mutex<Option<DateTime<Utc>>>>,
+    slow_lock_waits: Arc<AtomicU64>,
+    slow_operations: Arc<AtomicU64>,
+}
+
+impl DatabaseHealth {
+    /// Record that a write succeeded just now.
+    pub fn record_write(&self) {
+        *self.last_write.lock().expect("health lock poisoned") = Some(Utc::now());
+    }
+
+    /// When the last write succeeded, if any has.
+    pub fn last_write(&self) -> Option<DateTime<Utc>> {
+        *self.last_write.lock().expect("health lock poisoned")
+    }
+
+    /// Number of times waiting for the database lock took longer than the
+    /// slow database threshold.
+    pub fn slow_lock_waits(&self) -> u64 {
+        self.slow_lock_waits.load(Ordering::Relaxed)
+    }
+
+    /// Number of writes that took longer than the slow database threshold.
+    pub fn slow_operations(&self) -> u64 {
+        self.slow_operations.load(Ordering::Relaxed)
+    }
+}
+
+/// Upper bounds in °C of the buckets of the temperature error histogram.
+const TEMPERATURE_ERROR_BUCKETS: [f64; 9] = [-2.0, -1.0, -0.5, -0.25, 0.0, 0.25, 0.5, 1.0, 2.0];
+
+/// Histogram of the temperature error, which is how far the temperature
+/// governing a heater is above its desired temperature, observed for every
+/// heater each time the enabled control evaluates them.
+#[derive(Debug, Clone, Default)]
+pub struct ErrorHistogram(Arc<std::sync::Mutex<ErrorCounts>>);
+
+#[derive(Debug, Default)]
+struct ErrorCounts {
+    /// Observations within each bucket, with a last bucket for those above
+    /// all bounds.
+    buckets: [u64; TEMPERATURE_ERROR_BUCKETS.len() + 1],
+    sum: f64,
+    count: u64,
+}
+
+impl ErrorHistogram {
+    fn observe(&self, error: f64) {
+        if !error.is_finite() {
+            return;
+        }
+        let bucket = TEMPERATURE_ERROR_BUCKETS
+            .iter()
+            .position(|bound| error <= *bound)
+            .unwrap_or(TEMPERATURE_ERROR_BUCKETS.len());
+        let mut counts = self.0.lock().expect("histogram lock poisoned");
+        counts.buckets[bucket] += 1;
+        counts.sum += error;
+        counts.count += 1;
+    }
+
+    /// The observations so far, with cumulative buckets like a Prometheus
+    /// histogram.
+    pub fn snapshot(&self) -> Histogram {
+        let counts = self.0.lock().expect("histogram lock poisoned");
+        let bounds = TEMPERATURE_ERROR_BUCKETS
+            .iter()
+            .map(f64::to_string)
+            .chain(std::iter::once("+Inf".to_string()));
+        let mut cumulative = 0;
+        let buckets = bounds
+            .zip(counts.buckets)
+            .map(|(le, count)| {
+                cumulative += count;
+                HistogramBucket {
+                    le,
+                    count: cumulative,
+                }
+            })
+            .collect();
+
+        Histogram {
+            buckets,
+            sum: counts.sum,
+            count: counts.count,
+        }
+    }
+}
+
+/// A histogram in the shape of a Prometheus histogram.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct Histogram {
+    pub buckets: Vec<HistogramBucket>,
+    /// Sum of all observations.
+    pub sum: f64,
+    /// Number of observations.
+    pub count: u64,
+}
+
+/// The number of observations less than or equal to `le`.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct HistogramBucket {
+    pub le: String,
+    pub count: u64,
+}
+
+/// Counters of actions that could not be handed to the `Executor` right away.
+#[derive(Debug, Clone, Default)]
+pub struct ChannelMetrics {
+    dropped_actions: Arc<AtomicU64>,
+    slow_sends: Arc<AtomicU64>,
+}
+
+impl ChannelMetrics {
+    /// Number of low priority actions dropped because the channel was full.
+    pub fn dropped_actions(&self) -> u64 {
+        self.dropped_actions.load(Ordering::Relaxed)
+    }
+
+    /// Number of sends that blocked for longer than the slow send threshold.
+    pub fn slow_sends(&self) -> u64 {
+        self.slow_sends.load(Ordering::Relaxed)
+    }
+}
+
+/// Snapshot of the state of the `Executor`, exposed over the HTTP API.
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize)]
+pub struct StateSnapshot {
+    pub enabled: bool,
+    pub mode: Mode,
+    pub desired_temperature: Option<f64>,
+    pub heater_desired_temperatures: HashMap<String, f64>,
+    /// The current temperature, keyed by measurement place.
+    pub temperatures: HashMap<String, f64>,
+    /// The last state each heater was commanded to, keyed by heater id.
+    pub heater_states: HashMap<String, HeaterState>,
+    pub inside_stale: bool,
+    pub over_temperature: bool,
+}
+
+impl From<&State> for StateSnapshot {
+    fn from(state: &State) -> Self {
+        Self {
+            enabled: state.enabled,
+            mode: state.mode,
+            desired_temperature: state.desired_temperature,
+            heater_desired_temperatures: state.heater_desired_temperatures.clone(),
+            temperatures: state.temperatures.clone(),
+            heater_states: state.heater_states.clone(),
+            inside_stale: state.inside_stale,
+            over_temperature: state.over_temperature,
+        }
+    }
+}
+
+/// Shared handle to the latest `StateSnapshot`.
+#[derive(Debug, Clone, Default)]
+pub struct SharedState(Arc<std::sync::RwLock<StateSnapshot>>);
+
+impl SharedState {
+    /// Create a handle to the given snapshot.
+    #[cfg(test)]
+    pub fn new(snapshot: StateSnapshot) -> Self {
+        Self(Arc::new(std::sync::RwLock::new(snapshot)))
+    }
+
+    /// Get a copy of the latest snapshot.
+    pub fn get(&self) -> StateSnapshot {
+        self.0.read().expect("state lock poisoned").clone()
+    }
+
+    fn update(&self, state: &State) {
+        *self.0.write().expect("state lock poisoned") = state.into();
+    }
+}
+
+/// An action handled by the executor, as streamed to clients of the HTTP API.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    Reading {
+        place: String,
+        temperature: f64,
+    },
+    DesiredTemperature {
+        temperature: f64,
+    },
+    HeaterDesiredTemperature {
+        heater_id: String,
+        temperature: f64,
+    },
+    HeaterState {
+        heater_id: String,
+        state: HeaterState,
+    },
+    Enabled {
+        enabled: bool,
+    },
+    Mode {
+        mode: Mode,
+    },
+}
+
+impl Event {
+    /// The event of an action, if it is of interest to clients.
+    fn from_action(action: &Action) -> Option<Self> {
+        use Action::*;
+        Some(match action {
+            SetDesiredTemperature(temperature, _) => Event::DesiredTemperature {
+                temperature: *temperature,
+            },
+            SetHeaterDesiredTemperature(heater_id, temperature) => {
+                Event::HeaterDesiredTemperature {
+                    heater_id: heater_id.clone(),
+                    temperature: *temperature,
+                }
+            }
+            SetInsideTemperature(temperature) => Event::Reading {
+                place: INSIDE.to_string(),
+                temperature: *temperature,
+            },
+            EnableController(enabled) => Event::Enabled { enabled: *enabled },
+            SetMode(mode) => Event::Mode { mode: *mode },
+            RegisterMeasurement(place, measurement) => Event::Reading {
+                place: place.clone(),
+                temperature: *measurement.temperature(),
+            },
+            RegisterHeaterStateChange(heater_id, state)
+            | OverrideHeater {
+                id: heater_id,
+                state,
+                ..
+            } => Event::HeaterState {
+                heater_id: heater_id.clone(),
+                state: *state,
+            },
+            RegisterHeaterPower(..)
+            | RegisterHeaterEnergy(..)
+            | RegisterDeadLetter(..)
+            | Reevaluate
+            | Boost { .. }
+            | PublishConfig
+            | ReloadConfig(_) => return None,
+        })
+    }
+}
+
+/// Rotates through the configured brokers, moving on to the next one after
+/// a number of consecutive failed polls of the current one.
+#[derive(Debug, Default)]
+struct Failover {
+    /// The options to connect to each broker, or none for a single broker.
+    brokers: Vec<MqttOptions>,
+    current: usize,
+    failures: u32,
+    threshold: u32,
+}
+
+impl Failover {
+    fn new(config: &MqttConfig) -> Result<Self> {
+        let brokers = config.brokers();
+        if brokers.len() == 1 {
+            return Ok(Self::default());
+        }
+        let brokers = brokers
+            .into_iter()
+            .map(|BrokerEndpoint { host, port }| {
+                create_mqtt_options(&MqttConfig {
+                    host,
+                    port,
+                    ..config.clone()
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            brokers,
+            current: 0,
+            failures: 0,
+            threshold: config.failover_after,
+        })
+    }
+
+    fn record_success(&mut self) {
+        self.failures = 0;
+    }
+
+    /// Record a failed poll, and get the options to connect to the next
+    /// broker when it is time to fail over.
+    fn record_failure(&mut self) -> Option<&MqttOptions> {
+        if self.brokers.len() < 2 {
+            return None;
+        }
+        self.failures += 1;
+        if self.failures < self.threshold {
+            return None;
+        }
+        self.failures = 0;
+        self.current = (self.current + 1) % self.brokers.len();
+        self.brokers.get(self.current)
+    }
+}
+
+/// Exponential backoff used between failed polls of the MQTT eventloop.
+#[derive(Debug, Default)]
+struct Backoff {
+    current: Option<Duration>,
+}
+
+impl Backoff {
+    /// Get the delay to wait before the next attempt, doubling it every time
+    /// up to `MAX_RECONNECT_DELAY`.
+    fn next_delay(&mut self) -> Duration {
+        let delay = self.current.map_or(INITIAL_RECONNECT_DELAY, |delay| {
+            (delay * 2).min(MAX_RECONNECT_DELAY)
+        });
+        self.current = Some(delay);
+        delay
+    }
+
+    /// Start over from `INITIAL_RECONNECT_DELAY`.
+    fn reset(&mut self) {
+        self.current = None;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use rumqttc::v5::Request;
+    use sqlx::SqlitePool;
+
+    use super::*;
+    use crate::{models::Firmware, schedule::ScheduleEntry, telemetry};
+
+    /// Create an executor controlling a single heater, together with the
+    /// receiving end of the requests it sends to the MQTT broker.
+    async fn executor(pool: SqlitePool) -> (Executor, flume::Receiver<Request>) {
+        let (executor, _, requests) = executor_with_sender(pool).await;
+        (executor, requests)
+    }
+
+    /// Create an executor like `executor`, which also returns the sender of
+    /// actions to it.
+    async fn executor_with_sender(
+        pool: SqlitePool,
+    ) -> (Executor, Sender<Action>, flume::Receiver<Request>) {
+        let (request_tx, request_rx) = flume::unbounded();
+        let db = Database::new(pool).await.unwrap();
+        let (tx, rx) = channel(10);
+        let executor = Executor::new(
+            AsyncClient::from_senders(request_tx),
+            Arc::new(Mutex::new(db)),
+            rx,
+            vec![heater(HEATER_ID)],
+            Schedule::default(),
+            &ControlConfig::default(),
+        );
+
+        (executor, tx, request_rx)
+    }
+
+    /// A signal that the hub is shutting down, after which the executor stops
+    /// cleanly once the senders of actions are dropped.
+    fn shutting_down() -> watch::Receiver<bool> {
+        watch::channel(true).1
+    }
+
+    fn time(hour: u32, minute: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    #[sqlx::test]
+    fn schedule_overrides_manual_setpoint_only_on_next_change(pool: SqlitePool) {
+        // Arrange
+        let (mut executor, _requests) = executor(pool).await;
+        executor.schedule = Schedule::new(vec![
+            ScheduleEntry::new(time(6, 0), 21.0),
+            ScheduleEntry::new(time(22, 0), 17.0),
+        ]);
+        executor.apply_schedule(time(7, 0)).await.unwrap();
+        assert_eq!(executor.state.desired_temperature, Some(21.0));
+
+        // Act
+        executor
+            .handle_action(&Action::SetDesiredTemperature(23.0, SetpointSource::Mqtt))
+            .await
+            .unwrap();
+        executor.apply_schedule(time(8, 0)).await.unwrap();
+        let manual = executor.state.desired_temperature;
+        executor.apply_schedule(time(22, 1)).await.unwrap();
+
+        // Assert
+        assert_eq!(manual, Some(23.0));
+        assert_eq!(executor.state.desired_temperature, Some(17.0));
+    }
+
+    #[sqlx::test]
+    fn restore_state_seeds_latest_reading_and_setpoint(pool: SqlitePool) {
+        // Arrange
+        let (mut executor, _requests) = executor(pool).await;
+        {
+            let db = executor.db.lock().await;
+            let now = Utc::now().naive_utc();
+            for (minutes_ago, temperature) in [(10, 19.0), (2, 19.5)] {
+                db.insert_reading(
+                    INSIDE,
+                    temperature,
+                    Some(50.0),
+                    None,
+                    Some(now - chrono::Duration::minutes(minutes_ago)),
+                )
+                .await
+                .unwrap();
+            }
+            db.insert_setpoint_change(21.0, SetpointSource::Http)
+                .await
+                .unwrap();
+        }
+
+        // Act
+        executor.restore_state().await.unwrap();
+
+        // Assert
+        assert_eq!(executor.state.temperatures.get(INSIDE), Some(&19.5));
+        assert_eq!(executor.state.desired_temperature, Some(21.0));
+        let updated_at = executor.state.inside_updated_at.unwrap();
+        assert!(updated_at.elapsed() >= Duration::from_secs(2 * 60));
+    }
+
+    #[sqlx::test]
+    fn restore_state_keeps_setpoint_newer_than_active_schedule_entry(pool: SqlitePool) {
+        // Arrange
+        let (mut executor, _requests) = executor(pool).await;
+        executor.schedule = Schedule::new(vec![ScheduleEntry::new(time(0, 0), 17.0)]);
+        executor
+            .db
+            .lock()
+            .await
+            .insert_setpoint_change(21.0, SetpointSource::Http)
+            .await
+            .unwrap();
+
+        // Act
+        executor.restore_state().await.unwrap();
+        executor.apply_schedule(Local::now().time()).await.unwrap();
+
+        // Assert
+        assert_eq!(executor.state.desired_temperature, Some(21.0));
+        let changes = executor
+            .db
+            .lock()
+            .await
+            .get_setpoint_history_since(Duration::from_secs(60))
+            .await
+            .unwrap();
+        assert!(changes
+            .iter()
+            .all(|change| *change.source() != SetpointSource::Schedule));
+    }
+
+    #[sqlx::test]
+    fn restore_state_uses_default_desired_temperature_without_history(pool: SqlitePool) {
+        // Arrange
+        let (mut executor, _requests) = executor(pool).await;
+        executor.default_desired_temperature = 18.5;
+
+        // Act
+        executor.restore_state().await.unwrap();
+
+        // Assert
+        assert!(executor.state.temperatures.is_empty());
+        assert_eq!(executor.state.desired_temperature, Some(18.5));
+    }
+
+    #[sqlx::test]
+    fn setpoint_changes_are_recorded_with_source(pool: SqlitePool) {
+        // Arrange
+        let (mut executor, _requests) = executor(pool).await;
+        executor.schedule = Schedule::new(vec![ScheduleEntry::new(time(6, 0), 21.0)]);
+
+        // Act
+        executor.apply_schedule(time(7, 0)).await.unwrap();
+        for source in [SetpointSource::Http, SetpointSource::Mqtt] {
+            executor
+                .handle_action(&Action::SetDesiredTemperature(22.0, source))
+                .await
+                .unwrap();
+        }
+
+        // Assert
+        let changes: Vec<_> = executor
+            .db
+            .lock()
+            .await
+            .get_setpoint_history_since(Duration::from_secs(60))
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|change| (*change.temperature(), *change.source()))
+            .collect();
+        // Setting the same temperature again is not a change.
+        assert_eq!(
+            changes,
+            vec![
+                (21.0, SetpointSource::Schedule),
+                (22.0, SetpointSource::Http)
+            ]
+        );
+    }
+
+    #[sqlx::test]
+    fn unchanged_heater_state_is_recorded_once(pool: SqlitePool) {
+        // Arrange
+        let (mut executor, _requests) = executor(pool.clone()).await;
+        let action = Action::RegisterHeaterStateChange(HEATER_ID.to_string(), HeaterState::On);
+
+        // Act
+        executor.handle_action(&action).await.unwrap();
+        executor.handle_action(&action).await.unwrap();
+
+        // Assert
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM heater_history")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(count, 1);
+        assert_eq!(
+            executor.state.heater_states.get(HEATER_ID),
+            Some(&HeaterState::On)
+        );
+    }
+
+    #[sqlx::test]
+    fn executor_drains_buffered_actions_on_shutdown(pool: SqlitePool) {
+        // Arrange
+        let (executor, tx, requests) = executor_with_sender(pool.clone()).await;
+        for state in [HeaterState::On, HeaterState::Off, HeaterState::On] {
+            tx.send(Action::RegisterHeaterStateChange(
+                HEATER_ID.to_string(),
+                state,
+            ))
+            .await
+            .unwrap();
+        }
+
+        // Act
+        drop(tx);
+        executor
+            .run_until_completion(shutting_down())
+            .await
+            .unwrap();
+
+        // Assert
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM heater_history")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(count, 3);
+        let requests: Vec<Request> = requests.try_iter().collect();
+        assert!(requests.iter().any(|request| matches!(
+            request,
+            Request::Publish(publish) if publish.topic == AVAILABILITY_TOPIC && publish.payload == "offline"
+        )));
+        assert!(matches!(requests.last(), Some(Request::Disconnect)));
+    }
+
+    /// Create a controller which is never connected to a broker.
+    fn controller() -> Controller {
+        let (mqtt_client, eventloop) =
+            AsyncClient::new(MqttOptions::new("test", "localhost", 1883), 10);
+        let (tx, _rx) = channel(10);
+        Controller::new(eventloop, mqtt_client, tx, Vec::new())
+    }
+
+    #[tokio::test]
+    async fn full_channel_drops_low_priority_actions() {
+        // Arrange
+        let (mqtt_client, eventloop) =
+            AsyncClient::new(MqttOptions::new("test", "localhost", 1883), 10);
+        let (tx, mut rx) = channel(1);
+        let mut controller = Controller::new(eventloop, mqtt_client, tx, Vec::new());
+        let measurement: Measurement =
+            serde_json::from_str(r#"{"temperature":21.0,"humidity":50.0}"#).unwrap();
+
+        // Act
+        for _ in 0..3 {
+            controller
+                .send_action(Action::RegisterMeasurement(INSIDE.to_string(), measurement))
+                .await;
+        }
+
+        // Assert
+        assert_eq!(controller.metrics().dropped_actions(), 2);
+        assert!(matches!(rx.try_recv(), Ok(Action::RegisterMeasurement(..))));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn full_channel_reports_slow_sends() {
+        // Arrange
+        let (mqtt_client, eventloop) =
+            AsyncClient::new(MqttOptions::new("test", "localhost", 1883), 10);
+        let (tx, mut rx) = channel(1);
+        let mut controller = Controller::new(eventloop, mqtt_client, tx, Vec::new());
+        controller.slow_send_threshold = Duration::from_millis(10);
+        controller.send_action(Action::EnableController(true)).await;
+
+        // Act
+        let (_, first) = tokio::join!(
+            controller.send_action(Action::SetDesiredTemperature(21.0, SetpointSource::Mqtt)),
+            async {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                rx.recv().await
+            }
+        );
+
+        // Assert
+        assert!(matches!(first, Some(Action::EnableController(true))));
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(Action::SetDesiredTemperature(..))
+        ));
+        assert_eq!(controller.metrics().slow_sends(), 1);
+        assert_eq!(controller.metrics().dropped_actions(), 0);
+    }
+
+    #[sqlx::test]
+    fn contended_database_lock_is_reported(pool: SqlitePool) {
+        // Arrange
+        let (mut executor, _requests) = executor(pool).await;
+        executor.slow_db_threshold = Duration::from_millis(10);
+        let db = executor.db.clone();
+        // Held like by a long query of another task.
+        let guard = db.lock().await;
+        let action = Action::RegisterHeaterPower(HEATER_ID.to_string(), 42.0);
+
+        // Act
+        let (result, _) = tokio::join!(executor.handle_action(&action), async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            drop(guard);
+        });
+
+        // Assert
+        result.unwrap();
+        assert_eq!(executor.db_health().slow_lock_waits(), 1);
+        assert!(executor.db_health().last_write().is_some());
+    }
+
+    #[test]
+    fn heater_state_is_unknown_before_first_decision() {
+        assert_eq!(
+            State::default().heater_state(HEATER_ID),
+            HeaterState::Unknown
+        );
+        assert_eq!(
+            state(20.0, Some(HeaterState::On)).heater_state(HEATER_ID),
+            HeaterState::On
+        );
+    }
+
+    #[tokio::test]
+    async fn energy_message_is_registered() {
+        let mut controller = controller();
+        let message = Packet::Publish(Publish::new(
+            "shellies/shelly1-C4402D/relay/0/energy",
+            QoS::AtLeastOnce,
+            "6000",
+            None,
+        ));
+
+        let action = controller.handle_incoming_message(message).await.unwrap();
+
+        assert!(matches!(
+            action,
+            Some(Action::RegisterHeaterEnergy(id, energy)) if id == "C4402D" && energy == 0.1
+        ));
+    }
+
+    #[sqlx::test]
+    fn topic_prefix_applies_to_subscriptions_and_commands(pool: SqlitePool) {
+        // Arrange
+        let (request_tx, requests) = flume::unbounded();
+        let (_, eventloop) = AsyncClient::new(MqttOptions::new("test", "localhost", 1883), 10);
+        let db = Arc::new(Mutex::new(Database::new(pool).await.unwrap()));
+        let mqtt_config = MqttConfig {
+            subscriptions: vec!["temperature/+".to_string(), "measurement/#".to_string()],
+            topic_prefix: "apartment1/".to_string(),
+            ..MqttConfig::default()
+        };
+
+        // Act
+        let (mut controller, executor) = create(
+            AsyncClient::from_senders(request_tx),
+            eventloop,
+            db,
+            &mqtt_config,
+            &ControlConfig::default(),
+        )
+        .await
+        .unwrap();
+        executor
+            .set_heater_state(&heater(HEATER_ID), HeaterState::On)
+            .await
+            .unwrap();
+        executor
+            .request_relay_state(&heater(HEATER_ID))
+            .await
+            .unwrap();
+        executor.publish_discovery().await.unwrap();
+        let action = controller
+            .handle_incoming_message(Packet::Publish(Publish::new(
+                "apartment1/temperature/inside",
+                QoS::AtLeastOnce,
+                "21.5",
+                None,
+            )))
+            .await
+            .unwrap();
+
+        // Assert
+        let requests: Vec<Request> = requests.try_iter().collect();
+        let filters: Vec<_> = requests
+            .iter()
+            .filter_map(|request| match request {
+                Request::Subscribe(subscribe) => Some(subscribe.filters.clone()),
+                _ => None,
+            })
+            .flatten()
+            .collect();
+        assert_eq!(
+            filters,
+            vec![
+                Filter::new("apartment1/temperature/+", ExactlyOnce),
+                Filter::new("apartment1/measurement/#", QoS::AtMostOnce),
+            ]
+        );
+        let (topic, _) = command(HEATER_ID, "on");
+        assert!(requests.iter().any(|request| matches!(
+            request,
+            Request::Publish(publish) if publish.topic == format!("apartment1/{topic}")
+        )));
+        assert!(requests.iter().any(|request| matches!(
+            request,
+            Request::Publish(publish)
+                if publish.topic == format!("apartment1/shellies/shelly1-{HEATER_ID}/command")
+        )));
+        let discovery: Vec<serde_json::Value> = requests
+            .iter()
+            .filter_map(|request| match request {
+                Request::Publish(publish) if publish.topic.starts_with(b"homeassistant/") => {
+                    serde_json::from_slice(&publish.payload).ok()
+                }
+                _ => None,
+            })
+            .collect();
+        assert!(discovery
+            .iter()
+            .any(|config| config["temperature_command_topic"]
+                == format!("apartment1/temperature/set/{HEATER_ID}")));
+        assert!(discovery
+            .iter()
+            .any(|config| config["state_topic"] == "apartment1/measurement/inside"));
+        assert!(matches!(action, Some(Action::SetInsideTemperature(t)) if t == 21.5));
+    }
+
+    #[sqlx::test]
+    fn create_subscribes_with_configured_qos(pool: SqlitePool) {
+        // Arrange
+        let (request_tx, requests) = flume::unbounded();
+        let (_, eventloop) = AsyncClient::new(MqttOptions::new("test", "localhost", 1883), 10);
+        let db = Arc::new(Mutex::new(Database::new(pool).await.unwrap()));
+        let mqtt_config = MqttConfig {
+            subscriptions: vec![
+                "temperature/+".to_string(),
+                "measurement/#".to_string(),
+                "shellies/+/relay/0".to_string(),
+            ],
+            subscription_qos: HashMap::from([
+                ("measurement/#".to_string(), 0),
+                ("shellies/+/relay/0".to_string(), 1),
+            ]),
+            ..MqttConfig::default()
+        };
+
+        // Act
+        create(
+            AsyncClient::from_senders(request_tx),
+            eventloop,
+            db,
+            &mqtt_config,
+            &ControlConfig::default(),
+        )
+        .await
+        .unwrap();
+
+        // Assert
+        let filters: Vec<_> = requests
+            .try_iter()
+            .filter_map(|request| match request {
+                Request::Subscribe(subscribe) => Some(subscribe.filters),
+                _ => None,
+            })
+            .flatten()
+            .collect();
+        assert_eq!(
+            filters,
+            vec![
+                Filter::new("temperature/+", ExactlyOnce),
+                Filter::new("measurement/#", QoS::AtMostOnce),
+                Filter::new("shellies/+/relay/0", QoS::AtLeastOnce),
+            ]
+        );
+    }
+
+    #[test]
+    fn filters_include_measurement_topic_template() {
+        let mqtt_config = MqttConfig {
+            subscriptions: vec!["measurement/#".to_string()],
+            measurement_topic: Some("home/{place}/climate".to_string()),
+            subscription_qos: HashMap::from([("home/+/climate".to_string(), 0)]),
+            topic_prefix: "apartment1/".to_string(),
+            ..MqttConfig::default()
+        };
+
+        assert_eq!(
+            mqtt_config.filters(),
+            vec![
+                Filter::new("apartment1/measurement/#", ExactlyOnce),
+                Filter::new("apartment1/home/+/climate", QoS::AtMostOnce),
+            ]
+        );
+        assert!(MqttConfig {
+            measurement_topic: Some("home/climate".to_string()),
+            ..MqttConfig::default()
+        }
+        .with_env_overrides(&|_| None)
+        .is_err());
+    }
+
+    #[sqlx::test]
+    fn create_rejects_invalid_subscription_qos(pool: SqlitePool) {
+        // Arrange
+        let (request_tx, _requests) = flume::unbounded();
+        let (_, eventloop) = AsyncClient::new(MqttOptions::new("test", "localhost", 1883), 10);
+        let db = Arc::new(Mutex::new(Database::new(pool).await.unwrap()));
+        let mqtt_config = MqttConfig {
+            subscription_qos: HashMap::from([("measurement/#".to_string(), 3)]),
+            ..MqttConfig::default()
+        };
+
+        // Act
+        let result = create(
+            AsyncClient::from_senders(request_tx),
+            eventloop,
+            db,
+            &mqtt_config,
+            &ControlConfig::default(),
+        )
+        .await;
+
+        // Assert
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn message_without_topic_prefix_is_ignored() {
+        let mut controller = controller();
+        controller.topic_prefix = "apartment1/".to_string();
+        let message = Packet::Publish(Publish::new(
+            "apartment2/temperature/inside",
+            QoS::AtLeastOnce,
+            "21.5",
+            None,
+        ));
+
+        let action = controller.handle_incoming_message(message).await.unwrap();
+
+        assert!(action.is_none());
+    }
+
+    #[sqlx::test]
+    fn auto_toggle_only_affects_executor_decisions(pool: SqlitePool) {
+        // Arrange
+        let mut controller = controller();
+        let (mut executor, requests) = executor(pool).await;
+        let auto = |enabled: &'static str| {
+            Packet::Publish(Publish::new(
+                "temperature/auto",
+                QoS::AtLeastOnce,
+                enabled,
+                None,
+            ))
+        };
+        executor
+            .handle_action(&Action::SetDesiredTemperature(21.0, SetpointSource::Mqtt))
+            .await
+            .unwrap();
+
+        // Act
+        let disable = controller
+            .handle_incoming_message(auto("false"))
+            .await
+            .unwrap()
+            .unwrap();
+        executor.handle_action(&disable).await.unwrap();
+        executor
+            .handle_action(&Action::SetInsideTemperature(18.0))
+            .await
+            .unwrap();
+        let while_disabled = published(&requests);
+        let enable = controller
+            .handle_incoming_message(auto("true"))
+            .await
+            .unwrap()
+            .unwrap();
+        executor.handle_action(&enable).await.unwrap();
+
+        // Assert
+        assert!(!while_disabled.contains(&command(HEATER_ID, "on")));
+        assert!(published(&requests).contains(&command(HEATER_ID, "on")));
+    }
+
+    #[tokio::test]
+    async fn power_message_is_not_parsed_as_state_change() {
+        let mut controller = controller();
+        let message = Packet::Publish(Publish::new(
+            "shellies/shelly1-C4402D/relay/0/power",
+            QoS::AtLeastOnce,
+            "42.5",
+            None,
+        ));
+
+        let action = controller.handle_incoming_message(message).await.unwrap();
+
+        assert!(matches!(
+            action,
+            Some(Action::RegisterHeaterPower(id, power)) if id == "C4402D" && power == 42.5
+        ));
+    }
+
+    #[sqlx::test]
+    fn malformed_measurement_is_written_as_dead_letter(pool: SqlitePool) {
+        // Arrange
+        let mut controller = controller();
+        let (mut executor, _requests) = executor(pool.clone()).await;
+        let message = Packet::Publish(Publish::new(
+            "measurement/inside",
+            QoS::AtLeastOnce,
+            r#"{"temperature":"warm"}"#,
+            None,
+        ));
+
+        // Act
+        let action = controller
+            .handle_incoming_message(message)
+            .await
+            .unwrap()
+            .expect("an action to be produced");
+        executor.handle_action(&action).await.unwrap();
+
+        // Assert
+        assert!(matches!(action, Action::RegisterDeadLetter(..)));
+        let topic: String = sqlx::query_scalar("SELECT topic FROM dead_letters")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(topic, "measurement/inside");
+    }
+
+    #[tokio::test]
+    async fn out_of_range_measurement_is_rejected() {
+        // Arrange
+        let mut controller = controller();
+        let message = Packet::Publish(Publish::new(
+            "measurement/inside",
+            QoS::AtLeastOnce,
+            r#"{"temperature":-400.0,"humidity":50.0}"#,
+            None,
+        ));
+
+        // Act
+        let action = controller.handle_incoming_message(message).await.unwrap();
+
+        // Assert
+        assert!(matches!(action, Some(Action::RegisterDeadLetter(..))));
+    }
+
+    #[tokio::test]
+    async fn out_of_range_inside_temperature_is_rejected() {
+        let mut controller = controller();
+        let message = |payload: &'static str| {
+            Packet::Publish(Publish::new(
+                "temperature/inside",
+                QoS::AtLeastOnce,
+                payload,
+                None,
+            ))
+        };
+
+        assert!(controller
+            .handle_incoming_message(message("-400"))
+            .await
+            .is_err());
+        assert!(matches!(
+            controller.handle_incoming_message(message("21.5")).await,
+            Ok(Some(Action::SetInsideTemperature(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn fahrenheit_setpoint_is_converted_to_celsius() {
+        let mut controller = controller();
+        let message = Packet::Publish(Publish::new(
+            "temperature/set/fahrenheit",
+            QoS::AtLeastOnce,
+            "70.7",
+            None,
+        ));
+
+        let action = controller.handle_incoming_message(message).await.unwrap();
+
+        assert!(matches!(
+            action,
+            Some(Action::SetDesiredTemperature(temperature, SetpointSource::Mqtt))
+                if (temperature - 21.5).abs() < 1e-9
+        ));
+    }
+
+    #[tokio::test]
+    async fn fahrenheit_inside_temperature_is_converted_to_celsius() {
+        let mut controller = controller();
+        let message = |payload: &'static str| {
+            Packet::Publish(Publish::new(
+                "temperature/inside/fahrenheit",
+                QoS::AtLeastOnce,
+                payload,
+                None,
+            ))
+        };
+
+        assert!(matches!(
+            controller.handle_incoming_message(message("68")).await,
+            Ok(Some(Action::SetInsideTemperature(temperature))) if temperature == 20.0
+        ));
+        // 200 °F is outside of the default bounds once converted.
+        assert!(controller
+            .handle_incoming_message(message("200"))
+            .await
+            .is_err());
+    }
+
+    #[sqlx::test]
+    fn executor_publishes_discovery_configs_on_startup(pool: SqlitePool) {
+        // Arrange
+        let (executor, tx, requests) = executor_with_sender(pool).await;
+
+        // Act
+        drop(tx);
+        executor
+            .run_until_completion(shutting_down())
+            .await
+            .unwrap();
+
+        // Assert
+        let topics: Vec<String> = published(&requests)
+            .into_iter()
+            .map(|(topic, _)| topic)
+            .filter(|topic| topic.starts_with("homeassistant/"))
+            .collect();
+        assert_eq!(
+            topics,
+            vec![
+                format!("homeassistant/climate/{HEATER_ID}/config"),
+                "homeassistant/sensor/inside/config".to_string(),
+                "homeassistant/sensor/outside/config".to_string(),
+            ]
+        );
+    }
+
+    #[sqlx::test]
+    fn executor_snapshot_reflects_handled_actions(pool: SqlitePool) {
+        // Arrange
+        let (executor, tx, _requests) = executor_with_sender(pool).await;
+        let snapshot = executor.snapshot();
+        tx.send(Action::EnableController(true)).await.unwrap();
+        tx.send(Action::SetDesiredTemperature(21.0, SetpointSource::Mqtt))
+            .await
+            .unwrap();
+        tx.send(Action::SetInsideTemperature(19.0)).await.unwrap();
+
+        // Act
+        drop(tx);
+        executor
+            .run_until_completion(shutting_down())
+            .await
+            .unwrap();
+
+        // Assert
+        let snapshot = snapshot.get();
+        assert!(snapshot.enabled);
+        assert_eq!(snapshot.desired_temperature, Some(21.0));
+        assert_eq!(snapshot.temperatures.get(INSIDE), Some(&19.0));
+        assert_eq!(
+            snapshot.heater_states.get(HEATER_ID),
+            Some(&HeaterState::On)
+        );
+    }
+
+    #[sqlx::test]
+    fn executor_publishes_event_of_handled_action(pool: SqlitePool) {
+        // Arrange
+        let (mut executor, _requests) = executor(pool).await;
+        let mut events = executor.events().subscribe();
+
+        // Act
+        executor
+            .handle_action(&Action::SetInsideTemperature(19.5))
+            .await
+            .unwrap();
+        executor
+            .handle_action(&Action::RegisterHeaterPower(HEATER_ID.to_string(), 400.0))
+            .await
+            .unwrap();
+
+        // Assert
+        assert_eq!(
+            events.try_recv().unwrap(),
+            Event::Reading {
+                place: INSIDE.to_string(),
+                temperature: 19.5
+            }
+        );
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn event_serializes_with_type_tag() {
+        // Arrange
+        let event = Event::HeaterState {
+            heater_id: HEATER_ID.to_string(),
+            state: HeaterState::On,
+        };
+
+        // Act
+        let json = serde_json::to_value(&event).unwrap();
+
+        // Assert
+        assert_eq!(
+            json,
+            serde_json::json!({ "type": "heater_state", "heater_id": HEATER_ID, "state": "on" })
+        );
+    }
+
+    #[sqlx::test]
+    fn reevaluate_checks_temperature_with_current_state(pool: SqlitePool) {
+        // Arrange
+        let (mut executor, requests) = executor(pool).await;
+        executor.state.enabled = true;
+        executor.state.desired_temperature = Some(21.0);
+        executor.state.record_temperature(INSIDE, 19.0);
+        executor
+            .state
+            .heater_states
+            .insert(HEATER_ID.to_string(), HeaterState::Off);
+
+        // Act
+        executor.handle_action(&Action::Reevaluate).await.unwrap();
+
+        // Assert
+        assert!(published(&requests).contains(&command(HEATER_ID, "on")));
+    }
+
+    #[sqlx::test]
+    fn config_request_publishes_config(pool: SqlitePool) {
+        // Arrange
+        let (executor, requests) = executor(pool).await;
+        let mut executor = executor.with_config(serde_json::json!({"mqtt": {"port": 1883}}));
+
+        // Act
+        executor
+            .handle_action(&Action::PublishConfig)
+            .await
+            .unwrap();
+
+        // Assert
+        assert_eq!(
+            published(&requests),
+            vec![(
+                "hub/config".to_string(),
+                r#"{"mqtt":{"port":1883}}"#.to_string()
+            )]
+        );
+    }
+
+    #[sqlx::test]
+    fn temperature_error_histogram_counts_evaluations(pool: SqlitePool) {
+        // Arrange
+        let (mut executor, _requests) = executor(pool).await;
+        let histogram = executor.temperature_error();
+        executor.state.desired_temperature = Some(21.0);
+        executor
+            .handle_action(&Action::SetInsideTemperature(15.0))
+            .await
+            .unwrap();
+        executor.state.enabled = true;
+
+        // Act
+        for temperature in [18.5, 20.8, 21.0, 21.3, 22.5] {
+            executor
+                .handle_action(&Action::SetInsideTemperature(temperature))
+                .await
+                .unwrap();
+        }
+
+        // Assert
+        let snapshot = histogram.snapshot();
+        let counts: Vec<u64> = snapshot.buckets.iter().map(|bucket| bucket.count).collect();
+        assert_eq!(counts, vec![1, 1, 1, 1, 3, 3, 4, 4, 5, 5]);
+        assert_eq!(snapshot.buckets[0].le, "-2");
+        assert_eq!(snapshot.buckets[9].le, "+Inf");
+        assert_eq!(snapshot.count, 5);
+        assert!((snapshot.sum + 0.9).abs() < 1e-9);
+    }
+
+    #[sqlx::test]
+    fn reloaded_config_changes_thresholds_and_heaters(pool: SqlitePool) {
+        // Arrange
+        let (mut executor, requests) = executor(pool).await;
+        executor.state.enabled = true;
+        executor.state.desired_temperature = Some(21.0);
+        executor.state.record_temperature(INSIDE, 20.8);
+        executor.check_temperature().await.unwrap();
+        assert!(published(&requests).is_empty());
+        let config = Config {
+            control: ControlConfig {
+                hysteresis: 0.2,
+                min_dwell_secs: 30,
+                ..Default::default()
+            },
+            heaters: vec![Heater::new(
+                "ABC123".into(),
+                "Anneks".into(),
+                "annex".into(),
+            )],
+            ..Default::default()
+        };
+
+        // Act
+        executor
+            .handle_action(&Action::ReloadConfig(Box::new(config)))
+            .await
+            .unwrap();
+
+        // Assert
+        assert_eq!(executor.state.hysteresis, 0.2);
+        assert_eq!(executor.dwell.min_dwell, Duration::from_secs(30));
+        assert!(executor
+            .heaters
+            .iter()
+            .any(|heater| heater.id() == "ABC123"));
+        assert!(published(&requests).contains(&command(HEATER_ID, "on")));
+    }
+
+    #[sqlx::test]
+    fn boost_raises_desired_temperature(pool: SqlitePool) {
+        // Arrange
+        let (mut executor, requests) = executor(pool).await;
+        executor.state.enabled = true;
+        executor.state.desired_temperature = Some(20.0);
+        executor.state.record_temperature(INSIDE, 21.0);
+        executor
+            .state
+            .heater_states
+            .insert(HEATER_ID.to_string(), HeaterState::Off);
+
+        // Act
+        executor
+            .handle_action(&Action::Boost {
+                delta: 2.0,
+                duration: Duration::from_secs(60 * 60),
+            })
+            .await
+            .unwrap();
+
+        // Assert
+        let published = published(&requests);
+        assert!(published.contains(&("hub/status/setpoint".to_string(), "22".to_string())));
+        assert!(published.contains(&command(HEATER_ID, "on")));
+        assert_eq!(
+            executor.state.desired_temperature_for(HEATER_ID),
+            Some(22.0)
+        );
+    }
+
+    #[sqlx::test]
+    fn boost_keeps_desired_temperature_within_range(pool: SqlitePool) {
+        // Arrange
+        let (mut executor, requests) = executor(pool).await;
+        executor.state.desired_temperature = Some(28.0);
+
+        // Act
+        executor
+            .handle_action(&Action::Boost {
+                delta: 5.0,
+                duration: Duration::from_secs(60 * 60),
+            })
+            .await
+            .unwrap();
+
+        // Assert
+        assert!(published(&requests).contains(&(
+            "hub/status/setpoint".to_string(),
+            MAX_DESIRED_TEMPERATURE.to_string()
+        )));
+        assert_eq!(
+            executor.state.desired_temperature_for(HEATER_ID),
+            Some(MAX_DESIRED_TEMPERATURE)
+        );
+    }
+
+    #[sqlx::test]
+    fn boost_reverts_once_expired(pool: SqlitePool) {
+        // Arrange
+        let (mut executor, requests) = executor(pool).await;
+        executor.state.desired_temperature = Some(20.0);
+        let duration = Duration::from_secs(60 * 60);
+        executor
+            .handle_action(&Action::Boost {
+                delta: 2.0,
+                duration,
+            })
+            .await
+            .unwrap();
+        let start = Instant::now();
+        requests.drain();
+
+        // Act
+        executor.expire_boost(start).await.unwrap();
+        let before_expiry = executor.state.desired_temperature_for(HEATER_ID);
+        executor.expire_boost(start + duration).await.unwrap();
+
+        // Assert
+        assert_eq!(before_expiry, Some(22.0));
+        assert_eq!(
+            executor.state.desired_temperature_for(HEATER_ID),
+            Some(20.0)
+        );
+        assert_eq!(executor.boost_expiry, None);
+        assert!(
+            published(&requests).contains(&("hub/status/setpoint".to_string(), "20".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn reevaluate_message_is_registered() {
+        let mut controller = controller();
+        let message = Packet::Publish(Publish::new("hub/reevaluate", QoS::AtLeastOnce, "", None));
+
+        let action = controller.handle_incoming_message(message).await.unwrap();
+
+        assert!(matches!(action, Some(Action::Reevaluate)));
+    }
+
+    #[sqlx::test]
+    fn executor_waits_for_relay_state_before_deciding(pool: SqlitePool) {
+        // Arrange
+        let (executor, tx, requests) = executor_with_sender(pool).await;
+        let snapshot = executor.snapshot();
+        tx.send(Action::EnableController(true)).await.unwrap();
+        tx.send(Action::SetDesiredTemperature(21.0, SetpointSource::Mqtt))
+            .await
+            .unwrap();
+        // Within the hysteresis band, so the decision depends on the relay.
+        tx.send(Action::SetInsideTemperature(21.0)).await.unwrap();
+        tx.send(Action::RegisterHeaterStateChange(
+            HEATER_ID.to_string(),
+            HeaterState::On,
+        ))
+        .await
+        .unwrap();
+
+        // Act
+        drop(tx);
+        executor
+            .run_until_completion(shutting_down())
+            .await
+            .unwrap();
+
+        // Assert
+        let published = published(&requests);
+        assert!(published.contains(&(
+            format!("shellies/shelly1-{HEATER_ID}/command"),
+            "update".to_string()
+        )));
+        assert!(published.contains(&(
+            format!("hub/status/heater/{HEATER_ID}"),
+            r#"{"state":"on","desired_temperature":21.0,"current_temperature":21.0}"#.to_string()
+        )));
+        assert_eq!(
+            snapshot.get().heater_states.get(HEATER_ID),
+            Some(&HeaterState::On)
+        );
+    }
+
+    #[sqlx::test]
+    fn relay_state_is_unknown_when_not_reported(pool: SqlitePool) {
+        // Arrange
+        let (mut executor, tx, _requests) = executor_with_sender(pool).await;
+        tx.send(Action::SetInsideTemperature(21.0)).await.unwrap();
+
+        // Act
+        let deferred = executor.await_relay_states(Duration::from_millis(10)).await;
+
+        // Assert
+        assert!(matches!(
+            deferred.as_slice(),
+            [Action::SetInsideTemperature(_)]
+        ));
+        assert_eq!(
+            executor.state.heater_states.get(HEATER_ID),
+            Some(&HeaterState::Unknown)
+        );
+    }
+
+    #[sqlx::test]
+    fn executor_fails_when_senders_are_dropped_without_shutdown(pool: SqlitePool) {
+        // Arrange
+        let (executor, tx, _requests) = executor_with_sender(pool).await;
+        let (_shutdown_tx, shutdown) = watch::channel(false);
+
+        // Act
+        drop(tx);
+        let result = executor.run_until_completion(shutdown).await;
+
+        // Assert
+        assert!(result.is_err());
+    }
+
+    #[sqlx::test]
+    fn executor_republishes_full_state_on_interval(pool: SqlitePool) {
+        // Arrange
+        let (mut executor, tx, requests) = executor_with_sender(pool).await;
+        executor.state.enabled = true;
+        executor.state.desired_temperature = Some(21.0);
+        executor
+            .state
+            .heater_states
+            .insert(HEATER_ID.to_string(), HeaterState::On);
+        tokio::time::pause();
+        let handle = tokio::spawn(executor.run_until_completion(shutting_down()));
+        tokio::time::sleep(DEFAULT_STATE_PUBLISH_INTERVAL - Duration::from_secs(1)).await;
+        let setpoint = ("hub/status/setpoint".to_string(), "21".to_string());
+        assert!(!published(&requests).contains(&setpoint));
+
+        // Act
+        tokio::time::sleep(Duration::from_secs(2)).await;
+
+        // Assert
+        let published = published(&requests);
+        assert!(published.contains(&setpoint));
+        assert!(published.contains(&("hub/status/mode".to_string(), "home".to_string())));
+        assert!(published.contains(&(
+            format!("hub/status/heater/{HEATER_ID}"),
+            r#"{"state":"on","desired_temperature":21.0,"current_temperature":null}"#.to_string()
+        )));
+        drop(tx);
+        handle.await.unwrap().unwrap();
+    }
+
+    #[sqlx::test]
+    fn executor_flushes_buffered_readings_on_shutdown(pool: SqlitePool) {
+        // Arrange
+        let (executor, tx, _requests) = executor_with_sender(pool.clone()).await;
+        for (place, temperature) in [(INSIDE, 20.5), ("outside", 21.0)] {
+            let measurement: Measurement = serde_json::from_str(&format!(
+                r#"{{"temperature":{temperature},"humidity":50.0}}"#
+            ))
+            .unwrap();
+            tx.send(Action::RegisterMeasurement(place.to_string(), measurement))
+                .await
+                .unwrap();
+        }
+
+        // Act
+        drop(tx);
+        executor
+            .run_until_completion(shutting_down())
+            .await
+            .unwrap();
+
+        // Assert
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM history")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(count, 2);
+    }
+
+    #[sqlx::test]
+    fn reading_of_custom_place_is_stored_without_checking_heaters(pool: SqlitePool) {
+        // Arrange
+        let (mut executor, requests) = executor(pool.clone()).await;
+        executor.state.enabled = true;
+        executor.state.desired_temperature = Some(21.0);
+        let measurement: Measurement =
+            serde_json::from_str(r#"{"temperature":12.5,"humidity":70.0}"#).unwrap();
+
+        // Act
+        executor
+            .handle_action(&Action::RegisterMeasurement(
+                "garage".to_string(),
+                measurement,
+            ))
+            .await
+            .unwrap();
+        executor.flush_readings().await.unwrap();
+
+        // Assert
+        let stored: Vec<(String, f64)> =
+            sqlx::query_as("SELECT location, temperature FROM history")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        assert_eq!(stored, vec![("garage".to_string(), 12.5)]);
+        assert_eq!(executor.state.temperatures.get("garage"), Some(&12.5));
+        assert!(published(&requests).is_empty());
+    }
+
+    #[sqlx::test]
+    fn failed_flush_keeps_readings_for_the_next_flush(pool: SqlitePool) {
+        // Arrange
+        let (mut executor, _requests) = executor(pool.clone()).await;
+        let measurement: Measurement =
+            serde_json::from_str(r#"{"temperature":12.5,"humidity":70.0}"#).unwrap();
+        executor
+            .handle_action(&Action::RegisterMeasurement(
+                "garage".to_string(),
+                measurement,
+            ))
+            .await
+            .unwrap();
+        sqlx::query("ALTER TABLE history RENAME TO history_unavailable")
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert!(executor.flush_readings().await.is_err());
+        sqlx::query("ALTER TABLE history_unavailable RENAME TO history")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        // Act
+        executor.flush_readings().await.unwrap();
+
+        // Assert
+        let stored: Vec<(String, f64)> =
+            sqlx::query_as("SELECT location, temperature FROM history")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        assert_eq!(stored, vec![("garage".to_string(), 12.5)]);
+        assert!(executor.pending_readings.is_empty());
+    }
+
+    #[sqlx::test]
+    fn successful_writes_are_tracked(pool: SqlitePool) {
+        // Arrange
+        let (mut executor, _requests) = executor(pool).await;
+        let db_health = executor.db_health();
+        let before = Utc::now();
+
+        // Act
+        executor
+            .handle_action(&Action::RegisterHeaterPower(HEATER_ID.to_string(), 400.0))
+            .await
+            .unwrap();
+
+        // Assert
+        assert!(db_health
+            .last_write()
+            .is_some_and(|last_write| last_write >= before));
+    }
+
+    #[sqlx::test]
+    fn rapid_readings_are_stored_at_the_minimum_interval(pool: SqlitePool) {
+        // Arrange
+        let (mut executor, _requests) = executor(pool.clone()).await;
+        executor.min_reading_interval = Duration::from_millis(100);
+        let reading = |temperature: f64| {
+            let measurement: Measurement = serde_json::from_str(&format!(
+                r#"{{"temperature":{temperature},"humidity":50.0}}"#
+            ))
+            .unwrap();
+            Action::RegisterMeasurement(INSIDE.to_string(), measurement)
+        };
+
+        // Act
+        for temperature in [20.0, 20.1, 20.2, 20.3] {
+            executor.handle_action(&reading(temperature)).await.unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        executor.handle_action(&reading(20.4)).await.unwrap();
+        executor.flush_readings().await.unwrap();
+
+        // Assert
+        let stored: Vec<f64> = sqlx::query_scalar("SELECT temperature FROM history ORDER BY rowid")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(stored, vec![20.0, 20.4]);
+        assert_eq!(executor.state.temperatures.get(INSIDE), Some(&20.4));
+    }
+
+    /// Get the topics and payloads of all the requests published so far.
+    fn published(requests: &flume::Receiver<Request>) -> Vec<(String, String)> {
+        requests
+            .try_iter()
+            .filter_map(|request| match request {
+                Request::Publish(publish) => Some((
+                    String::from_utf8_lossy(&publish.topic).to_string(),
+                    String::from_utf8_lossy(&publish.payload).to_string(),
+                )),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[sqlx::test]
+    fn commands_and_statuses_are_published_with_their_flags(pool: SqlitePool) {
+        // Arrange
+        let (mut executor, requests) = executor(pool).await;
+        executor.command_qos = QoS::ExactlyOnce;
+        let heater = heater(HEATER_ID);
+
+        // Act
+        executor
+            .set_heater_state(&heater, HeaterState::On)
+            .await
+            .unwrap();
+        executor
+            .publish_heater_status(&heater, TargetState::On)
+            .await
+            .unwrap();
+
+        // Assert
+        let flags: Vec<_> = requests
+            .try_iter()
+            .filter_map(|request| match request {
+                Request::Publish(publish) => Some((
+                    String::from_utf8_lossy(&publish.topic).to_string(),
+                    publish.qos,
+                    publish.retain,
+                )),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            flags,
+            vec![
+                (command(HEATER_ID, "on").0, QoS::ExactlyOnce, false),
+                (
+                    format!("hub/status/heater/{HEATER_ID}"),
+                    QoS::AtLeastOnce,
+                    true
+                ),
+            ]
+        );
+    }
+
+    fn command(heater_id: &str, state: &str) -> (String, String) {
+        (
+            format!("shellies/shelly1-{heater_id}/relay/0/command"),
+            state.to_string(),
+        )
+    }
+
+    #[sqlx::test]
+    fn check_temperature_publishes_command_per_heater(pool: SqlitePool) {
+        // Arrange
+        let (mut executor, requests) = executor(pool).await;
+        executor.heaters = vec![
+            heater(HEATER_ID),
+            Heater::new("ABC123".into(), "Anneks".into(), "annex".into()),
+        ];
+        executor.state.enabled = true;
+        executor.state.desired_temperature = Some(21.0);
+        executor.state.record_temperature(INSIDE, 19.0);
+        executor.state.record_temperature("annex", 23.0);
+
+        // Act
+        executor.check_temperature().await.unwrap();
+
+        // Assert
+        let commands: Vec<_> = published(&requests)
+            .into_iter()
+            .filter(|(topic, _)| topic.ends_with("/command"))
+            .collect();
+        assert_eq!(
+            commands,
+            vec![command(HEATER_ID, "on"), command("ABC123", "off")]
+        );
+    }
+
+    #[sqlx::test]
+    fn plus_heater_is_asked_for_its_state_on_its_own_topic(pool: SqlitePool) {
+        // Arrange
+        let (executor, requests) = executor(pool).await;
+        let heater = heater(HEATER_ID).with_firmware(Firmware::Plus);
+
+        // Act
+        executor.request_relay_state(&heater).await.unwrap();
+
+        // Assert
+        assert_eq!(
+            published(&requests),
+            vec![(
+                format!("shellyplus1-{HEATER_ID}/command"),
+                "status_update".to_string()
+            )]
+        );
+    }
+
+    #[sqlx::test]
+    fn plus_heater_is_commanded_with_json(pool: SqlitePool) {
+        // Arrange
+        let (executor, requests) = executor(pool).await;
+        let heater = heater(HEATER_ID).with_firmware(Firmware::Plus);
+
+        // Act
+        executor
+            .set_heater_state(&heater, HeaterState::On)
+            .await
+            .unwrap();
+
+        // Assert
+        assert_eq!(
+            published(&requests),
+            vec![(
+                format!("shellyplus1-{HEATER_ID}/rpc"),
+                r#"{"id":0,"on":true}"#.to_string()
+            )]
+        );
+    }
+
+    #[sqlx::test]
+    fn dry_run_logs_heater_command_without_publishing(pool: SqlitePool) {
+        // Arrange
+        let (mut executor, requests) = executor(pool).await;
+        executor.dry_run = true;
+        let logs = telemetry::test::Buffer::default();
+        let _guard = tracing::subscriber::set_default(telemetry::create_minimal_subscriber(
+            "paletten_cloud_hub".to_string(),
+            tracing::Level::INFO,
+            telemetry::LogFormat::Json,
+            logs.clone(),
+        ));
+
+        // Act
+        for action in [
+            Action::EnableController(true),
+            Action::SetDesiredTemperature(21.0, SetpointSource::Mqtt),
+            Action::SetInsideTemperature(19.0),
+        ] {
+            executor.handle_action(&action).await.unwrap();
+        }
+
+        // Assert
+        let topics: Vec<String> = published(&requests).into_iter().map(|(t, _)| t).collect();
+        assert!(!topics.contains(&command(HEATER_ID, "on").0));
+        assert!(topics.contains(&format!("hub/status/heater/{HEATER_ID}")));
+        assert_eq!(
+            executor.state.heater_states.get(HEATER_ID),
+            Some(&HeaterState::On)
+        );
+        let logged = logs.contents();
+        let line = logged
+            .lines()
+            .find(|line| line.contains("Dry run, not publishing heater command"))
+            .expect("the command to be logged");
+        assert!(line.contains(r#""heater_id":"C4402D""#));
+        assert!(line.contains(r#""state":"on""#));
+    }
+
+    #[sqlx::test]
+    fn pid_strategy_switches_heater_by_duty_cycle(pool: SqlitePool) {
+        // Arrange
+        let (mut executor, requests) = executor(pool).await;
+        executor.strategy = ControlStrategy::Pid {
+            kp: 0.5,
+            ki: 0.0,
+            kd: 0.0,
+        };
+        executor
+            .handle_action(&Action::EnableController(true))
+            .await
+            .unwrap();
+        executor
+            .handle_action(&Action::SetDesiredTemperature(21.0, SetpointSource::Mqtt))
+            .await
+            .unwrap();
+
+        // Act
+        executor
+            .handle_action(&Action::SetInsideTemperature(20.8))
+            .await
+            .unwrap();
+
+        // Assert
+        assert!(published(&requests).contains(&command(HEATER_ID, "on")));
+        // A duty cycle of 10% switches the heater off again after a minute.
+        let next_switch = executor.next_regulator_switch().unwrap();
+        assert!(next_switch < Instant::now() + executor.pid_window / 5);
+    }
+
+    #[sqlx::test]
+    fn override_forces_heater_state_until_it_expires(pool: SqlitePool) {
+        // Arrange
+        let (mut executor, requests) = executor(pool).await;
+        executor.dwell = RelayDwell::new(Duration::ZERO);
+        for action in [
+            Action::EnableController(true),
+            Action::SetDesiredTemperature(20.0, SetpointSource::Mqtt),
+            Action::SetInsideTemperature(22.0),
+        ] {
+            executor.handle_action(&action).await.unwrap();
+        }
+        published(&requests);
+
+        // Act
+        executor
+            .handle_action(&Action::OverrideHeater {
+                id: HEATER_ID.to_string(),
+                state: HeaterState::On,
+                duration: Duration::from_secs(30 * 60),
+            })
+            .await
+            .unwrap();
+        executor
+            .handle_action(&Action::SetInsideTemperature(22.5))
+            .await
+            .unwrap();
+
+        // Assert
+        let published = published(&requests);
+        assert!(published.contains(&command(HEATER_ID, "on")));
+        assert!(!published.contains(&command(HEATER_ID, "off")));
+        assert_eq!(
+            executor.state.heater_states.get(HEATER_ID),
+            Some(&HeaterState::On)
+        );
+    }
+
+    #[sqlx::test]
+    fn expired_override_returns_heater_to_automatic_control(pool: SqlitePool) {
+        // Arrange
+        let (mut executor, requests) = executor(pool).await;
+        executor.dwell = RelayDwell::new(Duration::ZERO);
+        for action in [
+            Action::EnableController(true),
+            Action::SetDesiredTemperature(20.0, SetpointSource::Mqtt),
+            Action::SetInsideTemperature(22.0),
+        ] {
+            executor.handle_action(&action).await.unwrap();
+        }
+        let duration = Duration::from_secs(30 * 60);
+        executor
+            .handle_action(&Action::OverrideHeater {
+                id: HEATER_ID.to_string(),
+                state: HeaterState::On,
+                duration,
+            })
+            .await
+            .unwrap();
+        published(&requests);
+
+        // Act
+        executor
+            .expire_overrides(Instant::now() + Duration::from_secs(60))
+            .await
+            .unwrap();
+        let before_expiry = published(&requests);
+        executor
+            .expire_overrides(Instant::now() + duration)
+            .await
+            .unwrap();
+
+        // Assert
+        assert!(before_expiry.is_empty());
+        assert!(executor.overrides.is_empty());
+        assert!(published(&requests).contains(&command(HEATER_ID, "off")));
+    }
+
+    #[sqlx::test]
+    fn frost_protection_turns_heaters_on_when_disabled(pool: SqlitePool) {
+        // Arrange
+        let (mut executor, requests) = executor(pool).await;
+        executor
+            .handle_action(&Action::EnableController(false))
+            .await
+            .unwrap();
+
+        // Act
+        executor
+            .handle_action(&Action::SetInsideTemperature(3.0))
+            .await
+            .unwrap();
+
+        // Assert
+        assert!(published(&requests).contains(&command(HEATER_ID, "on")));
+    }
+
+    #[sqlx::test]
+    fn frost_protection_turns_heaters_off_again_when_disabled(pool: SqlitePool) {
+        // Arrange
+        let (mut executor, requests) = executor(pool).await;
+        executor.dwell = RelayDwell::new(Duration::ZERO);
+        executor
+            .handle_action(&Action::SetInsideTemperature(3.0))
+            .await
+            .unwrap();
+        published(&requests);
+
+        // Act
+        executor
+            .handle_action(&Action::SetInsideTemperature(8.0))
+            .await
+            .unwrap();
+
+        // Assert
+        assert!(published(&requests).contains(&command(HEATER_ID, "off")));
+        assert!(executor.state.frost_protected.is_empty());
+    }
+
+    #[sqlx::test]
+    fn over_temperature_forces_heaters_off_and_alerts(pool: SqlitePool) {
+        // Arrange
+        let (mut executor, requests) = executor(pool).await;
+        executor
+            .handle_action(&Action::SetDesiredTemperature(35.0, SetpointSource::Mqtt))
+            .await
+            .unwrap();
+        executor
+            .handle_action(&Action::EnableController(true))
+            .await
+            .unwrap();
+
+        // Act
+        executor
+            .handle_action(&Action::SetInsideTemperature(31.0))
+            .await
+            .unwrap();
+
+        // Assert
+        let published = published(&requests);
+        assert!(published.contains(&command(HEATER_ID, "off")));
+        assert!(!published.contains(&command(HEATER_ID, "on")));
+        assert!(published.iter().any(|(topic, payload)| {
+            topic == "hub/alert/temperature" && payload.contains(r#""active":true"#)
+        }));
+    }
+
+    #[sqlx::test]
+    fn over_temperature_only_commands_heaters_not_already_off(pool: SqlitePool) {
+        // Arrange
+        let (mut executor, requests) = executor(pool).await;
+        executor
+            .handle_action(&Action::SetDesiredTemperature(35.0, SetpointSource::Mqtt))
+            .await
+            .unwrap();
+        executor
+            .handle_action(&Action::EnableController(true))
+            .await
+            .unwrap();
+        executor
+            .handle_action(&Action::SetInsideTemperature(31.0))
+            .await
+            .unwrap();
+        assert!(published(&requests).contains(&command(HEATER_ID, "off")));
+
+        // Act
+        executor
+            .handle_action(&Action::SetInsideTemperature(31.5))
+            .await
+            .unwrap();
+
+        // Assert
+        let published = published(&requests);
+        assert!(!published.contains(&command(HEATER_ID, "off")));
+        assert!(!published
+            .iter()
+            .any(|(topic, _)| topic == &format!("hub/status/heater/{HEATER_ID}")));
+    }
+
+    #[sqlx::test]
+    fn unconfirmed_heater_command_publishes_alert(pool: SqlitePool) {
+        // Arrange
+        let (mut executor, requests) = executor(pool).await;
+        executor
+            .handle_action(&Action::SetDesiredTemperature(22.0, SetpointSource::Mqtt))
+            .await
+            .unwrap();
+        executor
+            .handle_action(&Action::EnableController(true))
+            .await
+            .unwrap();
+        executor
+            .handle_action(&Action::SetInsideTemperature(18.0))
+            .await
+            .unwrap();
+        assert!(published(&requests).contains(&command(HEATER_ID, "on")));
+
+        // Act
+        let timeout = DEFAULT_COMMAND_CONFIRMATION_TIMEOUT;
+        executor
+            .check_confirmations(Instant::now() + timeout)
+            .await
+            .unwrap();
+
+        // Assert
+        assert!(published(&requests).contains(&(
+            "hub/alert/heater".to_string(),
+            format!(r#"{{"heater_id":"{HEATER_ID}","state":"on","timeout_secs":30}}"#)
+        )));
+        assert_eq!(executor.confirmations.next_deadline(), None);
+    }
+
+    #[sqlx::test]
+    fn repeated_heater_command_keeps_confirmation_deadline(pool: SqlitePool) {
+        // Arrange
+        let (mut executor, requests) = executor(pool).await;
+        executor
+            .handle_action(&Action::SetDesiredTemperature(22.0, SetpointSource::Mqtt))
+            .await
+            .unwrap();
+        executor
+            .handle_action(&Action::EnableController(true))
+            .await
+            .unwrap();
+        executor
+            .handle_action(&Action::SetInsideTemperature(18.0))
+            .await
+            .unwrap();
+        let deadline = executor.confirmations.next_deadline().unwrap();
+
+        // Act
+        for temperature in [18.1, 18.2, 18.3] {
+            executor
+                .handle_action(&Action::SetInsideTemperature(temperature))
+                .await
+                .unwrap();
+        }
+        executor.check_confirmations(deadline).await.unwrap();
+
+        // Assert
+        assert!(published(&requests).contains(&(
+            "hub/alert/heater".to_string(),
+            format!(r#"{{"heater_id":"{HEATER_ID}","state":"on","timeout_secs":30}}"#)
+        )));
+    }
+
+    #[sqlx::test]
+    fn confirmed_heater_command_does_not_alert(pool: SqlitePool) {
+        // Arrange
+        let (mut executor, requests) = executor(pool).await;
+        executor
+            .handle_action(&Action::OverrideHeater {
+                id: HEATER_ID.to_string(),
+                state: HeaterState::On,
+                duration: Duration::from_secs(60),
+            })
+            .await
+            .unwrap();
+        executor
+            .handle_action(&Action::RegisterHeaterStateChange(
+                HEATER_ID.to_string(),
+                HeaterState::On,
+            ))
+            .await
+            .unwrap();
+
+        // Act
+        executor
+            .check_confirmations(Instant::now() + Duration::from_secs(3600))
+            .await
+            .unwrap();
+
+        // Assert
+        assert!(!published(&requests)
+            .iter()
+            .any(|(topic, _)| topic == "hub/alert/heater"));
+    }
+
+    #[test]
+    fn confirmation_of_other_state_keeps_command_pending() {
+        let mut confirmations = PendingConfirmations::new(&ControlConfig::default());
+        let now = Instant::now();
+        confirmations.expect(HEATER_ID, HeaterState::On, now);
+
+        confirmations.confirm(HEATER_ID, HeaterState::Off);
+
+        assert_eq!(
+            confirmations.take_expired(now + Duration::from_secs(30)),
+            vec![(HEATER_ID.to_string(), HeaterState::On)]
+        );
+    }
+
+    #[test]
+    fn confirmations_are_not_tracked_in_dry_run() {
+        let mut confirmations = PendingConfirmations::new(&ControlConfig {
+            dry_run: true,
+            ..ControlConfig::default()
+        });
+
+        confirmations.expect(HEATER_ID, HeaterState::On, Instant::now());
+
+        assert_eq!(confirmations.next_deadline(), None);
+    }
+
+    #[test]
+    fn over_temperature_triggers_above_ceiling() {
+        let mut state = state(30.5, None);
+
+        let alert = state.update_over_temperature(&[heater(HEATER_ID)]);
+
+        assert!(state.over_temperature);
+        assert_eq!(
+            alert,
+            Some(TemperatureAlert {
+                active: true,
+                place: INSIDE.to_string(),
+                temperature: 30.5,
+                ceiling: DEFAULT_MAX_TEMPERATURE,
+            })
+        );
+        assert_eq!(state.update_over_temperature(&[heater(HEATER_ID)]), None);
+    }
+
+    #[test]
+    fn over_temperature_releases_below_band() {
+        let heaters = [heater(HEATER_ID)];
+        let mut state = state(31.0, None);
+        state.update_over_temperature(&heaters);
+
+        state.temperatures.insert(INSIDE.to_string(), 29.8);
+        assert_eq!(state.update_over_temperature(&heaters), None);
+        assert!(state.over_temperature);
+
+        state.temperatures.insert(INSIDE.to_string(), 29.5);
+        let alert = state.update_over_temperature(&heaters).unwrap();
+        assert!(!alert.active);
+        assert!(!state.over_temperature);
+    }
+
+    #[test]
+    fn over_temperature_ignores_places_without_heaters() {
+        let mut state = state(20.0, None);
+        state.temperatures.insert("outside".to_string(), 35.0);
+
+        assert_eq!(state.update_over_temperature(&[heater(HEATER_ID)]), None);
+        assert!(!state.over_temperature);
+    }
+
+    #[test]
+    fn frost_protection_stays_engaged_within_band() {
+        let mut state = state(5.1, None);
+        state.frost_protected.insert(HEATER_ID.to_string());
+
+        assert_eq!(
+            state.target_state(&heater(HEATER_ID)),
+            Some(TargetState::On)
+        );
+    }
+
+    #[test]
+    fn target_state_idle_when_disabled() {
+        let mut state = state(18.0, None);
+
+        assert_eq!(
+            state.target_state(&heater(HEATER_ID)),
+            Some(TargetState::Idle)
+        );
+    }
+
+    #[test]
+    fn mqtt_config_defaults_when_env_is_empty() {
+        let config = MqttConfig::default().with_env_overrides(&|_| None).unwrap();
+        assert_eq!(config, MqttConfig::default());
+    }
+
+    #[test]
+    fn mqtt_config_from_env() {
+        // Arrange
+        let env = HashMap::from([
+            ("MQTT_HOST", "localhost"),
+            ("MQTT_PORT", "8883"),
+            ("MQTT_CLIENT_ID", "test-hub"),
+        ]);
+
+        // Act
+        let config = MqttConfig::default()
+            .with_env_overrides(&|key| env.get(key).map(|v| v.to_string()))
+            .unwrap();
+
+        // Assert
+        assert_eq!(config.host, "localhost");
+        assert_eq!(config.port, 8883);
+        assert_eq!(config.client_id, "test-hub");
+    }
+
+    #[test]
+    fn mqtt_config_default_subscriptions() {
+        let config = MqttConfig::default();
+
+        assert_eq!(
+            config.filters(),
+            vec![
+                Filter::new("temperature/+", ExactlyOnce),
+                Filter::new("temperature/inside/fahrenheit", QoS::AtMostOnce),
+                Filter::new("temperature/set/+", ExactlyOnce),
+                Filter::new("measurement/#", QoS::AtMostOnce),
+                Filter::new("shellies/+/relay/0", ExactlyOnce),
+                Filter::new("+/status/switch:0", ExactlyOnce),
+                Filter::new("shellies/+/relay/0/power", QoS::AtMostOnce),
+                Filter::new("shellies/+/relay/0/energy", QoS::AtMostOnce),
+                Filter::new("heater/+/override", ExactlyOnce),
+                Filter::new("hub/reevaluate", ExactlyOnce),
+                Filter::new("hub/config/get", ExactlyOnce),
+            ]
+        );
+    }
+
+    #[test]
+    fn mqtt_config_with_extra_subscriptions() {
+        // Arrange
+        let env = HashMap::from([(
+            "MQTT_SUBSCRIPTIONS",
+            "temperature/+, measurement/+,door/+/state,",
+        )]);
+
+        // Act
+        let config = MqttConfig::default()
+            .with_env_overrides(&|key| env.get(key).map(|v| v.to_string()))
+            .unwrap();
+
+        // Assert
+        assert_eq!(
+            config.filters(),
+            vec![
+                Filter::new("temperature/+", ExactlyOnce),
+                Filter::new("measurement/+", ExactlyOnce),
+                Filter::new("door/+/state", ExactlyOnce),
+            ]
+        );
+    }
+
+    #[test]
+    fn mqtt_config_rejects_topic_prefix_with_wildcards() {
+        let env = HashMap::from([("MQTT_TOPIC_PREFIX", "apartment+/")]);
+
+        assert!(MqttConfig::default()
+            .with_env_overrides(&|key| env.get(key).map(|v| v.to_string()))
+            .is_err());
+    }
+
+    #[test]
+    fn mqtt_config_rejects_invalid_subscription() {
+        let env = HashMap::from([("MQTT_SUBSCRIPTIONS", "temperature/+,door/#/state")]);
+
+        assert!(MqttConfig::default()
+            .with_env_overrides(&|key| env.get(key).map(|v| v.to_string()))
+            .is_err());
+    }
+
+    #[test]
+    fn mqtt_options_set_offline_last_will() {
+        let options = create_mqtt_options(&MqttConfig::default()).unwrap();
+
+        let will = options.last_will().expect("last will to be configured");
+
+        assert_eq!(will.topic, AVAILABILITY_TOPIC.as_bytes());
+        assert_eq!(will.message, "offline".as_bytes());
+        assert_eq!(will.qos, QoS::AtLeastOnce);
+        assert!(will.retain);
+    }
+
+    #[test]
+    fn publish_availability_announces_online() {
+        let (request_tx, requests) = flume::unbounded();
+        let (tx, _rx) = channel(10);
+        let (_, eventloop) = AsyncClient::new(MqttOptions::new("test", "localhost", 1883), 10);
+        let controller = Controller::new(
+            eventloop,
+            AsyncClient::from_senders(request_tx),
+            tx,
+            Vec::new(),
+        );
+
+        controller.publish_availability();
+
+        assert_eq!(
+            published(&requests),
+            vec![(AVAILABILITY_TOPIC.to_string(), "online".to_string())]
+        );
+    }
+
+    #[test]
+    fn mqtt_options_use_keep_alive_and_timeout_from_config() {
+        // Arrange
+        let env = HashMap::from([
+            ("MQTT_KEEP_ALIVE_SECS", "60"),
+            ("MQTT_CONNECTION_TIMEOUT_SECS", "30"),
+        ]);
+        let config = MqttConfig::default()
+            .with_env_overrides(&|key| env.get(key).map(|v| v.to_string()))
+            .unwrap();
+
+        // Act
+        let options = create_mqtt_options(&config).unwrap();
+
+        // Assert
+        assert_eq!(options.keep_alive(), Duration::from_secs(60));
+        assert_eq!(options.connection_timeout(), 30);
+    }
+
+    #[test]
+    fn mqtt_options_reject_too_short_keep_alive() {
+        let config = MqttConfig {
+            keep_alive_secs: 2,
+            ..MqttConfig::default()
+        };
+
+        assert!(create_mqtt_options(&config).is_err());
+    }
+
+    #[test]
+    fn mqtt_options_reject_failover_without_failures() {
+        let config = MqttConfig {
+            failover_after: 0,
+            ..MqttConfig::default()
+        };
+
+        assert!(create_mqtt_options(&config).is_err());
+    }
+
+    #[test]
+    fn mqtt_options_use_tcp_without_tls() {
+        let options = create_mqtt_options(&MqttConfig::default()).unwrap();
+        assert!(matches!(options.transport(), Transport::Tcp));
+    }
+
+    #[test]
+    fn mqtt_options_use_tls_with_ca_file() {
+        // Arrange
+        let ca_path = std::env::temp_dir().join("paletten-cloud-hub-test-ca.pem");
+        std::fs::write(&ca_path, b"test certificate").unwrap();
+        let config = MqttConfig {
+            use_tls: true,
+            ca_path: Some(ca_path),
+            ..Default::default()
+        };
+
+        // Act
+        let options = create_mqtt_options(&config).unwrap();
+
+        // Assert
+        match options.transport() {
+            Transport::Tls(TlsConfiguration::Simple { ca, .. }) => {
+                assert_eq!(ca, b"test certificate")
+            }
+            _ => panic!("expected TLS transport with the configured CA"),
+        }
+    }
+
+    #[test]
+    fn mqtt_options_fail_with_missing_ca_file() {
+        let config = MqttConfig {
+            use_tls: true,
+            ca_path: Some(PathBuf::from("/does/not/exist.pem")),
+            ..Default::default()
+        };
+        assert!(create_mqtt_options(&config).is_err());
+    }
+
+    #[test]
+    fn mqtt_config_with_credentials() {
+        // Arrange
+        let env = HashMap::from([("MQTT_USERNAME", "hub"), ("MQTT_PASSWORD", "secret")]);
+
+        // Act
+        let config = MqttConfig::default()
+            .with_env_overrides(&|key| env.get(key).map(|v| v.to_string()))
+            .unwrap();
+        let options = create_mqtt_options(&config).unwrap();
+
+        // Assert
+        assert_eq!(
+            options.credentials(),
+            Some(("hub".to_string(), "secret".to_string()))
+        );
+        assert!(!format!("{config:?}").contains("secret"));
+    }
+
+    #[test]
+    fn mqtt_config_anonymous_with_partial_credentials() {
+        let config = MqttConfig::default()
+            .with_env_overrides(&|key| (key == "MQTT_USERNAME").then(|| "hub".to_string()))
+            .unwrap();
+        assert_eq!(config.credentials, None);
+    }
+
+    #[test]
+    fn mqtt_config_rejects_invalid_port() {
+        let result = MqttConfig::default()
+            .with_env_overrides(&|key| (key == "MQTT_PORT").then(|| "abc".to_string()));
+        assert!(result.is_err());
+    }
+
+    const HEATER_ID: &str = "C4402D";
+
+    fn heater(id: &str) -> Heater {
+        Heater::new(id.to_string(), "Test".to_string(), INSIDE.to_string())
+    }
+
+    fn state(current: f64, heater_state: Option<HeaterState>) -> State {
+        State {
+            desired_temperature: Some(20.0),
+            temperatures: HashMap::from([(INSIDE.to_string(), current)]),
+            heater_states: heater_state
+                .map(|state| HashMap::from([(HEATER_ID.to_string(), state)]))
+                .unwrap_or_default(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn relay_dwell_allows_first_change() {
+        let mut dwell = RelayDwell::new(DEFAULT_MIN_DWELL);
+        assert!(dwell.request(
+            HEATER_ID,
+            HeaterState::Unknown,
+            HeaterState::On,
+            Instant::now()
+        ));
+        assert_eq!(dwell.next_deadline(), None);
+    }
+
+    #[test]
+    fn relay_dwell_defers_second_toggle_within_window() {
+        // Arrange
+        let start = Instant::now();
+        let mut dwell = RelayDwell::new(DEFAULT_MIN_DWELL);
+        dwell.request(HEATER_ID, HeaterState::Unknown, HeaterState::On, start);
+
+        // Act
+        let allowed = dwell.request(
+            HEATER_ID,
+            HeaterState::On,
+            HeaterState::Off,
+            start + Duration::from_secs(30),
+        );
+
+        // Assert
+        assert!(!allowed);
+        assert_eq!(dwell.next_deadline(), Some(start + DEFAULT_MIN_DWELL));
+        assert!(dwell.take_due(start + Duration::from_secs(60)).is_empty());
+        assert_eq!(
+            dwell.take_due(start + DEFAULT_MIN_DWELL),
+            vec![(HEATER_ID.to_string(), HeaterState::Off)]
+        );
+        assert_eq!(dwell.next_deadline(), None);
+    }
+
+    #[test]
+    fn relay_dwell_allows_toggle_after_window() {
+        let start = Instant::now();
+        let mut dwell = RelayDwell::new(DEFAULT_MIN_DWELL);
+        dwell.request(HEATER_ID, HeaterState::Unknown, HeaterState::On, start);
+
+        assert!(dwell.request(
+            HEATER_ID,
+            HeaterState::On,
+            HeaterState::Off,
+            start + DEFAULT_MIN_DWELL,
+        ));
+    }
+
+    #[test]
+    fn relay_dwell_drops_pending_change_when_reverted() {
+        let start = Instant::now();
+        let mut dwell = RelayDwell::new(DEFAULT_MIN_DWELL);
+        dwell.request(HEATER_ID, HeaterState::Unknown, HeaterState::On, start);
+        dwell.request(
+            HEATER_ID,
+            HeaterState::On,
+            HeaterState::Off,
+            start + Duration::from_secs(10),
+        );
+
+        assert!(dwell.request(
+            HEATER_ID,
+            HeaterState::On,
+            HeaterState::On,
+            start + Duration::from_secs(20),
+        ));
+        assert_eq!(dwell.next_deadline(), None);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_cap() {
+        let mut backoff = Backoff::default();
+        let delays: Vec<u64> = (0..8).map(|_| backoff.next_delay().as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 8, 16, 32, 60, 60]);
+    }
+
+    fn broker_address(options: Option<&MqttOptions>) -> Option<(String, u16)> {
+        options.map(MqttOptions::broker_address)
+    }
+
+    #[test]
+    fn failover_selects_next_broker_after_consecutive_failures() {
+        let config = MqttConfig {
+            host: "primary.local".to_string(),
+            fallback_brokers: vec![BrokerEndpoint {
+                host: "backup.local".to_string(),
+                port: 1884,
+            }],
+            failover_after: 2,
+            ..Default::default()
+        };
+        let mut failover = Failover::new(&config).unwrap();
+
+        assert_eq!(broker_address(failover.record_failure()), None);
+        failover.record_success();
+        assert_eq!(broker_address(failover.record_failure()), None);
+        assert_eq!(
+            broker_address(failover.record_failure()),
+            Some(("backup.local".to_string(), 1884))
+        );
+        assert_eq!(broker_address(failover.record_failure()), None);
+        assert_eq!(
+            broker_address(failover.record_failure()),
+            Some(("primary.local".to_string(), DEFAULT_MQTT_PORT))
+        );
+    }
+
+    #[test]
+    fn failover_without_fallback_brokers_keeps_broker() {
+        let mut failover = Failover::new(&MqttConfig::default()).unwrap();
+
+        for _ in 0..10 {
+            assert!(failover.record_failure().is_none());
+        }
+    }
+
+    #[test]
+    fn backoff_reset_starts_over() {
+        let mut backoff = Backoff::default();
+        backoff.next_delay();
+        backoff.next_delay();
+
+        backoff.reset();
+
+        assert_eq!(backoff.next_delay(), INITIAL_RECONNECT_DELAY);
+    }
+
+    #[test]
+    fn desired_temperature_for_falls_back_to_global() {
+        let state = State {
+            desired_temperature: Some(20.0),
+            heater_desired_temperatures: HashMap::from([("10DB9C".to_string(), 17.0)]),
+            ..Default::default()
+        };
+
+        assert_eq!(state.desired_temperature_for("10DB9C"), Some(17.0));
+        assert_eq!(state.desired_temperature_for(HEATER_ID), Some(20.0));
+    }
+
+    #[test]
+    fn desired_temperature_is_compensated_for_outside_temperature() {
+        let mut state = State {
+            desired_temperature: Some(20.0),
+            weather_compensation: Some(WeatherCompensation {
+                slope: 0.1,
+                reference_outside_temperature: 10.0,
+                max_offset: 1.5,
+            }),
+            ..Default::default()
+        };
+        assert_eq!(state.desired_temperature_for(HEATER_ID), Some(20.0));
+
+        for (outside, desired) in [(15.0, 20.0), (10.0, 20.0), (0.0, 21.0), (-20.0, 21.5)] {
+            state.temperatures.insert(OUTSIDE.to_string(), outside);
+            let compensated = state.desired_temperature_for(HEATER_ID).unwrap();
+            assert!(
+                (compensated - desired).abs() < 1e-9,
+                "{compensated} != {desired} at {outside}°C outside"
+            );
+        }
+    }
+
+    #[test]
+    fn record_temperature_without_smoothing_uses_latest_reading() {
+        let mut state = State::default();
+
+        state.record_temperature(INSIDE, 20.0);
+        state.record_temperature(INSIDE, 21.5);
+
+        assert_eq!(state.temperatures.get(INSIDE), Some(&21.5));
+    }
+
+    #[test]
+    fn record_temperature_averages_sensors_of_a_place() {
+        let mut state = State::default();
+
+        state.record_temperature("outside/north", -2.0);
+        state.record_temperature("outside/south", -4.0);
+        state.record_temperature("outside/north", -1.0);
+
+        assert_eq!(state.temperatures.get("outside"), Some(&-2.5));
+        assert_eq!(
+            state
+                .sensor_temperatures
+                .get("outside/north")
+                .map(|(temperature, _)| *temperature),
+            Some(-1.0)
+        );
+    }
+
+    #[test]
+    fn record_temperature_drops_stale_sensors() {
+        let mut state = State::default();
+        let start = Instant::now();
+
+        state.record_temperature_at("inside/bedroom", 18.0, start);
+        state.record_temperature_at(
+            INSIDE,
+            20.0,
+            start + state.stale_after + Duration::from_secs(1),
+        );
+
+        assert_eq!(state.temperatures.get(INSIDE), Some(&20.0));
+        assert!(!state.sensor_temperatures.contains_key("inside/bedroom"));
+    }
+
+    #[test]
+    fn restored_inside_temperature_is_not_averaged_as_a_sensor() {
+        let mut state = State::default();
+
+        state.restore_inside_temperature(19.0, Duration::from_secs(60));
+        state.record_temperature("inside/bedroom", 21.0);
+
+        assert_eq!(state.temperatures.get(INSIDE), Some(&21.0));
+        assert!(!state.sensor_temperatures.contains_key(INSIDE));
+    }
+
+    #[test]
+    fn record_temperature_averages_inside_sensors_before_smoothing() {
+        let mut state = State::default();
+
+        state.record_temperature(INSIDE, 20.0);
+        state.record_temperature("inside/bedroom", 18.0);
+
+        assert_eq!(state.temperatures.get(INSIDE), Some(&19.0));
+    }
+
+    #[test]
+    fn record_temperature_averages_inside_readings() {
+        let mut state = State {
+            smoothing_window: 3,
+            ..Default::default()
+        };
+
+        for temperature in [18.0, 20.0, 19.0, 21.0] {
+            state.record_temperature(INSIDE, temperature);
+        }
+        state.record_temperature("outside", -3.0);
+
+        assert_eq!(state.temperatures.get(INSIDE), Some(&20.0));
+        assert_eq!(state.temperatures.get("outside"), Some(&-3.0));
+    }
+
+    #[sqlx::test]
+    fn smoothed_temperature_drives_decision_while_raw_is_stored(pool: SqlitePool) {
+        // Arrange
+        let (mut executor, _requests) = executor(pool.clone()).await;
+        executor.state.enabled = true;
+        executor.state.desired_temperature = Some(20.0);
+        executor.state.smoothing_window = 3;
+        executor.min_reading_interval = Duration::ZERO;
+
+        // Act
+        for temperature in [19.0, 19.0, 21.4] {
+            let measurement: Measurement = serde_json::from_str(&format!(
+                r#"{{"temperature":{temperature},"humidity":50.0}}"#
+            ))
+            .unwrap();
+            executor
+                .handle_action(&Action::RegisterMeasurement(
+                    INSIDE.to_string(),
+                    measurement,
+                ))
+                .await
+                .unwrap();
+        }
+
+        // Assert
+        assert_eq!(
+            executor.state.heater_states.get(HEATER_ID),
+            Some(&HeaterState::On)
+        );
+        executor.flush_readings().await.unwrap();
+        let stored: Vec<f64> = sqlx::query_scalar("SELECT temperature FROM history ORDER BY rowid")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(stored, vec![19.0, 19.0, 21.4]);
+    }
+
+    #[test]
+    fn update_staleness_after_threshold() {
+        let mut state = State::default();
+        assert!(!state.update_staleness(Instant::now()));
+
+        let now = Instant::now();
+        state.record_temperature(INSIDE, 20.0);
+
+        assert!(!state.update_staleness(now + DEFAULT_STALE_AFTER));
+        assert!(state.update_staleness(now + DEFAULT_STALE_AFTER + Duration::from_secs(1)));
+        assert!(!state.update_staleness(now + 2 * DEFAULT_STALE_AFTER));
+
+        state.record_temperature(INSIDE, 20.0);
+        assert!(!state.inside_stale);
+    }
+
+    #[sqlx::test]
+    fn stale_inside_sensor_turns_heaters_off(pool: SqlitePool) {
+        // Arrange
+        let (mut executor, requests) = executor(pool).await;
+        executor.dwell = RelayDwell::new(Duration::ZERO);
+        executor.state.enabled = true;
+        executor.state.desired_temperature = Some(22.0);
+        executor
+            .handle_action(&Action::SetInsideTemperature(18.0))
+            .await
+            .unwrap();
+        assert_eq!(
+            executor.state.heater_states.get(HEATER_ID),
+            Some(&HeaterState::On)
+        );
+        published(&requests);
+
+        // Act
+        let later = Instant::now() + DEFAULT_STALE_AFTER + Duration::from_secs(60);
+        executor.check_staleness(later).await.unwrap();
+
+        // Assert
+        assert_eq!(
+            executor.state.heater_states.get(HEATER_ID),
+            Some(&HeaterState::Off)
+        );
+        assert!(published(&requests).contains(&command(HEATER_ID, "off")));
+    }
+
+    #[test]
+    fn desired_temperature_for_applies_mode_offset() {
+        let mut state = State {
+            desired_temperature: Some(21.0),
+            heater_desired_temperatures: HashMap::from([("10DB9C".to_string(), 17.0)]),
+            ..Default::default()
+        };
+
+        state.mode = Mode::Away;
+        assert_eq!(state.desired_temperature_for(HEATER_ID), Some(17.0));
+        assert_eq!(state.desired_temperature_for("10DB9C"), Some(13.0));
+
+        state.mode = Mode::Eco;
+        assert_eq!(state.desired_temperature_for(HEATER_ID), Some(19.0));
+
+        state.mode = Mode::Home;
+        assert_eq!(state.desired_temperature_for(HEATER_ID), Some(21.0));
+    }
+
+    #[test]
+    fn get_heater_state_turns_off_in_away_mode() {
+        let mut state = state(19.0, Some(HeaterState::On));
+        state.desired_temperature = Some(21.0);
+        assert_eq!(state.get_heater_state(&heater(HEATER_ID)), HeaterState::On);
+
+        state.mode = Mode::Away;
+
+        assert_eq!(state.get_heater_state(&heater(HEATER_ID)), HeaterState::Off);
+    }
+
+    #[sqlx::test]
+    fn set_mode_publishes_retained_status(pool: SqlitePool) {
+        // Arrange
+        let (mut executor, requests) = executor(pool).await;
+
+        // Act
+        executor
+            .handle_action(&Action::SetMode(Mode::Eco))
+            .await
+            .unwrap();
+
+        // Assert
+        assert_eq!(executor.state.mode, Mode::Eco);
+        assert!(published(&requests).contains(&("hub/status/mode".to_string(), "eco".to_string())));
+    }
+
+    #[test]
+    fn desired_temperature_for_without_global() {
+        let state = State {
+            heater_desired_temperatures: HashMap::from([("10DB9C".to_string(), 17.0)]),
+            ..Default::default()
+        };
+
+        assert_eq!(state.desired_temperature_for("10DB9C"), Some(17.0));
+        assert_eq!(state.desired_temperature_for(HEATER_ID), None);
+    }
+
+    #[test]
+    fn get_heater_state_uses_heater_specific_target() {
+        let state = State {
+            desired_temperature: Some(20.0),
+            heater_desired_temperatures: HashMap::from([("10DB9C".to_string(), 17.0)]),
+            temperatures: HashMap::from([(INSIDE.to_string(), 18.5)]),
+            ..Default::default()
+        };
+
+        assert_eq!(state.get_heater_state(&heater("10DB9C")), HeaterState::Off);
+        assert_eq!(state.get_heater_state(&heater(HEATER_ID)), HeaterState::On);
+    }
+
+    #[test]
+    fn get_heater_state_ignores_readings_from_other_places() {
+        let state = State {
+            desired_temperature: Some(20.0),
+            temperatures: HashMap::from([("outside".to_string(), 3.0)]),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            state.get_heater_state(&heater(HEATER_ID)),
+            HeaterState::Unknown
+        );
+    }
+
+    #[test]
+    fn get_heater_state_uses_reading_from_heater_place() {
+        let state = State {
+            desired_temperature: Some(20.0),
+            temperatures: HashMap::from([("outside".to_string(), 3.0), (INSIDE.to_string(), 21.0)]),
+            ..Default::default()
+        };
+
+        assert_eq!(state.get_heater_state(&heater(HEATER_ID)), HeaterState::Off);
+    }
+
+    #[test]
+    fn get_heater_state_missing_temperatures() {
+        assert_eq!(
+            State::default().get_heater_state(&heater(HEATER_ID)),
+            HeaterState::Unknown
+        );
+    }
+
+    #[test]
+    fn get_heater_state_undecided_within_band() {
+        assert_eq!(
+            state(20.0, None).get_heater_state(&heater(HEATER_ID)),
+            HeaterState::Unknown
+        );
+    }
+
+    #[test]
+    fn get_heater_state_below_band() {
+        assert_eq!(
+            state(19.7, None).get_heater_state(&heater(HEATER_ID)),
+            HeaterState::On
+        );
+        assert_eq!(
+            state(19.7, Some(HeaterState::Off)).get_heater_state(&heater(HEATER_ID)),
+            HeaterState::On
+        );
+    }
+
+    #[test]
+    fn get_heater_state_above_band() {
+        assert_eq!(
+            state(20.3, None).get_heater_state(&heater(HEATER_ID)),
+            HeaterState::Off
+        );
+        assert_eq!(
+            state(20.3, Some(HeaterState::On)).get_heater_state(&heater(HEATER_ID)),
+            HeaterState::Off
+        );
+    }
+
+    #[test]
+    fn get_heater_state_keeps_previous_state_at_band_edges() {
+        for current in [19.75, 20.25] {
+            assert_eq!(
+                state(current, Some(HeaterState::On)).get_heater_state(&heater(HEATER_ID)),
+                HeaterState::On
+            );
+            assert_eq!(
+                state(current, Some(HeaterState::Off)).get_heater_state(&heater(HEATER_ID)),
+                HeaterState::Off
+            );
+        }
+    }
 }
@@ -0,0 +1,1082 @@
+//! Routing of incoming messages to the actions they request, independently of
+//! the MQTT eventloop.
+
+use std::{collections::HashMap, str::FromStr, sync::OnceLock, time::Duration};
+
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use regex::bytes::Regex;
+
+use super::Action;
+use crate::{
+    error::HubError,
+    models::{Calibration, HeaterState, Measurement, MeasurementBounds, Mode, SetpointSource},
+};
+
+/// Number of watt-minutes, the unit of the Shelly energy counters, in a kWh.
+const WATT_MINUTES_PER_KWH: f64 = 60_000.0;
+
+/// Increase in °C of the desired temperature of a boost without a `delta`.
+const DEFAULT_BOOST_DELTA: f64 = 2.0;
+
+/// Length in seconds of a boost without a `duration_secs`.
+const DEFAULT_BOOST_DURATION_SECS: u64 = 60 * 60;
+
+/// Largest change in °C of the desired temperature a boost can make.
+const MAX_BOOST_DELTA: f64 = 10.0;
+
+/// Longest a boost can last, in seconds.
+const MAX_BOOST_DURATION_SECS: u64 = 24 * 60 * 60;
+
+/// Longest a heater can be overridden for, in seconds.
+const MAX_OVERRIDE_DURATION_SECS: u64 = 24 * 60 * 60;
+
+/// Patterns of the topics whose ids are extracted, compiled the first time
+/// they are used and shared by all routers.
+static MEASUREMENT: OnceLock<Regex> = OnceLock::new();
+static HEATER_STATE: OnceLock<Regex> = OnceLock::new();
+static PLUS_HEATER_STATE: OnceLock<Regex> = OnceLock::new();
+static HEATER_POWER: OnceLock<Regex> = OnceLock::new();
+static HEATER_ENERGY: OnceLock<Regex> = OnceLock::new();
+
+/// Get the pattern compiled in `cell`, compiling it on first use.
+fn compiled(cell: &'static OnceLock<Regex>, pattern: &str) -> &'static Regex {
+    cell.get_or_init(|| Regex::new(pattern).expect("invalid regex"))
+}
+
+fn measurement_regex() -> &'static Regex {
+    compiled(
+        &MEASUREMENT,
+        r#"^measurement/(?<location>[A-Za-z0-9_-]{1,32}(?:/[A-Za-z0-9_-]{1,32})?)$"#,
+    )
+}
+
+fn heater_state_regex() -> &'static Regex {
+    compiled(
+        &HEATER_STATE,
+        r#"shellies/shelly1-(?<id>[A-F0-9]{6})/relay/0"#,
+    )
+}
+
+fn plus_heater_state_regex() -> &'static Regex {
+    compiled(
+        &PLUS_HEATER_STATE,
+        r#"^shellyplus1-(?<id>[A-F0-9]{6})/status/switch:0$"#,
+    )
+}
+
+fn heater_power_regex() -> &'static Regex {
+    compiled(
+        &HEATER_POWER,
+        r#"^shellies/shelly1-(?<id>[A-F0-9]{6})/relay/0/power$"#,
+    )
+}
+
+fn heater_energy_regex() -> &'static Regex {
+    compiled(
+        &HEATER_ENERGY,
+        r#"^shellies/shelly1-(?<id>[A-F0-9]{6})/relay/0/energy$"#,
+    )
+}
+
+/// Pattern of a place or sensor in a measurement topic.
+const LOCATION_LEVEL: &str = "[A-Za-z0-9_-]{1,32}";
+
+/// A template of measurement topics with named placeholders, like
+/// `home/{place}/climate`, compiled into the pattern the topics are matched
+/// against. `{place}` must be used once, and `{sensor}` at most once, each
+/// standing for a whole level of the topic. The fixed routes are tried before
+/// the template, so topics it shares with them, like `temperature/{place}`
+/// or `shellies/{place}/relay/0`, are routed as those instead.
+#[derive(Debug, Clone)]
+pub struct MeasurementTopic {
+    template: String,
+    regex: Regex,
+}
+
+impl MeasurementTopic {
+    pub fn new(template: &str) -> Result<Self> {
+        let invalid = |reason: &str| anyhow!("Invalid measurement topic '{template}': {reason}");
+        let mut pattern = String::from("^");
+        let (mut places, mut sensors) = (0, 0);
+        for (i, level) in template.split('/').enumerate() {
+            if i > 0 {
+                pattern.push('/');
+            }
+            match level {
+                "{place}" => {
+                    places += 1;
+                    pattern.push_str(&format!("(?<place>{LOCATION_LEVEL})"));
+                }
+                "{sensor}" => {
+                    sensors += 1;
+                    pattern.push_str(&format!("(?<sensor>{LOCATION_LEVEL})"));
+                }
+                "" => return Err(invalid("levels must not be empty")),
+                _ if level.contains(['{', '}']) => {
+                    return Err(invalid(
+                        "only {place} and {sensor} are supported, as whole levels",
+                    ))
+                }
+                _ if level.contains(['+', '#']) => {
+                    return Err(invalid("wildcards are not allowed"))
+                }
+                _ => pattern.push_str(&regex::escape(level)),
+            }
+        }
+        if places != 1 {
+            return Err(invalid("{place} must be used exactly once"));
+        }
+        if sensors > 1 {
+            return Err(invalid("{sensor} must be used at most once"));
+        }
+        pattern.push('$');
+
+        Ok(Self {
+            template: template.to_string(),
+            regex: Regex::new(&pattern)?,
+        })
+    }
+
+    /// The topic filter subscribing to the topics of the template.
+    pub fn filter(&self) -> String {
+        self.template
+            .split('/')
+            .map(|level| match level {
+                "{place}" | "{sensor}" => "+",
+                level => level,
+            })
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    /// The location of a measurement published to `topic`, which is
+    /// `<place>` or `<place>/<sensor>`, or `None` if the topic does not match
+    /// the template.
+    fn location(&self, topic: &[u8]) -> Option<String> {
+        let captures = self.regex.captures(topic)?;
+        let level = |name| {
+            captures
+                .name(name)
+                .and_then(|m| std::str::from_utf8(m.as_bytes()).ok())
+        };
+        let place = level("place")?;
+
+        Some(match level("sensor") {
+            Some(sensor) => format!("{place}/{sensor}"),
+            None => place.to_string(),
+        })
+    }
+}
+
+/// The kinds of topics the hub acts on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Route {
+    Setpoint,
+    FahrenheitSetpoint,
+    HeaterSetpoint,
+    InsideTemperature,
+    FahrenheitInsideTemperature,
+    Auto,
+    Mode,
+    Boost,
+    Reevaluate,
+    ConfigRequest,
+    Measurement,
+    Override,
+    HeaterPower,
+    HeaterEnergy,
+    HeaterState,
+    PlusHeaterState,
+}
+
+impl Route {
+    /// The kind of a topic, or `None` if the hub does not act on it.
+    fn from_topic(topic: &[u8]) -> Option<Self> {
+        let route = match topic {
+            b"temperature/set" => Self::Setpoint,
+            b"temperature/set/fahrenheit" => Self::FahrenheitSetpoint,
+            _ if topic.starts_with(b"temperature/set/") => Self::HeaterSetpoint,
+            b"temperature/inside" => Self::InsideTemperature,
+            b"temperature/inside/fahrenheit" => Self::FahrenheitInsideTemperature,
+            b"temperature/auto" => Self::Auto,
+            b"temperature/mode" => Self::Mode,
+            b"temperature/boost" => Self::Boost,
+            b"hub/reevaluate" => Self::Reevaluate,
+            b"hub/config/get" => Self::ConfigRequest,
+            _ if topic.starts_with(b"measurement/") => Self::Measurement,
+            _ if topic.starts_with(b"heater/") && topic.ends_with(b"/override") => Self::Override,
+            _ if topic.starts_with(b"shellies/") && topic.ends_with(b"/relay/0/power") => {
+                Self::HeaterPower
+            }
+            _ if topic.starts_with(b"shellies/") && topic.ends_with(b"/relay/0/energy") => {
+                Self::HeaterEnergy
+            }
+            _ if topic.starts_with(b"shellies/") => Self::HeaterState,
+            _ if topic.starts_with(b"shellyplus1-") && topic.ends_with(b"/status/switch:0") => {
+                Self::PlusHeaterState
+            }
+            _ => return None,
+        };
+        Some(route)
+    }
+}
+
+/// Maps the topic and payload of a message to the action it requests.
+#[derive(Debug)]
+pub struct Router {
+    /// Readings outside of these bounds are rejected.
+    measurement_bounds: MeasurementBounds,
+    /// Offsets applied to the measurements, keyed by location.
+    calibration: HashMap<String, Calibration>,
+    /// Topic of measurements in addition to `measurement/<place>`.
+    measurement_topic: Option<MeasurementTopic>,
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        Self::new(MeasurementBounds::default(), HashMap::new())
+    }
+}
+
+impl Router {
+    pub fn new(
+        measurement_bounds: MeasurementBounds,
+        calibration: HashMap<String, Calibration>,
+    ) -> Self {
+        Self {
+            measurement_bounds,
+            calibration,
+            measurement_topic: None,
+        }
+    }
+
+    /// Also accept measurements published to the topics of `topic`.
+    pub fn with_measurement_topic(self, topic: Option<MeasurementTopic>) -> Self {
+        Self {
+            measurement_topic: topic,
+            ..self
+        }
+    }
+
+    /// The action requested by a message, or `None` if the hub does not act
+    /// on it. Measurements that cannot be parsed are turned into dead
+    /// letters, while other invalid messages are errors.
+    pub fn route(&self, topic: &[u8], payload: Bytes) -> Result<Option<Action>, HubError> {
+        let Some(route) = Route::from_topic(topic).or_else(|| {
+            self.measurement_topic
+                .as_ref()
+                .and_then(|template| template.location(topic))
+                .map(|_| Route::Measurement)
+        }) else {
+            return Ok(None);
+        };
+
+        let action = match route {
+            Route::Setpoint => {
+                Action::SetDesiredTemperature(parse_float_payload(&payload)?, SetpointSource::Mqtt)
+            }
+            Route::FahrenheitSetpoint => Action::SetDesiredTemperature(
+                fahrenheit_to_celsius(parse_float_payload(&payload)?),
+                SetpointSource::Mqtt,
+            ),
+            Route::HeaterSetpoint => Action::SetHeaterDesiredTemperature(
+                parse_setpoint_heater_id(topic)?,
+                parse_float_payload(&payload)?,
+            ),
+            Route::InsideTemperature => {
+                let temperature = parse_float_payload(&payload)?;
+                self.measurement_bounds.validate_temperature(temperature)?;
+                Action::SetInsideTemperature(temperature)
+            }
+            Route::FahrenheitInsideTemperature => {
+                let temperature = fahrenheit_to_celsius(parse_float_payload(&payload)?);
+                self.measurement_bounds.validate_temperature(temperature)?;
+                Action::SetInsideTemperature(temperature)
+            }
+            Route::Auto => match payload.as_ref() {
+                b"true" => Action::EnableController(true),
+                b"false" => Action::EnableController(false),
+                _ => return Ok(None),
+            },
+            Route::Mode => {
+                let mode = std::str::from_utf8(&payload)
+                    .map_err(|_| HubError::parse("payload is not utf8"))
+                    .and_then(|s| {
+                        Mode::from_str(s.trim())
+                            .map_err(|_| HubError::parse("payload is not a valid mode"))
+                    })?;
+                Action::SetMode(mode)
+            }
+            Route::Boost => parse_boost(&payload)?,
+            Route::Reevaluate => Action::Reevaluate,
+            Route::ConfigRequest => Action::PublishConfig,
+            Route::Measurement => match self.parse_measurement(topic, &payload) {
+                Ok((place, measurement)) => Action::RegisterMeasurement(place, measurement),
+                Err(e) => {
+                    tracing::error!(error = %e, "Failed to parse measurement");
+                    Action::RegisterDeadLetter(
+                        String::from_utf8_lossy(topic).to_string(),
+                        payload,
+                        e.to_string(),
+                    )
+                }
+            },
+            Route::Override => parse_override(topic, &payload)?,
+            Route::HeaterPower => {
+                let (heater_id, power) = self.parse_heater_power_message(topic, &payload)?;
+                Action::RegisterHeaterPower(heater_id, power)
+            }
+            Route::HeaterEnergy => {
+                let (heater_id, energy) = self.parse_heater_energy_message(topic, &payload)?;
+                Action::RegisterHeaterEnergy(heater_id, energy)
+            }
+            Route::HeaterState => {
+                let (heater_id, state) = self.parse_heater_state_change_message(topic, &payload)?;
+                Action::RegisterHeaterStateChange(heater_id, state)
+            }
+            Route::PlusHeaterState => {
+                let (heater_id, state) = parse_plus_heater_state(topic, &payload)?;
+                Action::RegisterHeaterStateChange(heater_id, state)
+            }
+        };
+
+        Ok(Some(action))
+    }
+
+    /// Handle receiving a measurement reading. The topic is either
+    /// `measurement/<place>` or `measurement/<place>/<sensor>` for places with
+    /// multiple sensors, and the returned location is the part after
+    /// `measurement/`, or one of the topics of the configured template. Any
+    /// place and sensor made of up to 32 letters, digits, `_` and `-` is
+    /// accepted, so new places are stored without changes.
+    /// Besides a JSON object, the payload may be a bare number for sensors
+    /// only reporting the temperature. The bounds apply to the raw reading,
+    /// which is then corrected by the calibration of the location, if any.
+    #[tracing::instrument(skip(self, topic, payload))]
+    fn parse_measurement(
+        &self,
+        topic: &[u8],
+        payload: &[u8],
+    ) -> Result<(String, Measurement), HubError> {
+        let place = measurement_regex()
+            .captures(topic)
+            .and_then(|m| m.name("location"))
+            .and_then(|x| std::str::from_utf8(x.as_bytes()).ok())
+            .map(str::to_string)
+            .or_else(|| {
+                self.measurement_topic
+                    .as_ref()
+                    .and_then(|template| template.location(topic))
+            })
+            .ok_or_else(|| {
+                HubError::parse(format!(
+                    "Received measurement from invalid place: '{:?}'",
+                    topic
+                ))
+            })?;
+
+        let measurement = match serde_json::from_slice::<Measurement>(payload) {
+            Ok(measurement) => measurement,
+            Err(e) => std::str::from_utf8(payload)
+                .ok()
+                .and_then(|s| s.trim().parse::<f64>().ok())
+                .map(Measurement::from_temperature)
+                .ok_or_else(|| {
+                    HubError::parse(format!("Failed to deserialize payload: {payload:?}. {e:?}"))
+                })?,
+        };
+        measurement
+            .validate(&self.measurement_bounds)
+            .map_err(|e| HubError::validation(format!("Rejected measurement from {place}: {e}")))?;
+        let measurement = match self.calibration.get(&place) {
+            Some(calibration) => measurement.calibrated(calibration),
+            None => measurement,
+        };
+
+        Ok((place, measurement))
+    }
+
+    /// Handle messages published about state changes to heaters.
+    #[tracing::instrument(skip(self, topic, payload))]
+    fn parse_heater_state_change_message(
+        &self,
+        topic: &[u8],
+        payload: &[u8],
+    ) -> Result<(String, HeaterState), HubError> {
+        let heater_id = heater_state_regex()
+            .captures(topic)
+            .and_then(|m| m.name("id"))
+            .and_then(|x| std::str::from_utf8(x.as_bytes()).ok())
+            .ok_or_else(|| {
+                HubError::parse(format!("Received state from unknown heater: '{:?}'", topic))
+            })?;
+        let state = std::str::from_utf8(payload)
+            .map_err(|_| HubError::parse("payload is not utf8"))
+            .and_then(|s| {
+                HeaterState::from_str(s)
+                    .map_err(|_| HubError::parse("payload is not a valid state"))
+            })?;
+        if state == HeaterState::Unknown {
+            return Err(HubError::validation("Relays only report being on or off"));
+        }
+
+        Ok((heater_id.to_string(), state))
+    }
+
+    /// Handle messages published about the power drawn by heaters.
+    #[tracing::instrument(skip(self, topic, payload))]
+    fn parse_heater_power_message(
+        &self,
+        topic: &[u8],
+        payload: &[u8],
+    ) -> Result<(String, f64), HubError> {
+        let heater_id = heater_power_regex()
+            .captures(topic)
+            .and_then(|m| m.name("id"))
+            .and_then(|x| std::str::from_utf8(x.as_bytes()).ok())
+            .ok_or_else(|| {
+                HubError::parse(format!("Received power from unknown heater: '{:?}'", topic))
+            })?;
+        let power = parse_number(payload, "payload is not a valid power")?;
+
+        Ok((heater_id.to_string(), power))
+    }
+
+    /// Handle messages published with the energy counters of heaters, which
+    /// count in watt-minutes. Returns the counter in kWh.
+    #[tracing::instrument(skip(self, topic, payload))]
+    fn parse_heater_energy_message(
+        &self,
+        topic: &[u8],
+        payload: &[u8],
+    ) -> Result<(String, f64), HubError> {
+        let heater_id = heater_energy_regex()
+            .captures(topic)
+            .and_then(|m| m.name("id"))
+            .and_then(|x| std::str::from_utf8(x.as_bytes()).ok())
+            .ok_or_else(|| {
+                HubError::parse(format!(
+                    "Received energy from unknown heater: '{:?}'",
+                    topic
+                ))
+            })?;
+        let watt_minutes = parse_number(payload, "payload is not a valid energy")?;
+
+        Ok((heater_id.to_string(), watt_minutes / WATT_MINUTES_PER_KWH))
+    }
+}
+
+/// Parse the heater id from a `temperature/set/<heater_id>` topic.
+fn parse_setpoint_heater_id(topic: &[u8]) -> Result<String, HubError> {
+    topic
+        .strip_prefix(b"temperature/set/")
+        .and_then(|id| std::str::from_utf8(id).ok())
+        .filter(|id| !id.is_empty())
+        .map(str::to_string)
+        .ok_or_else(|| {
+            HubError::parse(format!(
+                "Received setpoint for unknown heater: '{:?}'",
+                topic
+            ))
+        })
+}
+
+/// Payload of a `heater/<heater_id>/override` message.
+#[derive(Debug, serde::Deserialize)]
+struct OverridePayload {
+    state: HeaterState,
+    duration_secs: u64,
+}
+
+/// Payload of a `temperature/boost` message, where missing fields take their
+/// defaults.
+#[derive(Debug, serde::Deserialize)]
+#[serde(default)]
+struct BoostPayload {
+    delta: f64,
+    duration_secs: u64,
+}
+
+impl Default for BoostPayload {
+    fn default() -> Self {
+        Self {
+            delta: DEFAULT_BOOST_DELTA,
+            duration_secs: DEFAULT_BOOST_DURATION_SECS,
+        }
+    }
+}
+
+/// Parse a boost from a payload like `{"delta":2.0,"duration_secs":3600}`.
+/// An empty payload boosts with the defaults.
+fn parse_boost(payload: &[u8]) -> Result<Action, HubError> {
+    let payload: BoostPayload = if payload.iter().all(u8::is_ascii_whitespace) {
+        BoostPayload::default()
+    } else {
+        serde_json::from_slice(payload)
+            .map_err(|e| HubError::parse(format!("payload is not a valid boost: {e}")))?
+    };
+    if !payload.delta.is_finite() || payload.delta.abs() > MAX_BOOST_DELTA {
+        return Err(HubError::validation(format!(
+            "A boost needs a delta of at most {MAX_BOOST_DELTA}°C"
+        )));
+    }
+    if !(1..=MAX_BOOST_DURATION_SECS).contains(&payload.duration_secs) {
+        return Err(HubError::validation(format!(
+            "A boost needs a duration between 1 and {MAX_BOOST_DURATION_SECS} seconds"
+        )));
+    }
+
+    Ok(Action::Boost {
+        delta: payload.delta,
+        duration: Duration::from_secs(payload.duration_secs),
+    })
+}
+
+/// Parse an override of a heater from a `heater/<heater_id>/override` topic
+/// with a payload like `{"state":"on","duration_secs":1800}`.
+fn parse_override(topic: &[u8], payload: &[u8]) -> Result<Action, HubError> {
+    let id = topic
+        .strip_prefix(b"heater/")
+        .and_then(|topic| topic.strip_suffix(b"/override"))
+        .and_then(|id| std::str::from_utf8(id).ok())
+        .filter(|id| !id.is_empty())
+        .ok_or_else(|| {
+            HubError::parse(format!(
+                "Received override for unknown heater: '{:?}'",
+                topic
+            ))
+        })?;
+    let payload: OverridePayload = serde_json::from_slice(payload)
+        .map_err(|e| HubError::parse(format!("payload is not a valid override: {e}")))?;
+    if payload.state == HeaterState::Unknown {
+        return Err(HubError::validation(
+            "A heater can only be overridden to on or off",
+        ));
+    }
+    if payload.duration_secs > MAX_OVERRIDE_DURATION_SECS {
+        return Err(HubError::validation(format!(
+            "A heater can be overridden for at most {MAX_OVERRIDE_DURATION_SECS} seconds"
+        )));
+    }
+
+    Ok(Action::OverrideHeater {
+        id: id.to_string(),
+        state: payload.state,
+        duration: Duration::from_secs(payload.duration_secs),
+    })
+}
+
+/// The status of the switch of a Shelly Plus relay, of which only whether
+/// it is on is used.
+#[derive(Debug, serde::Deserialize)]
+struct PlusSwitchStatus {
+    output: bool,
+}
+
+/// Parse the state of a Shelly Plus relay from a
+/// `shellyplus1-<id>/status/switch:0` topic with a payload like
+/// `{"id":0,"output":true}`.
+fn parse_plus_heater_state(
+    topic: &[u8],
+    payload: &[u8],
+) -> Result<(String, HeaterState), HubError> {
+    let heater_id = plus_heater_state_regex()
+        .captures(topic)
+        .and_then(|m| m.name("id"))
+        .and_then(|x| std::str::from_utf8(x.as_bytes()).ok())
+        .ok_or_else(|| {
+            HubError::parse(format!("Received state from unknown heater: '{:?}'", topic))
+        })?;
+    let status: PlusSwitchStatus = serde_json::from_slice(payload)
+        .map_err(|e| HubError::parse(format!("payload is not a valid switch status: {e}")))?;
+    let state = if status.output {
+        HeaterState::On
+    } else {
+        HeaterState::Off
+    };
+
+    Ok((heater_id.to_string(), state))
+}
+
+/// Parse `Bytes` which represents the string representation of a float.
+fn parse_float_payload(payload: &Bytes) -> Result<f64, HubError> {
+    payload
+        .escape_ascii()
+        .to_string()
+        .parse::<f64>()
+        .map_err(|_| HubError::parse("Failed to parse temperature to float"))
+}
+
+/// Parse a payload holding a number surrounded by whitespace, failing with
+/// `message` otherwise.
+fn parse_number(payload: &[u8], message: &str) -> Result<f64, HubError> {
+    std::str::from_utf8(payload)
+        .ok()
+        .and_then(|s| s.trim().parse::<f64>().ok())
+        .ok_or_else(|| HubError::parse(message))
+}
+
+/// Convert a temperature in °F to °C, which is used everywhere else.
+fn fahrenheit_to_celsius(fahrenheit: f64) -> f64 {
+    (fahrenheit - 32.0) * 5.0 / 9.0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn route(topic: &str, payload: &'static str) -> Result<Option<Action>, HubError> {
+        Router::default().route(topic.as_bytes(), Bytes::from_static(payload.as_bytes()))
+    }
+
+    #[test]
+    fn patterns_are_compiled_once_and_shared() {
+        let first = measurement_regex();
+        let router = Router::default();
+        router
+            .parse_measurement(
+                b"measurement/inside",
+                br#"{"temperature":21.0,"humidity":40.0}"#,
+            )
+            .unwrap();
+
+        assert!(std::ptr::eq(first, measurement_regex()));
+        assert!(std::ptr::eq(heater_state_regex(), heater_state_regex()));
+        assert!(std::ptr::eq(heater_power_regex(), heater_power_regex()));
+        assert!(std::ptr::eq(heater_energy_regex(), heater_energy_regex()));
+    }
+
+    #[test]
+    fn topics_map_to_their_route() {
+        for (topic, expected) in [
+            ("temperature/set", Some(Route::Setpoint)),
+            (
+                "temperature/set/fahrenheit",
+                Some(Route::FahrenheitSetpoint),
+            ),
+            ("temperature/set/C4402D", Some(Route::HeaterSetpoint)),
+            ("temperature/inside", Some(Route::InsideTemperature)),
+            (
+                "temperature/inside/fahrenheit",
+                Some(Route::FahrenheitInsideTemperature),
+            ),
+            ("temperature/auto", Some(Route::Auto)),
+            ("temperature/mode", Some(Route::Mode)),
+            ("temperature/boost", Some(Route::Boost)),
+            ("hub/reevaluate", Some(Route::Reevaluate)),
+            ("hub/config/get", Some(Route::ConfigRequest)),
+            ("measurement/garage", Some(Route::Measurement)),
+            ("heater/C4402D/override", Some(Route::Override)),
+            (
+                "shellies/shelly1-C4402D/relay/0/power",
+                Some(Route::HeaterPower),
+            ),
+            (
+                "shellies/shelly1-C4402D/relay/0/energy",
+                Some(Route::HeaterEnergy),
+            ),
+            ("shellies/shelly1-C4402D/relay/0", Some(Route::HeaterState)),
+            (
+                "shellyplus1-C4402D/status/switch:0",
+                Some(Route::PlusHeaterState),
+            ),
+            ("temperature/outside", None),
+            ("door/front/state", None),
+        ] {
+            assert_eq!(Route::from_topic(topic.as_bytes()), expected, "{topic}");
+        }
+    }
+
+    #[test]
+    fn route_messages_to_actions() {
+        assert!(matches!(
+            route("temperature/set", "21.5"),
+            Ok(Some(Action::SetDesiredTemperature(t, SetpointSource::Mqtt))) if t == 21.5
+        ));
+        assert!(matches!(
+            route("temperature/set/C4402D", "19"),
+            Ok(Some(Action::SetHeaterDesiredTemperature(id, t))) if id == "C4402D" && t == 19.0
+        ));
+        assert!(matches!(
+            route("temperature/inside", "20.5"),
+            Ok(Some(Action::SetInsideTemperature(t))) if t == 20.5
+        ));
+        assert!(matches!(
+            route("temperature/auto", "false"),
+            Ok(Some(Action::EnableController(false)))
+        ));
+        assert!(matches!(
+            route("temperature/mode", "away"),
+            Ok(Some(Action::SetMode(Mode::Away)))
+        ));
+        assert!(matches!(
+            route("measurement/outside", r#"{"temperature":4.0,"humidity":80.0}"#),
+            Ok(Some(Action::RegisterMeasurement(place, _))) if place == "outside"
+        ));
+        assert!(matches!(
+            route("shellies/shelly1-C4402D/relay/0", "off"),
+            Ok(Some(Action::RegisterHeaterStateChange(id, HeaterState::Off))) if id == "C4402D"
+        ));
+    }
+
+    #[test]
+    fn route_plus_switch_status_to_heater_state() {
+        assert!(matches!(
+            route(
+                "shellyplus1-C4402D/status/switch:0",
+                r#"{"id":0,"source":"MQTT","output":true,"apower":800.0}"#
+            ),
+            Ok(Some(Action::RegisterHeaterStateChange(id, HeaterState::On))) if id == "C4402D"
+        ));
+        assert!(matches!(
+            route(
+                "shellyplus1-C4402D/status/switch:0",
+                r#"{"id":0,"output":false}"#
+            ),
+            Ok(Some(Action::RegisterHeaterStateChange(_, HeaterState::Off)))
+        ));
+        assert!(matches!(
+            route("shellyplus1-C4402D/status/switch:0", "on"),
+            Err(HubError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn route_ignores_unknown_topics_and_auto_payloads() {
+        assert!(matches!(route("door/front/state", "open"), Ok(None)));
+        assert!(matches!(route("temperature/auto", "maybe"), Ok(None)));
+    }
+
+    #[test]
+    fn route_turns_invalid_measurement_into_dead_letter() {
+        assert!(matches!(
+            route("measurement/inside", "{not json"),
+            Ok(Some(Action::RegisterDeadLetter(topic, payload, _)))
+                if topic == "measurement/inside" && payload == "{not json"
+        ));
+        assert!(matches!(
+            route("temperature/set", "warm"),
+            Err(HubError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn parse_heater_state_change_rejects_unknown_state() {
+        let router = Router::default();
+        let topic = b"shellies/shelly1-C4402D/relay/0";
+
+        assert_eq!(
+            router
+                .parse_heater_state_change_message(topic, b"on")
+                .unwrap(),
+            ("C4402D".to_string(), HeaterState::On)
+        );
+        assert!(matches!(
+            router.parse_heater_state_change_message(topic, b"unknown"),
+            Err(HubError::Validation(_))
+        ));
+    }
+
+    #[test]
+    fn parse_heater_power_message_from_topic() {
+        let router = Router::default();
+
+        let (heater_id, power) = router
+            .parse_heater_power_message(b"shellies/shelly1-C4402D/relay/0/power", b"1500.25")
+            .unwrap();
+
+        assert_eq!(heater_id, "C4402D");
+        assert_eq!(power, 1500.25);
+    }
+
+    #[test]
+    fn parse_heater_power_message_rejects_other_topics() {
+        let router = Router::default();
+
+        for topic in [
+            &b"shellies/shelly1-C4402D/relay/0"[..],
+            b"shellies/shelly1-C4402D/relay/0/energy",
+            b"shellies/shelly1-c4402d/relay/0/power",
+        ] {
+            assert!(router.parse_heater_power_message(topic, b"12.0").is_err());
+        }
+    }
+
+    #[test]
+    fn parse_heater_energy_message_converts_to_kwh() {
+        let router = Router::default();
+
+        let (heater_id, energy) = router
+            .parse_heater_energy_message(b"shellies/shelly1-C4402D/relay/0/energy", b"90000")
+            .unwrap();
+
+        assert_eq!(heater_id, "C4402D");
+        assert_eq!(energy, 1.5);
+    }
+
+    #[test]
+    fn fahrenheit_converts_to_celsius() {
+        assert_eq!(fahrenheit_to_celsius(32.0), 0.0);
+        assert_eq!(fahrenheit_to_celsius(212.0), 100.0);
+        assert_eq!(fahrenheit_to_celsius(-40.0), -40.0);
+    }
+
+    #[test]
+    fn parse_override_from_topic_and_payload() {
+        let action = parse_override(
+            b"heater/C4402D/override",
+            br#"{"state":"on","duration_secs":1800}"#,
+        )
+        .unwrap();
+
+        assert!(matches!(
+            action,
+            Action::OverrideHeater { id, state: HeaterState::On, duration }
+                if id == "C4402D" && duration == Duration::from_secs(1800)
+        ));
+        assert!(
+            parse_override(b"heater//override", br#"{"state":"on","duration_secs":1}"#).is_err()
+        );
+        assert!(matches!(
+            parse_override(
+                b"heater/C4402D/override",
+                br#"{"state":"unknown","duration_secs":1}"#
+            ),
+            Err(HubError::Validation(_))
+        ));
+    }
+
+    #[test]
+    fn parse_override_rejects_oversized_duration() {
+        assert!(matches!(
+            parse_override(
+                b"heater/C4402D/override",
+                br#"{"state":"on","duration_secs":18446744073709551615}"#
+            ),
+            Err(HubError::Validation(_))
+        ));
+        assert!(parse_override(
+            b"heater/C4402D/override",
+            format!(r#"{{"state":"on","duration_secs":{MAX_OVERRIDE_DURATION_SECS}}}"#).as_bytes()
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn parse_boost_with_payload_and_defaults() {
+        for (payload, expected_delta, expected_duration) in [
+            (&br#"{"delta":1.5,"duration_secs":1800}"#[..], 1.5, 1800),
+            (br#"{"delta":3.0}"#, 3.0, 3600),
+            (b"", 2.0, 3600),
+        ] {
+            let action = parse_boost(payload).unwrap();
+
+            assert!(matches!(
+                action,
+                Action::Boost { delta, duration }
+                    if delta == expected_delta && duration == Duration::from_secs(expected_duration)
+            ));
+        }
+        for payload in [
+            &br#"{"duration_secs":0}"#[..],
+            br#"{"duration_secs":18446744073709551615}"#,
+            br#"{"delta":500}"#,
+            br#"{"delta":-10.5}"#,
+        ] {
+            assert!(matches!(parse_boost(payload), Err(HubError::Validation(_))));
+        }
+        assert!(matches!(parse_boost(b"warmer"), Err(HubError::Parse(_))));
+    }
+
+    #[test]
+    fn parse_measurement_from_place_and_sensor_topics() {
+        let router = Router::default();
+        let payload = br#"{"temperature":-2.0,"humidity":80.0}"#;
+
+        for (topic, location) in [
+            ("measurement/outside", "outside"),
+            ("measurement/outside/north", "outside/north"),
+            ("measurement/inside/living-room", "inside/living-room"),
+            ("measurement/garage", "garage"),
+            ("measurement/garage/door_2", "garage/door_2"),
+        ] {
+            let (parsed, _) = router.parse_measurement(topic.as_bytes(), payload).unwrap();
+            assert_eq!(parsed, location);
+        }
+        for topic in [
+            "measurement/",
+            "measurement/outside/north/roof",
+            "measurement/gar age",
+            "measurement/garage.1",
+            "measurement/a-place-name-that-is-far-too-long-to-be-real",
+        ] {
+            assert!(router.parse_measurement(topic.as_bytes(), payload).is_err());
+        }
+    }
+
+    #[test]
+    fn parse_measurement_from_custom_topic_templates() {
+        let payload = br#"{"temperature":-2.0,"humidity":80.0}"#;
+
+        for (template, topic, location) in [
+            ("home/{place}/climate", "home/kitchen/climate", "kitchen"),
+            ("{place}/climate", "annex/climate", "annex"),
+            (
+                "sensors/{place}/{sensor}/state",
+                "sensors/garage/door_2/state",
+                "garage/door_2",
+            ),
+            (
+                "tele/{sensor}/{place}",
+                "tele/north/outside",
+                "outside/north",
+            ),
+        ] {
+            let router = Router::default()
+                .with_measurement_topic(Some(MeasurementTopic::new(template).unwrap()));
+
+            let (parsed, _) = router.parse_measurement(topic.as_bytes(), payload).unwrap();
+            let (default, _) = router
+                .parse_measurement(b"measurement/inside", payload)
+                .unwrap();
+
+            assert_eq!(parsed, location, "{template}");
+            assert_eq!(default, "inside");
+        }
+    }
+
+    #[test]
+    fn custom_topic_template_only_matches_its_topics() {
+        let router = Router::default()
+            .with_measurement_topic(Some(MeasurementTopic::new("home/{place}/climate").unwrap()));
+        let payload = br#"{"temperature":-2.0,"humidity":80.0}"#;
+
+        for topic in [
+            "home/kitchen",
+            "home/kitchen/climate/extra",
+            "away/kitchen/climate",
+            "home/kit chen/climate",
+        ] {
+            assert!(router.parse_measurement(topic.as_bytes(), payload).is_err());
+            assert!(matches!(
+                router.route(topic.as_bytes(), Bytes::from_static(payload)),
+                Ok(None)
+            ));
+        }
+        assert!(matches!(
+            router.route(b"home/kitchen/climate", Bytes::from_static(payload)),
+            Ok(Some(Action::RegisterMeasurement(location, _))) if location == "kitchen"
+        ));
+    }
+
+    #[test]
+    fn fixed_routes_take_precedence_over_custom_topic_template() {
+        let router = Router::default()
+            .with_measurement_topic(Some(MeasurementTopic::new("temperature/{place}").unwrap()));
+
+        assert!(matches!(
+            router.route(b"temperature/inside", Bytes::from_static(b"21.5")),
+            Ok(Some(Action::SetInsideTemperature(_)))
+        ));
+        assert!(matches!(
+            router.route(b"temperature/garage", Bytes::from_static(b"21.5")),
+            Ok(Some(Action::RegisterMeasurement(location, _))) if location == "garage"
+        ));
+    }
+
+    #[test]
+    fn custom_topic_template_requires_place_capture() {
+        for template in [
+            "home/climate",
+            "home/{sensor}/climate",
+            "home/{place}/{place}",
+            "home/{place}/{sensor}/{sensor}",
+            "home/room-{place}",
+            "home/{room}/climate",
+            "home/{place}/+",
+            "home/{place}/#",
+            "home//{place}",
+        ] {
+            assert!(MeasurementTopic::new(template).is_err(), "{template}");
+        }
+    }
+
+    #[test]
+    fn custom_topic_template_filter_has_wildcards_for_placeholders() {
+        let topic = MeasurementTopic::new("sensors/{place}/{sensor}/state").unwrap();
+
+        assert_eq!(topic.filter(), "sensors/+/+/state");
+    }
+
+    #[test]
+    fn parse_measurement_from_json_object() {
+        let (place, measurement) = Router::default()
+            .parse_measurement(
+                b"measurement/outside",
+                br#"{"temperature":-2.5,"humidity":80.0}"#,
+            )
+            .unwrap();
+
+        assert_eq!(place, "outside");
+        assert_eq!(*measurement.temperature(), -2.5);
+        assert_eq!(*measurement.humidity(), Some(80.0));
+    }
+
+    #[test]
+    fn parse_measurement_from_bare_number() {
+        let router = Router::default();
+
+        let (place, measurement) = router
+            .parse_measurement(b"measurement/garage", b" 21.4\n")
+            .unwrap();
+
+        assert_eq!(place, "garage");
+        assert_eq!(*measurement.temperature(), 21.4);
+        assert_eq!(*measurement.humidity(), None);
+        assert!(matches!(
+            router.parse_measurement(b"measurement/garage", b"-400"),
+            Err(HubError::Validation(_))
+        ));
+        assert!(matches!(
+            router.parse_measurement(b"measurement/garage", b"warm"),
+            Err(HubError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn measurement_is_calibrated_for_configured_location() {
+        let router = Router::new(
+            MeasurementBounds::default(),
+            HashMap::from([(
+                "inside".to_string(),
+                Calibration {
+                    temperature: -0.8,
+                    humidity: 2.0,
+                },
+            )]),
+        );
+        let payload = br#"{"temperature":21.8,"humidity":50.0}"#;
+
+        let (_, inside) = router
+            .parse_measurement(b"measurement/inside", payload)
+            .unwrap();
+        let (_, outside) = router
+            .parse_measurement(b"measurement/outside", payload)
+            .unwrap();
+
+        assert!((inside.temperature() - 21.0).abs() < 1e-9);
+        assert_eq!(*inside.humidity(), Some(52.0));
+        assert_eq!(*outside.temperature(), 21.8);
+        assert_eq!(*outside.humidity(), Some(50.0));
+    }
+
+    #[test]
+    fn parse_setpoint_heater_id_from_topic() {
+        assert_eq!(
+            parse_setpoint_heater_id(b"temperature/set/10DB9C").unwrap(),
+            "10DB9C"
+        );
+        assert!(parse_setpoint_heater_id(b"temperature/set/").is_err());
+    }
+}
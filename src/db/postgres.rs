@@ -0,0 +1,583 @@
+//! Storage of the hub's data in a Postgres database. The queries are checked
+//! at runtime, as the compile time checked queries can only be prepared
+//! against a single backend.
+
+use std::{str::FromStr, time::Duration};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{NaiveDateTime, NaiveTime, Utc};
+use futures_util::stream::BoxStream;
+use sqlx::{postgres::PgPoolOptions, PgPool};
+
+use super::{
+    energy_increment, heater_cycles, heater_runtime, retry_write, DbConfig, HeaterHistoryRecord,
+    HourlyAggregate, HumidityRecord, MigrationError, NewReading, SetpointChange, Storage,
+    TemperatureMeasurementRecord, MAX_DEAD_LETTERS, PRUNE_CHUNK_SIZE,
+};
+use crate::{
+    error::HubError,
+    models::{Firmware, Heater, HeaterState, SetpointSource},
+    schedule::{Schedule, ScheduleEntry},
+};
+
+/// Create a connection pool to the configured Postgres database.
+pub async fn create_pool(config: &DbConfig) -> Result<PgPool> {
+    PgPoolOptions::new()
+        .max_connections(config.max_connections)
+        .acquire_timeout(Duration::from_secs(config.acquire_timeout_secs))
+        .connect(&config.url)
+        .await
+        .context("Failed to connect to database")
+}
+
+/// Storage in a Postgres database.
+#[derive(Debug)]
+pub struct PostgresStorage {
+    db_pool: PgPool,
+}
+
+impl PostgresStorage {
+    /// Create the storage, applying the migrations to the database.
+    pub async fn new(db_pool: PgPool) -> Result<Self> {
+        tracing::trace!("Applying migrations");
+        sqlx::migrate!("./migrations/postgres")
+            .run(&db_pool)
+            .await
+            .map_err(MigrationError::from)?;
+        tracing::trace!("Migrations completed");
+
+        Ok(Self { db_pool })
+    }
+
+    /// Create the storage for a database whose schema is managed externally.
+    pub fn without_migrations(db_pool: PgPool) -> Self {
+        Self { db_pool }
+    }
+}
+
+#[async_trait]
+impl Storage for PostgresStorage {
+    #[tracing::instrument(skip(self))]
+    async fn ping(&self) -> Result<(), HubError> {
+        sqlx::query("SELECT 1").execute(&self.db_pool).await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_heaters(&self) -> Result<Vec<Heater>, HubError> {
+        sqlx::query_as::<_, (String, String, String, String, Option<f64>)>(
+            "SELECT id, name, place, firmware, watts FROM heaters ORDER BY position",
+        )
+        .fetch_all(&self.db_pool)
+        .await?
+        .into_iter()
+        .map(|(id, name, place, firmware, watts)| {
+            let firmware = Firmware::from_str(&firmware)
+                .map_err(|_| HubError::parse(format!("Unknown heater firmware '{firmware}'")))?;
+            Ok(Heater::new(id, name, place)
+                .with_firmware(firmware)
+                .with_watts(watts))
+        })
+        .collect()
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn upsert_heater(&self, heater: &Heater) -> Result<(), HubError> {
+        retry_write(|| async {
+            sqlx::query(
+                "INSERT INTO heaters (id, name, place, firmware, watts) VALUES ($1, $2, $3, $4, $5) ON CONFLICT (id) DO UPDATE SET name = excluded.name, place = excluded.place, firmware = excluded.firmware, watts = excluded.watts",
+            )
+            .bind(heater.id())
+            .bind(heater.name())
+            .bind(heater.place())
+            .bind(heater.firmware().as_ref())
+            .bind(heater.watts())
+            .execute(&self.db_pool)
+            .await?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_schedule(&self) -> Result<Schedule, HubError> {
+        let entries = sqlx::query_as::<_, (NaiveTime, f64)>(
+            "SELECT time_of_day, desired_temperature FROM schedule",
+        )
+        .fetch_all(&self.db_pool)
+        .await?
+        .into_iter()
+        .map(|(time_of_day, desired_temperature)| {
+            ScheduleEntry::new(time_of_day, desired_temperature)
+        })
+        .collect();
+
+        Ok(Schedule::new(entries))
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_history_since(
+        &self,
+        duration: Duration,
+    ) -> Result<Vec<TemperatureMeasurementRecord>, HubError> {
+        let since = Utc::now().naive_utc()
+            - chrono::Duration::from_std(duration)
+                .map_err(|_| HubError::validation("History duration is too long"))?;
+
+        sqlx::query_as(
+            "SELECT timestamp, location, temperature, humidity, battery FROM history WHERE timestamp > $1 ORDER BY timestamp",
+        )
+        .bind(since)
+        .fetch_all(&self.db_pool)
+        .await
+        .map_err(HubError::from)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_humidity_history_since(
+        &self,
+        duration: Duration,
+    ) -> Result<Vec<HumidityRecord>, HubError> {
+        let since = Utc::now().naive_utc()
+            - chrono::Duration::from_std(duration)
+                .map_err(|_| HubError::validation("History duration is too long"))?;
+
+        sqlx::query_as(
+            "SELECT timestamp, location, humidity FROM history WHERE timestamp > $1 AND humidity IS NOT NULL ORDER BY timestamp",
+        )
+        .bind(since)
+        .fetch_all(&self.db_pool)
+        .await
+        .map_err(HubError::from)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_hourly_averages(
+        &self,
+        location: &str,
+        since: NaiveDateTime,
+    ) -> Result<Vec<HourlyAggregate>, HubError> {
+        let rows = sqlx::query_as::<_, (NaiveDateTime, f64, Option<f64>, i64)>(
+            "SELECT date_trunc('hour', timestamp), AVG(temperature), AVG(humidity), COUNT(*) FROM history WHERE location = $1 AND timestamp >= $2 GROUP BY 1 ORDER BY 1",
+        )
+        .bind(location)
+        .bind(since)
+        .fetch_all(&self.db_pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(
+                |(hour, average_temperature, average_humidity, readings)| HourlyAggregate {
+                    hour,
+                    average_temperature,
+                    average_humidity,
+                    readings: readings as u64,
+                },
+            )
+            .collect())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_latest_reading(
+        &self,
+        location: &str,
+    ) -> Result<Option<TemperatureMeasurementRecord>, HubError> {
+        sqlx::query_as(
+            "SELECT timestamp, location, temperature, humidity, battery FROM history WHERE location = $1 ORDER BY timestamp DESC LIMIT 1",
+        )
+        .bind(location)
+        .fetch_optional(&self.db_pool)
+        .await
+        .map_err(HubError::from)
+    }
+
+    fn stream_history_between<'a>(
+        &'a self,
+        from: Option<&'a NaiveDateTime>,
+        to: &'a NaiveDateTime,
+    ) -> BoxStream<'a, sqlx::Result<TemperatureMeasurementRecord>> {
+        sqlx::query_as(
+            "SELECT timestamp, location, temperature, humidity, battery FROM history WHERE ($1::timestamp IS NULL OR timestamp >= $1) AND timestamp < $2 ORDER BY timestamp",
+        )
+        .bind(from.copied())
+        .bind(*to)
+        .fetch(&self.db_pool)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn insert_reading(
+        &self,
+        location: &str,
+        temperature: f64,
+        humidity: Option<f64>,
+        battery: Option<f64>,
+        timestamp: Option<NaiveDateTime>,
+    ) -> Result<(), HubError> {
+        retry_write(|| async {
+            sqlx::query(
+                "INSERT INTO history (timestamp, location, temperature, humidity, battery) VALUES (COALESCE($1, now() AT TIME ZONE 'UTC'), $2, $3, $4, $5)",
+            )
+            .bind(timestamp)
+            .bind(location)
+            .bind(temperature)
+            .bind(humidity)
+            .bind(battery)
+            .execute(&self.db_pool)
+            .await?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self, readings), fields(count = readings.len()))]
+    async fn insert_readings_batch(&self, readings: &[NewReading]) -> Result<(), HubError> {
+        retry_write(|| async {
+            let mut transaction = self
+                .db_pool
+                .begin()
+                .await?;
+            for reading in readings {
+                sqlx::query(
+                    "INSERT INTO history (timestamp, location, temperature, humidity, battery) VALUES (COALESCE($1, now() AT TIME ZONE 'UTC'), $2, $3, $4, $5)",
+                )
+                .bind(reading.timestamp)
+                .bind(&reading.location)
+                .bind(reading.temperature)
+                .bind(reading.humidity)
+                .bind(reading.battery)
+                .execute(&mut *transaction)
+                .await?;
+            }
+            transaction
+                .commit()
+                .await?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn insert_heater_state(
+        &self,
+        heater_id: &str,
+        state: HeaterState,
+    ) -> Result<(), HubError> {
+        if state == HeaterState::Unknown {
+            return Err(HubError::validation(
+                "An unknown heater state cannot be recorded",
+            ));
+        }
+        retry_write(|| async {
+            sqlx::query(
+                "INSERT INTO heater_history (timestamp, shelly_id, is_active) VALUES (now() AT TIME ZONE 'UTC', $1, $2)",
+            )
+            .bind(heater_id)
+            .bind(state == HeaterState::On)
+            .execute(&self.db_pool)
+            .await?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn prune_history_older_than(&self, duration: Duration) -> Result<u64, HubError> {
+        let before = Utc::now().naive_utc()
+            - chrono::Duration::from_std(duration)
+                .map_err(|_| HubError::validation("Retention is too long"))?;
+
+        let mut deleted = 0;
+        loop {
+            let rows = sqlx::query(
+                "DELETE FROM history WHERE ctid IN (SELECT ctid FROM history WHERE timestamp < $1 LIMIT $2)",
+            )
+            .bind(before)
+            .bind(PRUNE_CHUNK_SIZE)
+            .execute(&self.db_pool)
+            .await?
+            .rows_affected();
+            deleted += rows;
+            if rows < PRUNE_CHUNK_SIZE as u64 {
+                return Ok(deleted);
+            }
+        }
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn prune_heater_history_older_than(&self, duration: Duration) -> Result<u64, HubError> {
+        let before = Utc::now().naive_utc()
+            - chrono::Duration::from_std(duration)
+                .map_err(|_| HubError::validation("Retention is too long"))?;
+
+        let mut deleted = 0;
+        loop {
+            let rows = sqlx::query(
+                "DELETE FROM heater_history WHERE ctid IN (SELECT ctid FROM heater_history WHERE timestamp < $1 LIMIT $2)",
+            )
+            .bind(before)
+            .bind(PRUNE_CHUNK_SIZE)
+            .execute(&self.db_pool)
+            .await?
+            .rows_affected();
+            deleted += rows;
+            if rows < PRUNE_CHUNK_SIZE as u64 {
+                return Ok(deleted);
+            }
+        }
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn size(&self) -> Result<u64, HubError> {
+        let size: i64 = sqlx::query_scalar("SELECT pg_database_size(current_database())")
+            .fetch_one(&self.db_pool)
+            .await?;
+
+        Ok(size as u64)
+    }
+
+    /// A plain `VACUUM` does not lock the tables, but only returns the space
+    /// at the end of them to the file system.
+    #[tracing::instrument(skip(self))]
+    async fn vacuum(&self) -> Result<(), HubError> {
+        sqlx::query("VACUUM").execute(&self.db_pool).await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn insert_heater_power(&self, heater_id: &str, power: f64) -> Result<(), HubError> {
+        retry_write(|| async {
+            sqlx::query(
+                "INSERT INTO power_history (timestamp, shelly_id, power) VALUES (now() AT TIME ZONE 'UTC', $1, $2)",
+            )
+            .bind(heater_id)
+            .bind(power)
+            .execute(&self.db_pool)
+            .await?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn insert_heater_energy(&self, heater_id: &str, counter: f64) -> Result<(), HubError> {
+        retry_write(|| async {
+            let mut transaction = self
+                .db_pool
+                .begin()
+                .await?;
+            let previous = sqlx::query_scalar::<_, f64>(
+                "SELECT counter FROM energy_history WHERE shelly_id = $1 ORDER BY timestamp DESC, id DESC LIMIT 1",
+            )
+            .bind(heater_id)
+            .fetch_optional(&mut *transaction)
+            .await?;
+            sqlx::query(
+                "INSERT INTO energy_history (timestamp, shelly_id, counter, energy) VALUES (now() AT TIME ZONE 'UTC', $1, $2, $3)",
+            )
+            .bind(heater_id)
+            .bind(counter)
+            .bind(energy_increment(previous, counter))
+            .execute(&mut *transaction)
+            .await?;
+            transaction
+                .commit()
+                .await?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_heater_energy_total(
+        &self,
+        heater_id: &str,
+        since: NaiveDateTime,
+    ) -> Result<f64, HubError> {
+        sqlx::query_scalar::<_, f64>(
+            "SELECT COALESCE(SUM(energy), 0.0) FROM energy_history WHERE shelly_id = $1 AND timestamp >= $2",
+        )
+        .bind(heater_id)
+        .bind(since)
+        .fetch_one(&self.db_pool)
+        .await
+        .map_err(HubError::from)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn insert_setpoint_change(
+        &self,
+        temperature: f64,
+        source: SetpointSource,
+    ) -> Result<(), HubError> {
+        retry_write(|| async {
+            sqlx::query(
+                "INSERT INTO setpoint_history (timestamp, temperature, source) VALUES (now() AT TIME ZONE 'UTC', $1, $2)",
+            )
+            .bind(temperature)
+            .bind(source.as_ref())
+            .execute(&self.db_pool)
+            .await?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_setpoint_history_since(
+        &self,
+        duration: Duration,
+    ) -> Result<Vec<SetpointChange>, HubError> {
+        let since = Utc::now().naive_utc()
+            - chrono::Duration::from_std(duration)
+                .map_err(|_| HubError::validation("History duration is too long"))?;
+
+        sqlx::query_as::<_, (NaiveDateTime, f64, String)>(
+            "SELECT timestamp, temperature, source FROM setpoint_history WHERE timestamp > $1 ORDER BY timestamp, id",
+        )
+        .bind(since)
+        .fetch_all(&self.db_pool)
+        .await?
+        .into_iter()
+        .map(setpoint_change)
+        .collect()
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_latest_setpoint(&self) -> Result<Option<SetpointChange>, HubError> {
+        sqlx::query_as::<_, (NaiveDateTime, f64, String)>(
+            "SELECT timestamp, temperature, source FROM setpoint_history ORDER BY timestamp DESC, id DESC LIMIT 1",
+        )
+        .fetch_optional(&self.db_pool)
+        .await?
+        .map(setpoint_change)
+        .transpose()
+    }
+
+    #[tracing::instrument(skip(self, payload))]
+    async fn insert_dead_letter(
+        &self,
+        topic: &str,
+        payload: &[u8],
+        error: &str,
+    ) -> Result<(), HubError> {
+        let payload = STANDARD.encode(payload);
+        retry_write(|| async {
+            sqlx::query(
+                "INSERT INTO dead_letters (timestamp, topic, payload, error) VALUES (now() AT TIME ZONE 'UTC', $1, $2, $3)",
+            )
+            .bind(topic)
+            .bind(&payload)
+            .bind(error)
+            .execute(&self.db_pool)
+            .await?;
+
+            Ok(())
+        })
+        .await?;
+
+        sqlx::query(
+            "DELETE FROM dead_letters WHERE id NOT IN (SELECT id FROM dead_letters ORDER BY id DESC LIMIT $1)",
+        )
+        .bind(MAX_DEAD_LETTERS)
+        .execute(&self.db_pool)
+        .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_heater_runtime(
+        &self,
+        heater_id: &str,
+        since: NaiveDateTime,
+    ) -> Result<Duration, HubError> {
+        let was_active = sqlx::query_scalar::<_, bool>(
+            "SELECT is_active FROM heater_history WHERE shelly_id = $1 AND timestamp < $2 ORDER BY timestamp DESC LIMIT 1",
+        )
+        .bind(heater_id)
+        .bind(since)
+        .fetch_optional(&self.db_pool)
+        .await?
+        .unwrap_or(false);
+
+        let transitions = sqlx::query_as::<_, (NaiveDateTime, bool)>(
+            "SELECT timestamp, is_active FROM heater_history WHERE shelly_id = $1 AND timestamp >= $2 ORDER BY timestamp",
+        )
+        .bind(heater_id)
+        .bind(since)
+        .fetch_all(&self.db_pool)
+        .await?;
+
+        Ok(heater_runtime(was_active, since, transitions))
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn count_heater_cycles(
+        &self,
+        heater_id: &str,
+        since: NaiveDateTime,
+    ) -> Result<u64, HubError> {
+        let was_active = sqlx::query_scalar::<_, bool>(
+            "SELECT is_active FROM heater_history WHERE shelly_id = $1 AND timestamp < $2 ORDER BY timestamp DESC LIMIT 1",
+        )
+        .bind(heater_id)
+        .bind(since)
+        .fetch_optional(&self.db_pool)
+        .await?
+        .unwrap_or(false);
+
+        let transitions = sqlx::query_scalar::<_, bool>(
+            "SELECT is_active FROM heater_history WHERE shelly_id = $1 AND timestamp >= $2 ORDER BY timestamp",
+        )
+        .bind(heater_id)
+        .bind(since)
+        .fetch_all(&self.db_pool)
+        .await?;
+
+        Ok(heater_cycles(was_active, transitions))
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_heater_history_since(
+        &self,
+        heater_id: &str,
+        duration: Duration,
+    ) -> Result<Vec<HeaterHistoryRecord>, HubError> {
+        let since = Utc::now().naive_utc()
+            - chrono::Duration::from_std(duration)
+                .map_err(|_| HubError::validation("History duration is too long"))?;
+
+        sqlx::query_as::<_, HeaterHistoryRecord>(
+            "SELECT timestamp, shelly_id, is_active FROM heater_history WHERE shelly_id = $1 AND timestamp > $2 ORDER BY timestamp",
+        )
+        .bind(heater_id)
+        .bind(since)
+        .fetch_all(&self.db_pool)
+        .await
+        .map_err(HubError::from)
+    }
+}
+
+/// Convert a row of the setpoint history, where the source is stored as text.
+fn setpoint_change(
+    (timestamp, temperature, source): (NaiveDateTime, f64, String),
+) -> Result<SetpointChange, HubError> {
+    Ok(SetpointChange {
+        timestamp,
+        temperature,
+        source: SetpointSource::from_str(&source)
+            .map_err(|_| HubError::parse(format!("Unknown setpoint source '{source}'")))?,
+    })
+}
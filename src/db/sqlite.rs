@@ -0,0 +1,629 @@
+//! Storage of the hub's data in a SQLite database.
+
+use std::{path::PathBuf, str::FromStr, time::Duration};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{NaiveDateTime, Utc};
+use futures_util::stream::BoxStream;
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions},
+    SqlitePool,
+};
+
+use super::{
+    energy_increment, heater_cycles, heater_runtime, retry_write, DbConfig, HeaterHistoryRecord,
+    HourlyAggregate, HumidityRecord, MigrationError, NewReading, SetpointChange, Storage,
+    TemperatureMeasurementRecord, MAX_DEAD_LETTERS, PRUNE_CHUNK_SIZE,
+};
+use crate::{
+    error::HubError,
+    models::{Firmware, Heater, HeaterState, SetpointSource},
+    schedule::{Schedule, ScheduleEntry},
+};
+
+/// Create a connection pool to the configured Sqlite database. Connections
+/// use write-ahead logging, so readers do not block the writer.
+///
+/// The connection string may carry parameters like `?mode=rwc`, and the
+/// directory of the database file is created when missing. An in-memory
+/// database, like `sqlite::memory:`, lives as long as the pool, which keeps a
+/// connection to it open.
+pub async fn create_pool(config: &DbConfig) -> Result<SqlitePool> {
+    let options = SqliteConnectOptions::from_str(&config.url)
+        .context("Invalid database connection string")?
+        .journal_mode(SqliteJournalMode::Wal)
+        .busy_timeout(Duration::from_millis(config.busy_timeout_ms));
+
+    let file = database_file(&config.url);
+    if let Some(parent) = file.as_ref().and_then(|file| file.parent()) {
+        if !parent.as_os_str().is_empty() && !parent.exists() {
+            tracing::info!(directory = %parent.display(), "Creating database directory");
+            tokio::fs::create_dir_all(parent)
+                .await
+                .with_context(|| format!("Failed to create directory {}", parent.display()))?;
+        }
+    }
+
+    let pool_options = SqlitePoolOptions::new()
+        .max_connections(config.max_connections)
+        .acquire_timeout(Duration::from_secs(config.acquire_timeout_secs));
+    // The in-memory database is gone once its last connection is closed.
+    let pool_options = match file {
+        Some(_) => pool_options,
+        None => pool_options
+            .min_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None),
+    };
+    pool_options
+        .connect_with(options)
+        .await
+        .context("Failed to connect to database")
+}
+
+/// The path of the database file of a SQLite connection string, or `None`
+/// for an in-memory database.
+pub fn database_file(url: &str) -> Option<PathBuf> {
+    let database = url.strip_prefix("sqlite:")?;
+    let database = database.strip_prefix("//").unwrap_or(database);
+    let (path, params) = database.split_once('?').unwrap_or((database, ""));
+    let in_memory = path.is_empty()
+        || path == ":memory:"
+        || params.split('&').any(|param| param == "mode=memory");
+
+    (!in_memory).then(|| PathBuf::from(path))
+}
+
+/// Storage in a SQLite database.
+#[derive(Debug)]
+pub struct SqliteStorage {
+    db_pool: SqlitePool,
+}
+
+impl SqliteStorage {
+    /// Create the storage, applying the migrations to the database.
+    pub async fn new(db_pool: SqlitePool) -> Result<Self> {
+        tracing::trace!("Applying migrations");
+        sqlx::migrate!("./migrations/sqlite")
+            .run(&db_pool)
+            .await
+            .map_err(MigrationError::from)?;
+        tracing::trace!("Migrations completed");
+
+        Ok(Self { db_pool })
+    }
+
+    /// Create the storage for a database whose schema is managed externally.
+    pub fn without_migrations(db_pool: SqlitePool) -> Self {
+        Self { db_pool }
+    }
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    #[tracing::instrument(skip(self))]
+    async fn ping(&self) -> Result<(), HubError> {
+        sqlx::query("SELECT 1").execute(&self.db_pool).await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_heaters(&self) -> Result<Vec<Heater>, HubError> {
+        sqlx::query!("SELECT id, name, place, firmware, watts FROM heaters ORDER BY rowid")
+            .fetch_all(&self.db_pool)
+            .await?
+            .into_iter()
+            .map(|row| {
+                let firmware = Firmware::from_str(&row.firmware).map_err(|_| {
+                    HubError::parse(format!("Unknown heater firmware '{}'", row.firmware))
+                })?;
+                Ok(Heater::new(row.id, row.name, row.place)
+                    .with_firmware(firmware)
+                    .with_watts(row.watts))
+            })
+            .collect()
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn upsert_heater(&self, heater: &Heater) -> Result<(), HubError> {
+        let (id, name, place) = (heater.id(), heater.name(), heater.place());
+        let firmware = heater.firmware().as_ref();
+        let watts = *heater.watts();
+        retry_write(|| async {
+            sqlx::query!(
+                "INSERT INTO heaters (id, name, place, firmware, watts) VALUES (?, ?, ?, ?, ?) ON CONFLICT (id) DO UPDATE SET name = excluded.name, place = excluded.place, firmware = excluded.firmware, watts = excluded.watts",
+                id,
+                name,
+                place,
+                firmware,
+                watts
+            )
+            .execute(&self.db_pool)
+            .await?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_schedule(&self) -> Result<Schedule, HubError> {
+        let entries = sqlx::query!(
+            r#"SELECT time_of_day as "time_of_day: chrono::NaiveTime", desired_temperature FROM schedule"#
+        )
+            .fetch_all(&self.db_pool)
+            .await?
+            .into_iter()
+            .map(|row| ScheduleEntry::new(row.time_of_day, row.desired_temperature))
+            .collect();
+
+        Ok(Schedule::new(entries))
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_history_since(
+        &self,
+        duration: Duration,
+    ) -> Result<Vec<TemperatureMeasurementRecord>, HubError> {
+        let since = Utc::now().naive_utc()
+            - chrono::Duration::from_std(duration)
+                .map_err(|_| HubError::validation("History duration is too long"))?;
+
+        sqlx::query_as!(
+            TemperatureMeasurementRecord,
+            "SELECT * FROM history WHERE timestamp > ? ORDER BY timestamp",
+            since
+        )
+        .fetch_all(&self.db_pool)
+        .await
+        .map_err(HubError::from)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_humidity_history_since(
+        &self,
+        duration: Duration,
+    ) -> Result<Vec<HumidityRecord>, HubError> {
+        let since = Utc::now().naive_utc()
+            - chrono::Duration::from_std(duration)
+                .map_err(|_| HubError::validation("History duration is too long"))?;
+
+        sqlx::query_as!(
+            HumidityRecord,
+            r#"SELECT timestamp, location, humidity as "humidity!: f64" FROM history WHERE timestamp > ? AND humidity IS NOT NULL ORDER BY timestamp"#,
+            since
+        )
+        .fetch_all(&self.db_pool)
+        .await
+        .map_err(HubError::from)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_hourly_averages(
+        &self,
+        location: &str,
+        since: NaiveDateTime,
+    ) -> Result<Vec<HourlyAggregate>, HubError> {
+        let rows = sqlx::query!(
+            r#"SELECT strftime('%Y-%m-%d %H:00:00', timestamp) as "hour!: NaiveDateTime", AVG(temperature) as "average_temperature!: f64", AVG(humidity) as "average_humidity: f64", COUNT(*) as "readings!: i64" FROM history WHERE location = ? AND timestamp >= ? GROUP BY 1 ORDER BY 1"#,
+            location,
+            since
+        )
+        .fetch_all(&self.db_pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| HourlyAggregate {
+                hour: row.hour,
+                average_temperature: row.average_temperature,
+                average_humidity: row.average_humidity,
+                readings: row.readings as u64,
+            })
+            .collect())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_latest_reading(
+        &self,
+        location: &str,
+    ) -> Result<Option<TemperatureMeasurementRecord>, HubError> {
+        sqlx::query_as!(
+            TemperatureMeasurementRecord,
+            "SELECT * FROM history WHERE location = ? ORDER BY timestamp DESC LIMIT 1",
+            location
+        )
+        .fetch_optional(&self.db_pool)
+        .await
+        .map_err(HubError::from)
+    }
+
+    fn stream_history_between<'a>(
+        &'a self,
+        from: Option<&'a NaiveDateTime>,
+        to: &'a NaiveDateTime,
+    ) -> BoxStream<'a, sqlx::Result<TemperatureMeasurementRecord>> {
+        match from {
+            Some(from) => sqlx::query_as!(
+                TemperatureMeasurementRecord,
+                "SELECT * FROM history WHERE timestamp >= ? AND timestamp < ? ORDER BY timestamp",
+                *from,
+                *to
+            )
+            .fetch(&self.db_pool),
+            None => sqlx::query_as!(
+                TemperatureMeasurementRecord,
+                "SELECT * FROM history WHERE timestamp < ? ORDER BY timestamp",
+                *to
+            )
+            .fetch(&self.db_pool),
+        }
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn insert_reading(
+        &self,
+        location: &str,
+        temperature: f64,
+        humidity: Option<f64>,
+        battery: Option<f64>,
+        timestamp: Option<NaiveDateTime>,
+    ) -> Result<(), HubError> {
+        retry_write(|| async {
+            sqlx::query!(
+                "INSERT INTO history(timestamp, location, temperature, humidity, battery) VALUES (COALESCE(?, current_timestamp), ?, ?, ?, ?)",
+                timestamp,
+                location,
+                temperature,
+                humidity,
+                battery
+            )
+            .execute(&self.db_pool)
+            .await?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self, readings), fields(count = readings.len()))]
+    async fn insert_readings_batch(&self, readings: &[NewReading]) -> Result<(), HubError> {
+        retry_write(|| async {
+            let mut transaction = self
+                .db_pool
+                .begin()
+                .await?;
+            for reading in readings {
+                sqlx::query!(
+                    "INSERT INTO history(timestamp, location, temperature, humidity, battery) VALUES (COALESCE(?, current_timestamp), ?, ?, ?, ?)",
+                    reading.timestamp,
+                    reading.location,
+                    reading.temperature,
+                    reading.humidity,
+                    reading.battery
+                )
+                .execute(&mut *transaction)
+                .await?;
+            }
+            transaction
+                .commit()
+                .await?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn insert_heater_state(
+        &self,
+        heater_id: &str,
+        state: HeaterState,
+    ) -> Result<(), HubError> {
+        if state == HeaterState::Unknown {
+            return Err(HubError::validation(
+                "An unknown heater state cannot be recorded",
+            ));
+        }
+        retry_write(|| async {
+            sqlx::query!("INSERT INTO heater_history (timestamp, shelly_id, is_active) VALUES (current_timestamp, ?, ?)", heater_id, state)
+                .execute(&self.db_pool).await?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn prune_history_older_than(&self, duration: Duration) -> Result<u64, HubError> {
+        let before = Utc::now().naive_utc()
+            - chrono::Duration::from_std(duration)
+                .map_err(|_| HubError::validation("Retention is too long"))?;
+
+        let mut deleted = 0;
+        loop {
+            let rows = sqlx::query!(
+                "DELETE FROM history WHERE rowid IN (SELECT rowid FROM history WHERE timestamp < ? LIMIT ?)",
+                before,
+                PRUNE_CHUNK_SIZE
+            )
+            .execute(&self.db_pool)
+            .await?
+            .rows_affected();
+            deleted += rows;
+            if rows < PRUNE_CHUNK_SIZE as u64 {
+                return Ok(deleted);
+            }
+        }
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn prune_heater_history_older_than(&self, duration: Duration) -> Result<u64, HubError> {
+        let before = Utc::now().naive_utc()
+            - chrono::Duration::from_std(duration)
+                .map_err(|_| HubError::validation("Retention is too long"))?;
+
+        let mut deleted = 0;
+        loop {
+            let rows = sqlx::query!(
+                "DELETE FROM heater_history WHERE rowid IN (SELECT rowid FROM heater_history WHERE timestamp < ? LIMIT ?)",
+                before,
+                PRUNE_CHUNK_SIZE
+            )
+            .execute(&self.db_pool)
+            .await?
+            .rows_affected();
+            deleted += rows;
+            if rows < PRUNE_CHUNK_SIZE as u64 {
+                return Ok(deleted);
+            }
+        }
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn size(&self) -> Result<u64, HubError> {
+        let size: i64 = sqlx::query_scalar(
+            "SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size()",
+        )
+        .fetch_one(&self.db_pool)
+        .await?;
+
+        Ok(size as u64)
+    }
+
+    /// The database is rebuilt through the write-ahead log, which is then
+    /// checkpointed and truncated, so the file shrinks without stopping the
+    /// readers.
+    #[tracing::instrument(skip(self))]
+    async fn vacuum(&self) -> Result<(), HubError> {
+        sqlx::query("VACUUM").execute(&self.db_pool).await?;
+        sqlx::query("PRAGMA wal_checkpoint(TRUNCATE)")
+            .execute(&self.db_pool)
+            .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn insert_heater_power(&self, heater_id: &str, power: f64) -> Result<(), HubError> {
+        retry_write(|| async {
+            sqlx::query!(
+                "INSERT INTO power_history (timestamp, shelly_id, power) VALUES (current_timestamp, ?, ?)",
+                heater_id,
+                power
+            )
+            .execute(&self.db_pool)
+            .await?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn insert_heater_energy(&self, heater_id: &str, counter: f64) -> Result<(), HubError> {
+        retry_write(|| async {
+            let mut transaction = self
+                .db_pool
+                .begin()
+                .await?;
+            let previous = sqlx::query_scalar!(
+                "SELECT counter FROM energy_history WHERE shelly_id = ? ORDER BY timestamp DESC, rowid DESC LIMIT 1",
+                heater_id
+            )
+            .fetch_optional(&mut *transaction)
+            .await?;
+            let energy = energy_increment(previous, counter);
+            sqlx::query!(
+                "INSERT INTO energy_history (timestamp, shelly_id, counter, energy) VALUES (current_timestamp, ?, ?, ?)",
+                heater_id,
+                counter,
+                energy
+            )
+            .execute(&mut *transaction)
+            .await?;
+            transaction
+                .commit()
+                .await?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_heater_energy_total(
+        &self,
+        heater_id: &str,
+        since: NaiveDateTime,
+    ) -> Result<f64, HubError> {
+        sqlx::query_scalar!(
+            r#"SELECT COALESCE(SUM(energy), 0.0) as "total!: f64" FROM energy_history WHERE shelly_id = ? AND timestamp >= ?"#,
+            heater_id,
+            since
+        )
+        .fetch_one(&self.db_pool)
+        .await
+        .map_err(HubError::from)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn insert_setpoint_change(
+        &self,
+        temperature: f64,
+        source: SetpointSource,
+    ) -> Result<(), HubError> {
+        retry_write(|| async {
+            sqlx::query!(
+                "INSERT INTO setpoint_history (timestamp, temperature, source) VALUES (current_timestamp, ?, ?)",
+                temperature,
+                source
+            )
+            .execute(&self.db_pool)
+            .await?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_setpoint_history_since(
+        &self,
+        duration: Duration,
+    ) -> Result<Vec<SetpointChange>, HubError> {
+        let since = Utc::now().naive_utc()
+            - chrono::Duration::from_std(duration)
+                .map_err(|_| HubError::validation("History duration is too long"))?;
+
+        sqlx::query_as!(
+            SetpointChange,
+            r#"SELECT timestamp, temperature, source as "source: SetpointSource" FROM setpoint_history WHERE timestamp > ? ORDER BY timestamp, rowid"#,
+            since
+        )
+        .fetch_all(&self.db_pool)
+        .await
+        .map_err(HubError::from)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_latest_setpoint(&self) -> Result<Option<SetpointChange>, HubError> {
+        sqlx::query_as!(
+            SetpointChange,
+            r#"SELECT timestamp, temperature, source as "source: SetpointSource" FROM setpoint_history ORDER BY timestamp DESC, rowid DESC LIMIT 1"#
+        )
+        .fetch_optional(&self.db_pool)
+        .await
+        .map_err(HubError::from)
+    }
+
+    #[tracing::instrument(skip(self, payload))]
+    async fn insert_dead_letter(
+        &self,
+        topic: &str,
+        payload: &[u8],
+        error: &str,
+    ) -> Result<(), HubError> {
+        let payload = STANDARD.encode(payload);
+        retry_write(|| async {
+            sqlx::query!(
+                "INSERT INTO dead_letters (timestamp, topic, payload, error) VALUES (current_timestamp, ?, ?, ?)",
+                topic,
+                payload,
+                error
+            )
+            .execute(&self.db_pool)
+            .await?;
+
+            Ok(())
+        })
+        .await?;
+
+        sqlx::query!(
+            "DELETE FROM dead_letters WHERE rowid NOT IN (SELECT rowid FROM dead_letters ORDER BY rowid DESC LIMIT ?)",
+            MAX_DEAD_LETTERS
+        )
+        .execute(&self.db_pool)
+        .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_heater_runtime(
+        &self,
+        heater_id: &str,
+        since: NaiveDateTime,
+    ) -> Result<Duration, HubError> {
+        let was_active = sqlx::query_scalar!(
+            "SELECT is_active FROM heater_history WHERE shelly_id = ? AND timestamp < ? ORDER BY timestamp DESC LIMIT 1",
+            heater_id,
+            since
+        )
+        .fetch_optional(&self.db_pool)
+        .await?
+        .unwrap_or(false);
+
+        let transitions = sqlx::query!(
+            "SELECT timestamp, is_active FROM heater_history WHERE shelly_id = ? AND timestamp >= ? ORDER BY timestamp",
+            heater_id,
+            since
+        )
+        .fetch_all(&self.db_pool)
+        .await?
+        .into_iter()
+        .map(|row| (row.timestamp, row.is_active));
+
+        Ok(heater_runtime(was_active, since, transitions))
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn count_heater_cycles(
+        &self,
+        heater_id: &str,
+        since: NaiveDateTime,
+    ) -> Result<u64, HubError> {
+        let was_active = sqlx::query_scalar!(
+            "SELECT is_active FROM heater_history WHERE shelly_id = ? AND timestamp < ? ORDER BY timestamp DESC LIMIT 1",
+            heater_id,
+            since
+        )
+        .fetch_optional(&self.db_pool)
+        .await?
+        .unwrap_or(false);
+
+        let transitions = sqlx::query_scalar!(
+            "SELECT is_active FROM heater_history WHERE shelly_id = ? AND timestamp >= ? ORDER BY timestamp, rowid",
+            heater_id,
+            since
+        )
+        .fetch_all(&self.db_pool)
+        .await?;
+
+        Ok(heater_cycles(was_active, transitions))
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_heater_history_since(
+        &self,
+        heater_id: &str,
+        duration: Duration,
+    ) -> Result<Vec<HeaterHistoryRecord>, HubError> {
+        let since = Utc::now().naive_utc()
+            - chrono::Duration::from_std(duration)
+                .map_err(|_| HubError::validation("History duration is too long"))?;
+
+        sqlx::query_as!(
+            HeaterHistoryRecord,
+            "SELECT timestamp, shelly_id, is_active FROM heater_history WHERE shelly_id = ? AND timestamp > ? ORDER BY timestamp",
+            heater_id,
+            since
+        )
+        .fetch_all(&self.db_pool)
+        .await
+        .map_err(HubError::from)
+    }
+}
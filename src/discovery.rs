@@ -0,0 +1,124 @@
+//! Home Assistant MQTT discovery, describing the heaters and sensors with the
+//! topics the hub already uses, so they appear in Home Assistant automatically.
+
+use serde_json::{json, Value};
+
+use crate::models::Heater;
+
+/// Topic announcing whether the hub is online, shared with the last will.
+pub const AVAILABILITY_TOPIC: &str = "hub/status/availability";
+
+/// Discovery topic and config payload for a heater, exposed as a climate
+/// entity whose setpoint is the heater specific desired temperature. The
+/// topics the hub subscribes to are prefixed with `topic_prefix`.
+pub fn heater_config(heater: &Heater, topic_prefix: &str) -> (String, Value) {
+    let id = heater.id();
+    let status_topic = format!("hub/status/heater/{id}");
+    let unique_id = format!("paletten_heater_{}", id.to_lowercase());
+    let config = json!({
+        "name": heater.name(),
+        "unique_id": unique_id,
+        "availability_topic": AVAILABILITY_TOPIC,
+        "temperature_command_topic": format!("{topic_prefix}temperature/set/{id}"),
+        "temperature_state_topic": status_topic,
+        "temperature_state_template": "{{ value_json.desired_temperature }}",
+        "current_temperature_topic": status_topic,
+        "current_temperature_template": "{{ value_json.current_temperature }}",
+        "action_topic": status_topic,
+        "action_template": "{{ {'on': 'heating', 'off': 'idle'}.get(value_json.state, 'off') }}",
+        "modes": ["auto", "off"],
+        "mode_command_topic": format!("{topic_prefix}temperature/auto"),
+        "mode_command_template": "{{ 'true' if value == 'auto' else 'false' }}",
+        "temperature_unit": "C",
+        "temp_step": 0.5,
+        "device": {
+            "identifiers": [unique_id],
+            "name": heater.name(),
+            "manufacturer": "Shelly",
+            "suggested_area": heater.place(),
+        },
+    });
+
+    (format!("homeassistant/climate/{id}/config"), config)
+}
+
+/// Discovery topic and config payload for the temperature sensor of a place,
+/// whose readings are published to the topic prefixed with `topic_prefix`.
+pub fn sensor_config(place: &str, topic_prefix: &str) -> (String, Value) {
+    let unique_id = format!("paletten_temperature_{place}");
+    let config = json!({
+        "name": format!("Temperature {place}"),
+        "unique_id": unique_id,
+        "availability_topic": AVAILABILITY_TOPIC,
+        "state_topic": format!("{topic_prefix}measurement/{place}"),
+        "value_template": "{{ value_json.temperature }}",
+        "device_class": "temperature",
+        "state_class": "measurement",
+        "unit_of_measurement": "°C",
+        "device": {
+            "identifiers": [unique_id],
+            "name": format!("Sensor {place}"),
+        },
+    });
+
+    (format!("homeassistant/sensor/{place}/config"), config)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn heater_config_describes_hub_topics() {
+        let heater = Heater::new("C4402D".into(), "Spisebord".into(), "inside".into());
+
+        let (topic, config) = heater_config(&heater, "");
+
+        assert_eq!(topic, "homeassistant/climate/C4402D/config");
+        assert_eq!(config["unique_id"], "paletten_heater_c4402d");
+        assert_eq!(config["name"], "Spisebord");
+        assert_eq!(
+            config["temperature_command_topic"],
+            "temperature/set/C4402D"
+        );
+        assert_eq!(
+            config["temperature_state_topic"],
+            "hub/status/heater/C4402D"
+        );
+        assert_eq!(
+            config["current_temperature_topic"],
+            "hub/status/heater/C4402D"
+        );
+        assert_eq!(config["mode_command_topic"], "temperature/auto");
+        assert_eq!(config["availability_topic"], AVAILABILITY_TOPIC);
+    }
+
+    #[test]
+    fn sensor_config_reads_measurement_topic() {
+        let (topic, config) = sensor_config("outside", "");
+
+        assert_eq!(topic, "homeassistant/sensor/outside/config");
+        assert_eq!(config["unique_id"], "paletten_temperature_outside");
+        assert_eq!(config["state_topic"], "measurement/outside");
+        assert_eq!(config["device_class"], "temperature");
+    }
+
+    #[test]
+    fn configs_prefix_the_subscribed_topics() {
+        let heater = Heater::new("C4402D".into(), "Spisebord".into(), "inside".into());
+
+        let (_, heater) = heater_config(&heater, "apartment1/");
+        let (_, sensor) = sensor_config("outside", "apartment1/");
+
+        assert_eq!(
+            heater["temperature_command_topic"],
+            "apartment1/temperature/set/C4402D"
+        );
+        assert_eq!(heater["mode_command_topic"], "apartment1/temperature/auto");
+        assert_eq!(
+            heater["temperature_state_topic"],
+            "hub/status/heater/C4402D"
+        );
+        assert_eq!(sensor["state_topic"], "apartment1/measurement/outside");
+    }
+}
@@ -0,0 +1,39 @@
+//! Errors that callers tell apart, e.g. to respond to a bad payload
+//! differently than to a failing database. Elsewhere errors are
+//! `anyhow::Error`, which these convert into and can be downcast from.
+
+use rumqttc::v5::ClientError;
+
+/// Reasons the hub fails to handle a message or request.
+#[derive(Debug, thiserror::Error)]
+pub enum HubError {
+    /// A payload or stored value could not be parsed.
+    #[error("{0}")]
+    Parse(String),
+    /// A value was parsed, but is not accepted, e.g. a reading out of bounds.
+    #[error("{0}")]
+    Validation(String),
+    /// The database could not be reached or rejected a query.
+    #[error("Database failed: {0}")]
+    Db(#[from] sqlx::Error),
+    /// A request could not be queued with the MQTT client. Boxed, as it
+    /// carries the request and would make every result large.
+    #[error("MQTT client failed: {0}")]
+    Mqtt(#[source] Box<ClientError>),
+}
+
+impl HubError {
+    pub fn parse(message: impl Into<String>) -> Self {
+        Self::Parse(message.into())
+    }
+
+    pub fn validation(message: impl Into<String>) -> Self {
+        Self::Validation(message.into())
+    }
+}
+
+impl From<ClientError> for HubError {
+    fn from(error: ClientError) -> Self {
+        Self::Mqtt(Box::new(error))
+    }
+}
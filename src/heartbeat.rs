@@ -0,0 +1,122 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use chrono::Utc;
+use rumqttc::v5::{mqttbytes::QoS, AsyncClient};
+use tokio::time::MissedTickBehavior;
+
+/// Topic the heartbeat is published to.
+pub const HEARTBEAT_TOPIC: &str = "hub/heartbeat";
+
+const DEFAULT_HEARTBEAT_INTERVAL_SECS: u64 = 30;
+
+/// Configuration of the heartbeat published while the hub is running, so a
+/// watchdog can tell it is alive when no heartbeat arrives for twice the
+/// interval.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct HeartbeatConfig {
+    pub interval_secs: u64,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            interval_secs: DEFAULT_HEARTBEAT_INTERVAL_SECS,
+        }
+    }
+}
+
+impl HeartbeatConfig {
+    /// Override the configuration with the `HEARTBEAT_INTERVAL_SECS`
+    /// environment variable found by `lookup`.
+    pub fn with_env_overrides(self, lookup: &impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let interval_secs = lookup("HEARTBEAT_INTERVAL_SECS")
+            .map(|secs| {
+                secs.parse::<u64>()
+                    .context("HEARTBEAT_INTERVAL_SECS is not a valid number of seconds")
+            })
+            .transpose()?
+            .unwrap_or(self.interval_secs);
+        if interval_secs == 0 {
+            return Err(anyhow!("The heartbeat interval must be at least 1 second"));
+        }
+
+        Ok(Self { interval_secs })
+    }
+
+    /// How often the heartbeat is published.
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_secs)
+    }
+}
+
+/// Periodically publish the current time to `hub/heartbeat`.
+pub async fn run(mqtt_client: AsyncClient, interval: Duration) -> Result<()> {
+    let mut interval = tokio::time::interval(interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    loop {
+        interval.tick().await;
+        if let Err(e) = mqtt_client
+            .publish(
+                HEARTBEAT_TOPIC,
+                QoS::AtMostOnce,
+                false,
+                Utc::now().to_rfc3339(),
+            )
+            .await
+        {
+            tracing::error!(error = %e, "Failed to publish heartbeat");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rumqttc::v5::Request;
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn heartbeat_is_published_every_interval() {
+        // Arrange
+        let (request_tx, requests) = flume::unbounded();
+        let task = tokio::spawn(run(
+            AsyncClient::from_senders(request_tx),
+            Duration::from_secs(30),
+        ));
+
+        // Act
+        tokio::time::sleep(Duration::from_secs(45)).await;
+        task.abort();
+
+        // Assert
+        let heartbeats: Vec<_> = requests
+            .try_iter()
+            .filter_map(|request| match request {
+                Request::Publish(publish) if publish.topic == HEARTBEAT_TOPIC => {
+                    Some(String::from_utf8(publish.payload.to_vec()).unwrap())
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(heartbeats.len(), 2);
+        assert!(chrono::DateTime::parse_from_rfc3339(&heartbeats[0]).is_ok());
+    }
+
+    #[test]
+    fn heartbeat_interval_from_env() {
+        let lookup = |secs: &'static str| {
+            move |key: &str| (key == "HEARTBEAT_INTERVAL_SECS").then(|| secs.to_string())
+        };
+
+        let config = HeartbeatConfig::default()
+            .with_env_overrides(&lookup("10"))
+            .unwrap();
+
+        assert_eq!(config.interval(), Duration::from_secs(10));
+        assert!(HeartbeatConfig::default()
+            .with_env_overrides(&lookup("0"))
+            .is_err());
+    }
+}
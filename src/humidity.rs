@@ -0,0 +1,232 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use chrono::{NaiveDateTime, Utc};
+use rumqttc::v5::{mqttbytes::QoS, AsyncClient};
+use tokio::{sync::Mutex, time::MissedTickBehavior};
+
+use crate::{
+    db::{Database, HumidityRecord},
+    models::HumidityAlert,
+};
+
+/// Topic the humidity alerts are published to.
+const HUMIDITY_ALERT_TOPIC: &str = "hub/alert/humidity";
+
+/// How often the humidity is checked.
+const CHECK_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+const DEFAULT_HUMIDITY_THRESHOLD: f64 = 80.0;
+const DEFAULT_HUMIDITY_DURATION_SECS: u64 = 60 * 60;
+
+/// Configuration of when a high humidity is alerted, which is when a place
+/// stays above `threshold` % for `duration_secs` seconds.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct HumidityConfig {
+    pub threshold: f64,
+    pub duration_secs: u64,
+}
+
+impl Default for HumidityConfig {
+    fn default() -> Self {
+        Self {
+            threshold: DEFAULT_HUMIDITY_THRESHOLD,
+            duration_secs: DEFAULT_HUMIDITY_DURATION_SECS,
+        }
+    }
+}
+
+impl HumidityConfig {
+    /// How long the humidity must stay above the threshold to be alerted.
+    pub fn duration(&self) -> Duration {
+        Duration::from_secs(self.duration_secs)
+    }
+}
+
+/// Periodically check the humidity history and publish an alert to
+/// `hub/alert/humidity` when a place starts or stops being too humid.
+pub async fn run(
+    db: Arc<Mutex<Database>>,
+    mqtt_client: AsyncClient,
+    config: HumidityConfig,
+) -> Result<()> {
+    let mut monitor = HumidityMonitor::new(config);
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    loop {
+        interval.tick().await;
+        if let Err(e) = check(&db, &mqtt_client, &mut monitor).await {
+            tracing::error!(error = %e, "Failed to check humidity");
+        }
+    }
+}
+
+#[tracing::instrument(skip_all)]
+async fn check(
+    db: &Mutex<Database>,
+    mqtt_client: &AsyncClient,
+    monitor: &mut HumidityMonitor,
+) -> Result<()> {
+    // Twice the duration, so the start of a sustained high humidity is found.
+    let history = db
+        .lock()
+        .await
+        .get_humidity_history_since(monitor.config.duration() * 2)
+        .await?;
+
+    for alert in monitor.update(&history, Utc::now().naive_utc()) {
+        tracing::warn!(?alert, "Humidity alert");
+        let payload = serde_json::to_vec(&alert).context("Failed to serialize alert")?;
+        mqtt_client
+            .publish(HUMIDITY_ALERT_TOPIC, QoS::AtLeastOnce, false, payload)
+            .await
+            .context("Failed to publish alert")?;
+    }
+
+    Ok(())
+}
+
+/// Tracks which places are alerted for a sustained high humidity.
+#[derive(Debug)]
+struct HumidityMonitor {
+    config: HumidityConfig,
+    alerted: HashSet<String>,
+}
+
+impl HumidityMonitor {
+    fn new(config: HumidityConfig) -> Self {
+        Self {
+            config,
+            alerted: HashSet::new(),
+        }
+    }
+
+    /// Update the alerted places from the humidity history ordered by time,
+    /// returning the alerts raised or released by it.
+    fn update(&mut self, history: &[HumidityRecord], now: NaiveDateTime) -> Vec<HumidityAlert> {
+        let mut places: BTreeMap<&str, Vec<&HumidityRecord>> = BTreeMap::new();
+        for record in history {
+            places.entry(record.location()).or_default().push(record);
+        }
+
+        let duration = chrono::Duration::from_std(self.config.duration())
+            .unwrap_or(chrono::Duration::max_value());
+        let mut alerts = Vec::new();
+        for (place, records) in places {
+            let Some(latest) = records.last() else {
+                continue;
+            };
+            let sustained = above_since(&records, self.config.threshold)
+                .is_some_and(|since| now - since >= duration);
+            let changed = if sustained {
+                self.alerted.insert(place.to_string())
+            } else {
+                self.alerted.remove(place)
+            };
+            if changed {
+                alerts.push(HumidityAlert {
+                    active: sustained,
+                    place: place.to_string(),
+                    humidity: *latest.humidity(),
+                    threshold: self.config.threshold,
+                });
+            }
+        }
+
+        alerts
+    }
+}
+
+/// When the humidity rose above `threshold`, if the latest of the `records`
+/// ordered by time is above it.
+fn above_since(records: &[&HumidityRecord], threshold: f64) -> Option<NaiveDateTime> {
+    records
+        .iter()
+        .rev()
+        .take_while(|record| *record.humidity() > threshold)
+        .last()
+        .map(|record| *record.timestamp())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn records(now: NaiveDateTime, readings: &[(i64, f64)]) -> Vec<HumidityRecord> {
+        readings
+            .iter()
+            .map(|(minutes_ago, humidity)| {
+                HumidityRecord::new(
+                    now - chrono::Duration::minutes(*minutes_ago),
+                    "bathroom",
+                    *humidity,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn above_since_finds_start_of_latest_high_humidity() {
+        let now = Utc::now().naive_utc();
+        let history = records(now, &[(90, 85.0), (60, 70.0), (40, 82.0), (10, 90.0)]);
+        let history: Vec<_> = history.iter().collect();
+
+        assert_eq!(
+            above_since(&history, 80.0),
+            Some(now - chrono::Duration::minutes(40))
+        );
+        assert_eq!(above_since(&history[..2], 80.0), None);
+    }
+
+    #[test]
+    fn sustained_high_humidity_is_alerted_once() {
+        // Arrange
+        let now = Utc::now().naive_utc();
+        let mut monitor = HumidityMonitor::new(HumidityConfig {
+            threshold: 80.0,
+            duration_secs: 30 * 60,
+        });
+
+        // Act
+        let short = monitor.update(&records(now, &[(20, 85.0), (5, 86.0)]), now);
+        let sustained = monitor.update(&records(now, &[(40, 85.0), (5, 86.0)]), now);
+        let repeated = monitor.update(&records(now, &[(45, 85.0), (1, 87.0)]), now);
+
+        // Assert
+        assert!(short.is_empty());
+        assert_eq!(
+            sustained,
+            vec![HumidityAlert {
+                active: true,
+                place: "bathroom".to_string(),
+                humidity: 86.0,
+                threshold: 80.0,
+            }]
+        );
+        assert!(repeated.is_empty());
+    }
+
+    #[test]
+    fn alert_is_released_when_humidity_drops() {
+        // Arrange
+        let now = Utc::now().naive_utc();
+        let mut monitor = HumidityMonitor::new(HumidityConfig {
+            threshold: 80.0,
+            duration_secs: 30 * 60,
+        });
+        monitor.update(&records(now, &[(40, 85.0), (5, 86.0)]), now);
+
+        // Act
+        let alerts = monitor.update(&records(now, &[(40, 85.0), (1, 65.0)]), now);
+
+        // Assert
+        assert_eq!(alerts.len(), 1);
+        assert!(!alerts[0].active);
+        assert_eq!(alerts[0].humidity, 65.0);
+    }
+}
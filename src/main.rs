@@ -1,55 +1,280 @@
 use std::{
     fmt::{Debug, Display},
+    io::Write,
+    path::Path,
     sync::Arc,
+    time::Duration,
 };
 
-use tokio::{sync::Mutex, task::JoinError};
+use anyhow::Context;
+use chrono::NaiveDateTime;
+use clap::Parser;
+use futures_util::StreamExt;
+use tokio::{
+    sync::{watch, Mutex},
+    task::JoinError,
+};
 
+mod api;
+mod cli;
+mod config;
 mod controller;
 mod db;
+mod discovery;
+mod error;
+mod heartbeat;
+mod humidity;
 pub mod models;
+mod pid;
+mod retention;
+mod schedule;
+#[cfg(feature = "simulation")]
+mod simulation;
 mod telemetry;
+#[cfg(test)]
+mod test_broker;
+
+/// How long to wait for pending actions to be handled when shutting down.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let subscriber =
-        telemetry::create_minimal_subscriber("paletten_cloud_hub".to_string(), std::io::stdout);
+    let cli = cli::Cli::parse();
+    let log_level = telemetry::log_level_from_env();
+    let log_format = telemetry::log_format_from_env();
+    let subscriber = telemetry::create_minimal_subscriber(
+        "paletten_cloud_hub".to_string(),
+        log_level.clone().unwrap_or(telemetry::DEFAULT_LOG_LEVEL),
+        log_format.clone().unwrap_or_default(),
+        std::io::stdout,
+    );
     telemetry::init_subscriber(subscriber);
+    if let Err(value) = log_level {
+        tracing::warn!(value, "Invalid LOG_LEVEL, using the default log level");
+    }
+    if let Err(value) = log_format {
+        tracing::warn!(value, "Invalid LOG_FORMAT, using the default log format");
+    }
 
-    tracing::info!("Starting hub");
+    let config = load_config(&cli)?;
+    tracing::debug!(?config, "Loaded configuration");
+
+    match cli.command() {
+        cli::Command::Run => run(&cli, config).await,
+        cli::Command::Migrate => migrate(&config).await,
+        cli::Command::Maintenance => maintenance(&config).await,
+        cli::Command::Export { output, from, to } => export(&config, &output, from, to).await,
+        #[cfg(feature = "simulation")]
+        cli::Command::Simulate { file, speed } => simulate(config, &file, speed).await,
+    }
+}
+
+/// Apply the database migrations, which happens when connecting, even when
+/// they are skipped otherwise.
+async fn migrate(config: &config::Config) -> anyhow::Result<()> {
+    db::Database::connect(&db::DbConfig {
+        skip_migrations: false,
+        ..config.database.clone()
+    })
+    .await?;
+    tracing::info!("Migrations applied");
+
+    Ok(())
+}
+
+/// Prune the history older than the retention and vacuum the database, as
+/// the running hub does periodically.
+async fn maintenance(config: &config::Config) -> anyhow::Result<()> {
+    let database = Mutex::new(db::Database::connect(&config.database).await?);
+    retention::prune(&database, config.retention.retention()).await?;
+    retention::vacuum(&database).await
+}
+
+/// Write the readings taken from `from` until `to` to a CSV file at `output`,
+/// defaulting to all readings until now.
+async fn export(
+    config: &config::Config,
+    output: &Path,
+    from: Option<NaiveDateTime>,
+    to: Option<NaiveDateTime>,
+) -> anyhow::Result<()> {
+    let (from, to) = api::export_range(from, to)?;
+    let database = db::Database::connect(&config.database).await?;
+    let file = std::fs::File::create(output)
+        .with_context(|| format!("Failed to create {}", output.display()))?;
+    let mut writer = std::io::BufWriter::new(file);
+
+    writer.write_all(&api::csv_header())?;
+    let mut records = database.stream_history_between(from.as_ref(), &to);
+    let mut count = 0;
+    while let Some(record) = records.next().await {
+        let record = record.context("Failed to read history")?;
+        writer.write_all(&api::csv_row(&record)?)?;
+        count += 1;
+    }
+    writer.flush().context("Failed to write export")?;
+    tracing::info!(count, output = %output.display(), "Exported history");
+
+    Ok(())
+}
+
+/// Replay the readings in `file` through the control, `speed` times faster
+/// than they were recorded, logging the decisions about the heaters.
+#[cfg(feature = "simulation")]
+async fn simulate(config: config::Config, file: &Path, speed: f64) -> anyhow::Result<()> {
+    let readings = simulation::read_readings(file)?;
+    tracing::info!(count = readings.len(), speed, "Replaying readings");
+    let decisions =
+        tokio::task::spawn_blocking(move || simulation::run(&config, readings, speed)).await??;
+    tracing::info!(count = decisions.len(), "Simulation finished");
+
+    Ok(())
+}
+
+/// Load the configuration from the file given by `cli`, with the flags of
+/// `cli` taking precedence.
+fn load_config(cli: &cli::Cli) -> anyhow::Result<config::Config> {
+    let mut config = config::Config::load(&cli.config)?;
+    config.control.dry_run |= cli.dry_run;
+    config.database.skip_migrations |= cli.skip_migrations;
+
+    Ok(config)
+}
 
+/// Run the hub until it is stopped.
+async fn run(cli: &cli::Cli, config: config::Config) -> anyhow::Result<()> {
+    tracing::info!("Starting hub");
     let database = {
-        let db_connection_string = "sqlite:data/paletten.sqlite";
-        let db_pool = db::create_db_pool(db_connection_string).await?;
-        Arc::new(Mutex::new(db::Database::new(db_pool).await?))
+        let database = db::Database::connect(&config.database).await?;
+        for heater in config.heaters.iter() {
+            database.upsert_heater(heater).await?;
+        }
+        Arc::new(Mutex::new(database))
     };
 
-    let (mqtt_client, mqtt_eventloop) = controller::create_mqtt_handler();
-    let (controller, executor) = controller::create(mqtt_client, mqtt_eventloop, database).await?;
+    let (mqtt_client, mqtt_eventloop) = controller::create_mqtt_handler(&config.mqtt)?;
+    let heartbeat_task = tokio::spawn(heartbeat::run(
+        mqtt_client.clone(),
+        config.heartbeat.interval(),
+    ));
+    let humidity_task = tokio::spawn(humidity::run(
+        database.clone(),
+        mqtt_client.clone(),
+        config.humidity.clone(),
+    ));
+    let (controller, executor) = controller::create(
+        mqtt_client,
+        mqtt_eventloop,
+        database.clone(),
+        &config.mqtt,
+        &config.control,
+    )
+    .await?;
+    let executor = executor.with_config(config.redacted()?);
+    if cli.watch_config {
+        tracing::info!(path = %cli.config.display(), "Watching the configuration for changes");
+        let cli = cli.clone();
+        let actions = controller.actions();
+        tokio::spawn(async move {
+            let path = cli.config.clone();
+            if let Err(e) = config::watch(path, || load_config(&cli), actions).await {
+                tracing::error!(error = %e, "Stopped watching the configuration");
+            }
+        });
+    }
+    let retention_task = tokio::spawn(retention::run(
+        database.clone(),
+        config.retention.retention(),
+    ));
+    let app_state = api::AppState::new(
+        database,
+        controller.health(),
+        executor.db_health(),
+        controller.metrics(),
+        controller.actions(),
+        executor.snapshot(),
+        executor.events(),
+    )
+    .with_temperature_error(executor.temperature_error())
+    .with_timezone(config.timezone);
 
-    let controller_task = tokio::spawn(controller.run_until_completion());
-    let executor_task = tokio::spawn(executor.run_until_completion());
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let mut executor_task = tokio::spawn(executor.run_until_completion(shutdown_rx.clone()));
+    let mut controller_task = tokio::spawn(controller.run_until_completion(shutdown_rx));
+    let api_task = tokio::spawn(api::serve(config.http.clone(), app_state));
     let signal_task = tokio::signal::ctrl_c();
 
-    tokio::select! {
-        result = controller_task => report_exit("controller", result),
-        result = executor_task => report_exit("executor", result),
-        result = signal_task => report_exit("closed by user", Ok(result)),
+    let exit = tokio::select! {
+        result = &mut controller_task => report_exit("controller", result),
+        result = &mut executor_task => report_exit("executor", result),
+        result = api_task => report_exit("api", result),
+        result = retention_task => report_exit("retention", result),
+        result = heartbeat_task => report_exit("heartbeat", result),
+        result = humidity_task => report_exit("humidity", result),
+        result = signal_task => {
+            let exit = report_exit("closed by user", Ok(result));
+            shutdown(shutdown_tx, controller_task, executor_task).await;
+            exit
+        }
     };
 
-    Ok(())
+    match exit {
+        Exit::Clean => Ok(()),
+        Exit::Failed => Err(anyhow::anyhow!("The hub stopped because a task failed")),
+    }
+}
+
+/// Signal the controller to stop producing actions and wait for the executor
+/// to handle the ones already buffered, giving up after `SHUTDOWN_TIMEOUT`.
+async fn shutdown(
+    shutdown_tx: watch::Sender<bool>,
+    controller_task: tokio::task::JoinHandle<anyhow::Result<()>>,
+    executor_task: tokio::task::JoinHandle<anyhow::Result<()>>,
+) {
+    tracing::info!("Shutting down, draining pending actions");
+    if shutdown_tx.send(true).is_err() {
+        tracing::warn!("Controller has already exited");
+    }
+
+    match tokio::time::timeout(SHUTDOWN_TIMEOUT, async {
+        tokio::join!(executor_task, controller_task)
+    })
+    .await
+    {
+        Ok((executor_result, controller_result)) => {
+            report_exit("executor", executor_result);
+            report_exit("controller", controller_result);
+        }
+        Err(_) => tracing::warn!("Timed out waiting for pending actions to be handled"),
+    }
 }
 
-fn report_exit(task_name: &str, outcome: Result<Result<(), impl Debug + Display>, JoinError>) {
+/// How the hub stopped, which decides the exit code of the process, so a
+/// supervisor can restart it after a failure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Exit {
+    Clean,
+    Failed,
+}
+
+/// Log how a task ended, which failed when it returned an error or panicked.
+fn report_exit(
+    task_name: &str,
+    outcome: Result<Result<(), impl Debug + Display>, JoinError>,
+) -> Exit {
     match outcome {
-        Ok(Ok(())) => tracing::info!("{} has exited", task_name),
+        Ok(Ok(())) => {
+            tracing::info!("{} has exited", task_name);
+            Exit::Clean
+        }
         Ok(Err(e)) => {
             tracing::error!(
                 error.cause_chain = ?e,
                 error.message = %e,
                 "{} failed",
                 task_name
-            )
+            );
+            Exit::Failed
         }
         Err(e) => {
             tracing::error!(
@@ -57,7 +282,37 @@ fn report_exit(task_name: &str, outcome: Result<Result<(), impl Debug + Display>
                 error.message = %e,
                 "{}' task failed to complete",
                 task_name
-            )
+            );
+            Exit::Failed
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn clean_exit_of_task() {
+        assert_eq!(
+            report_exit("closed by user", Ok(Ok::<_, std::io::Error>(()))),
+            Exit::Clean
+        );
+    }
+
+    #[test]
+    fn failed_exit_when_task_returns_error() {
+        let outcome = Ok(Err(anyhow::anyhow!("connection lost")));
+
+        assert_eq!(report_exit("controller", outcome), Exit::Failed);
+    }
+
+    #[tokio::test]
+    async fn failed_exit_when_task_panics() {
+        let outcome = tokio::spawn(async { panic!("executor crashed") })
+            .await
+            .map(|()| Ok::<_, anyhow::Error>(()));
+
+        assert_eq!(report_exit("executor", outcome), Exit::Failed);
+    }
+}
@@ -1,30 +1,564 @@
+use chrono::{DateTime, Utc};
 use derive_getters::Getters;
 
-/// Describes the states a heater can be on.
-#[derive(Debug, Clone, Copy, strum::AsRefStr, strum::EnumString, strum::Display, sqlx::Type)]
+use crate::error::HubError;
+
+/// Describes the states a heater can be on. `Unknown` is used until a relay
+/// has reported its state, and is never persisted.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    strum::AsRefStr,
+    strum::EnumString,
+    strum::Display,
+    sqlx::Type,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
 #[repr(u8)]
 pub enum HeaterState {
     #[strum(serialize = "off")]
     Off = 0,
     #[strum(serialize = "on")]
     On = 1,
+    #[strum(serialize = "unknown")]
+    Unknown = 2,
+}
+
+/// The state the controller wants a heater to be in. `Idle` is used when the
+/// automated temperature control is disabled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TargetState {
+    On,
+    Off,
+    Idle,
+}
+
+impl TargetState {
+    /// The state the heater should be commanded to, if any.
+    pub fn heater_state(self) -> Option<HeaterState> {
+        match self {
+            Self::On => Some(HeaterState::On),
+            Self::Off => Some(HeaterState::Off),
+            Self::Idle => None,
+        }
+    }
+}
+
+impl TargetState {
+    /// The target for turning a heater to `state`, if it is known.
+    pub fn from_heater_state(state: HeaterState) -> Option<Self> {
+        match state {
+            HeaterState::On => Some(Self::On),
+            HeaterState::Off => Some(Self::Off),
+            HeaterState::Unknown => None,
+        }
+    }
+}
+
+/// Operating mode of the hub. In `Away` and `Eco` mode the desired
+/// temperatures are lowered by an offset to save energy.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, strum::EnumString, strum::Display, serde::Serialize,
+)]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    #[default]
+    Home,
+    Away,
+    Eco,
 }
 
-#[derive(Debug, Getters)]
+/// Where a change of the desired temperature came from.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    strum::AsRefStr,
+    strum::Display,
+    strum::EnumString,
+    sqlx::Type,
+    serde::Serialize,
+)]
+#[strum(serialize_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum SetpointSource {
+    Mqtt,
+    Http,
+    Schedule,
+}
+
+/// Status published for a heater, describing the decision of the controller.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct HeaterStatus {
+    pub state: TargetState,
+    pub desired_temperature: Option<f64>,
+    pub current_temperature: Option<f64>,
+}
+
+/// Alert published when a place governing heaters exceeds the safety ceiling,
+/// and again when it is released.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct TemperatureAlert {
+    pub active: bool,
+    pub place: String,
+    pub temperature: f64,
+    pub ceiling: f64,
+}
+
+/// Alert published when a relay did not confirm the state it was commanded to
+/// in time.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct HeaterAlert {
+    pub heater_id: String,
+    pub state: HeaterState,
+    pub timeout_secs: u64,
+}
+
+/// Alert published when the humidity of a place has stayed above the
+/// threshold for too long, and again when it drops below it.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct HumidityAlert {
+    pub active: bool,
+    pub place: String,
+    pub humidity: f64,
+    pub threshold: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize, Getters)]
 pub struct Heater {
-    #[allow(unused)]
     name: String,
     id: String,
+    /// The measurement place whose temperature governs this heater.
+    #[serde(default = "default_place")]
+    place: String,
+    /// The firmware of the relay, which decides how it is commanded.
+    #[serde(default)]
+    firmware: Firmware,
+    /// The rated power in watts, to estimate the energy used by a heater
+    /// without a power meter.
+    #[serde(default)]
+    watts: Option<f64>,
+}
+
+fn default_place() -> String {
+    "inside".to_string()
 }
 
 impl Heater {
-    pub fn new(id: String, name: String) -> Self {
-        Self { id, name }
+    pub fn new(id: String, name: String, place: String) -> Self {
+        Self {
+            id,
+            name,
+            place,
+            firmware: Firmware::default(),
+            watts: None,
+        }
+    }
+
+    /// Use a relay with the given firmware.
+    pub fn with_firmware(self, firmware: Firmware) -> Self {
+        Self { firmware, ..self }
+    }
+
+    /// Set the rated power of the heater in watts.
+    pub fn with_watts(self, watts: Option<f64>) -> Self {
+        Self { watts, ..self }
     }
 }
 
+/// The firmware of the relay switching a heater, which decides the topic and
+/// payload of its commands.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    strum::AsRefStr,
+    strum::Display,
+    strum::EnumString,
+    serde::Deserialize,
+    serde::Serialize,
+)]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum Firmware {
+    /// First generation Shellies, commanded with `on` or `off` on
+    /// `shellies/shelly1-<id>/relay/0/command`.
+    #[default]
+    Gen1,
+    /// Shelly Plus devices, commanded with `{"id":0,"on":true}` on
+    /// `shellyplus1-<id>/rpc`, which report their state as JSON on
+    /// `shellyplus1-<id>/status/switch:0`.
+    Plus,
+}
+
+impl Firmware {
+    /// The topic the commands of relay `id` are published to.
+    pub fn command_topic(&self, id: &str) -> String {
+        match self {
+            Self::Gen1 => format!("shellies/shelly1-{id}/relay/0/command"),
+            Self::Plus => format!("shellyplus1-{id}/rpc"),
+        }
+    }
+
+    /// The topic the state of relay `id` is received on.
+    pub fn state_topic(&self, id: &str) -> String {
+        match self {
+            Self::Gen1 => format!("shellies/shelly1-{id}/relay/0"),
+            Self::Plus => format!("shellyplus1-{id}/status/switch:0"),
+        }
+    }
+
+    /// The topic and payload asking relay `id` to report its state.
+    pub fn state_request(&self, id: &str) -> (String, &'static str) {
+        match self {
+            Self::Gen1 => (format!("shellies/shelly1-{id}/command"), "update"),
+            Self::Plus => (format!("shellyplus1-{id}/command"), "status_update"),
+        }
+    }
+
+    /// The payload of the command switching the relay to `state`.
+    pub fn command_payload(&self, state: HeaterState) -> String {
+        match self {
+            Self::Gen1 => state.to_string(),
+            Self::Plus => {
+                serde_json::json!({ "id": 0, "on": state == HeaterState::On }).to_string()
+            }
+        }
+    }
+}
+
+/// A reading published by a sensor. Besides the hub's own format, the
+/// payloads of Zigbee2MQTT are accepted, whose thermostats report
+/// `local_temperature` and whose devices report `last_seen` as an ISO 8601
+/// timestamp. Fields not used by the hub, like `linkquality`, are ignored.
 #[derive(Debug, Clone, Copy, serde::Deserialize, Getters)]
 pub struct Measurement {
+    #[serde(alias = "local_temperature")]
     temperature: f64,
-    humidity: f64,
+    /// Relative humidity in percent, if the sensor reports it.
+    #[serde(default)]
+    humidity: Option<f64>,
+    /// Battery level of the sensor in percent, if it reports it.
+    #[serde(default)]
+    battery: Option<f64>,
+    /// When the measurement was taken by the sensor, if it reports it.
+    #[serde(default, alias = "last_seen")]
+    timestamp: Option<DateTime<Utc>>,
+}
+
+impl Measurement {
+    pub fn new(temperature: f64, humidity: f64) -> Self {
+        Self {
+            temperature,
+            humidity: Some(humidity),
+            battery: None,
+            timestamp: None,
+        }
+    }
+
+    /// A measurement of a sensor reporting only the temperature, which is
+    /// stored without a humidity.
+    pub fn from_temperature(temperature: f64) -> Self {
+        Self {
+            temperature,
+            humidity: None,
+            battery: None,
+            timestamp: None,
+        }
+    }
+
+    /// Fail if the temperature or humidity is outside of `bounds`.
+    pub fn validate(&self, bounds: &MeasurementBounds) -> Result<(), HubError> {
+        bounds.validate_temperature(self.temperature)?;
+        if let Some(humidity) = self.humidity {
+            if !(bounds.min_humidity..=bounds.max_humidity).contains(&humidity) {
+                return Err(HubError::validation(format!(
+                    "Humidity {humidity}% is outside of {}..{}%",
+                    bounds.min_humidity, bounds.max_humidity
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// The measurement corrected by the offsets of `calibration`. The
+    /// humidity is kept within 0..100%.
+    pub fn calibrated(self, calibration: &Calibration) -> Self {
+        Self {
+            temperature: self.temperature + calibration.temperature,
+            humidity: self
+                .humidity
+                .map(|humidity| (humidity + calibration.humidity).clamp(0.0, 100.0)),
+            ..self
+        }
+    }
+}
+
+/// Offsets added to the readings of a sensor, to correct one that reads high
+/// or low compared to a reference.
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct Calibration {
+    /// Offset in °C added to the temperature.
+    pub temperature: f64,
+    /// Offset in percentage points added to the humidity.
+    pub humidity: f64,
+}
+
+/// The range of values a sensor can physically report. Readings outside of
+/// it are from a glitching sensor and are rejected.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct MeasurementBounds {
+    pub min_temperature: f64,
+    pub max_temperature: f64,
+    pub min_humidity: f64,
+    pub max_humidity: f64,
+}
+
+impl Default for MeasurementBounds {
+    fn default() -> Self {
+        Self {
+            min_temperature: -50.0,
+            max_temperature: 60.0,
+            min_humidity: 0.0,
+            max_humidity: 100.0,
+        }
+    }
+}
+
+impl MeasurementBounds {
+    /// Fail if `temperature` is outside of the bounds.
+    pub fn validate_temperature(&self, temperature: f64) -> Result<(), HubError> {
+        if !(self.min_temperature..=self.max_temperature).contains(&temperature) {
+            return Err(HubError::validation(format!(
+                "Temperature {temperature}°C is outside of {}..{}°C",
+                self.min_temperature, self.max_temperature
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn measurement(temperature: f64, humidity: f64) -> Measurement {
+        Measurement {
+            temperature,
+            humidity: Some(humidity),
+            battery: None,
+            timestamp: None,
+        }
+    }
+
+    #[test]
+    fn gen1_command_is_plain_state() {
+        let firmware = Firmware::Gen1;
+
+        assert_eq!(
+            firmware.command_topic("C4402D"),
+            "shellies/shelly1-C4402D/relay/0/command"
+        );
+        assert_eq!(firmware.command_payload(HeaterState::On), "on");
+        assert_eq!(firmware.command_payload(HeaterState::Off), "off");
+    }
+
+    #[test]
+    fn state_topics_and_requests_depend_on_firmware() {
+        assert_eq!(
+            Firmware::Gen1.state_topic("C4402D"),
+            "shellies/shelly1-C4402D/relay/0"
+        );
+        assert_eq!(
+            Firmware::Gen1.state_request("C4402D"),
+            ("shellies/shelly1-C4402D/command".to_string(), "update")
+        );
+        assert_eq!(
+            Firmware::Plus.state_topic("C4402D"),
+            "shellyplus1-C4402D/status/switch:0"
+        );
+        assert_eq!(
+            Firmware::Plus.state_request("C4402D"),
+            ("shellyplus1-C4402D/command".to_string(), "status_update")
+        );
+    }
+
+    #[test]
+    fn plus_command_is_json() {
+        let firmware = Firmware::Plus;
+
+        assert_eq!(firmware.command_topic("C4402D"), "shellyplus1-C4402D/rpc");
+        assert_eq!(
+            firmware.command_payload(HeaterState::On),
+            r#"{"id":0,"on":true}"#
+        );
+        assert_eq!(
+            firmware.command_payload(HeaterState::Off),
+            r#"{"id":0,"on":false}"#
+        );
+    }
+
+    #[test]
+    fn measurement_within_bounds_is_valid() {
+        let bounds = MeasurementBounds::default();
+
+        for (temperature, humidity) in [(21.4, 58.3), (-50.0, 0.0), (60.0, 100.0)] {
+            assert!(measurement(temperature, humidity).validate(&bounds).is_ok());
+        }
+    }
+
+    #[test]
+    fn measurement_outside_bounds_is_rejected() {
+        let bounds = MeasurementBounds::default();
+
+        for (temperature, humidity) in [(-400.0, 50.0), (60.1, 50.0), (20.0, -1.0), (20.0, 101.0)] {
+            assert!(matches!(
+                measurement(temperature, humidity).validate(&bounds),
+                Err(HubError::Validation(_))
+            ));
+        }
+        assert!(measurement(f64::NAN, 50.0).validate(&bounds).is_err());
+    }
+
+    #[test]
+    fn measurement_bounds_are_configurable() {
+        let bounds = MeasurementBounds {
+            max_temperature: 30.0,
+            ..Default::default()
+        };
+
+        assert!(measurement(35.0, 50.0).validate(&bounds).is_err());
+    }
+
+    #[test]
+    fn calibration_offsets_measurement() {
+        let calibration = Calibration {
+            temperature: -0.8,
+            humidity: 3.0,
+        };
+
+        let calibrated = measurement(21.8, 98.5).calibrated(&calibration);
+
+        assert!((calibrated.temperature - 21.0).abs() < 1e-9);
+        assert_eq!(calibrated.humidity, Some(100.0));
+    }
+
+    #[test]
+    fn measurement_deserializes_without_timestamp() {
+        let measurement: Measurement =
+            serde_json::from_str(r#"{"temperature":21.4,"humidity":58.3}"#).unwrap();
+
+        assert_eq!(*measurement.temperature(), 21.4);
+        assert_eq!(*measurement.humidity(), Some(58.3));
+        assert_eq!(*measurement.battery(), None);
+        assert_eq!(*measurement.timestamp(), None);
+    }
+
+    #[test]
+    fn measurement_deserializes_with_battery() {
+        let measurement: Measurement =
+            serde_json::from_str(r#"{"temperature":-2.5,"humidity":80.0,"battery":17.0}"#).unwrap();
+
+        assert_eq!(*measurement.battery(), Some(17.0));
+    }
+
+    #[test]
+    fn measurement_deserializes_with_timestamp() {
+        let measurement: Measurement = serde_json::from_str(
+            r#"{"temperature":21.4,"humidity":58.3,"timestamp":"2024-01-05T07:30:00Z"}"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            measurement.timestamp().map(|t| t.to_rfc3339()),
+            Some("2024-01-05T07:30:00+00:00".to_string())
+        );
+    }
+
+    #[test]
+    fn measurement_deserializes_with_extra_fields() {
+        let measurement: Measurement = serde_json::from_str(
+            r#"{"temperature":21.3,"humidity":55,"battery":90,"linkquality":120}"#,
+        )
+        .unwrap();
+
+        assert_eq!(*measurement.temperature(), 21.3);
+        assert_eq!(*measurement.humidity(), Some(55.0));
+        assert_eq!(*measurement.battery(), Some(90.0));
+    }
+
+    #[test]
+    fn measurement_deserializes_zigbee2mqtt_keys() {
+        let measurement: Measurement = serde_json::from_str(
+            r#"{"local_temperature":20.5,"humidity":48,"linkquality":87,"last_seen":"2024-01-05T07:30:00+01:00"}"#,
+        )
+        .unwrap();
+
+        assert_eq!(*measurement.temperature(), 20.5);
+        assert_eq!(*measurement.humidity(), Some(48.0));
+        assert_eq!(
+            measurement.timestamp().map(|t| t.to_rfc3339()),
+            Some("2024-01-05T06:30:00+00:00".to_string())
+        );
+    }
+
+    #[test]
+    fn heater_state_round_trips_through_strings() {
+        for state in [HeaterState::Off, HeaterState::On, HeaterState::Unknown] {
+            assert_eq!(state.to_string().parse::<HeaterState>().unwrap(), state);
+        }
+        assert_eq!(HeaterState::Unknown.to_string(), "unknown");
+    }
+
+    #[test]
+    fn unknown_heater_state_has_no_target() {
+        assert_eq!(TargetState::from_heater_state(HeaterState::Unknown), None);
+        assert_eq!(
+            TargetState::from_heater_state(HeaterState::On),
+            Some(TargetState::On)
+        );
+    }
+
+    #[test]
+    fn heater_status_serializes_to_json() {
+        let status = HeaterStatus {
+            state: TargetState::On,
+            desired_temperature: Some(21.5),
+            current_temperature: Some(19.0),
+        };
+
+        assert_eq!(
+            serde_json::to_string(&status).unwrap(),
+            r#"{"state":"on","desired_temperature":21.5,"current_temperature":19.0}"#
+        );
+    }
+
+    #[test]
+    fn idle_heater_status_serializes_to_json() {
+        let status = HeaterStatus {
+            state: TargetState::Idle,
+            desired_temperature: None,
+            current_temperature: Some(19.0),
+        };
+
+        assert_eq!(
+            serde_json::to_string(&status).unwrap(),
+            r#"{"state":"idle","desired_temperature":null,"current_temperature":19.0}"#
+        );
+    }
 }
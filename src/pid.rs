@@ -0,0 +1,179 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::models::HeaterState;
+
+/// A PID controller computing a duty cycle between 0 and 1 from the error
+/// between the desired and current temperature.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PidController {
+    kp: f64,
+    ki: f64,
+    kd: f64,
+    integral: f64,
+    last_error: Option<f64>,
+}
+
+impl PidController {
+    pub fn new(kp: f64, ki: f64, kd: f64) -> Self {
+        Self {
+            kp,
+            ki,
+            kd,
+            integral: 0.0,
+            last_error: None,
+        }
+    }
+
+    /// Update the controller with the error measured `dt` seconds after the
+    /// previous one and get the resulting duty cycle. The integral term is
+    /// limited to the output range to avoid winding up while saturated.
+    pub fn update(&mut self, error: f64, dt: f64) -> f64 {
+        self.integral += error * dt;
+        if self.ki > 0.0 {
+            self.integral = self.integral.clamp(0.0, 1.0 / self.ki);
+        }
+        let derivative = match self.last_error {
+            Some(last_error) if dt > 0.0 => (error - last_error) / dt,
+            _ => 0.0,
+        };
+        self.last_error = Some(error);
+
+        (self.kp * error + self.ki * self.integral + self.kd * derivative).clamp(0.0, 1.0)
+    }
+}
+
+/// Drives a relay with time-proportional control: every window the PID
+/// controller computes a duty cycle, and the relay is on for that fraction of
+/// the window.
+#[derive(Debug, Clone)]
+pub struct TimeProportional {
+    pid: PidController,
+    window: Duration,
+    window_start: Option<Instant>,
+    duty: f64,
+}
+
+impl TimeProportional {
+    pub fn new(pid: PidController, window: Duration) -> Self {
+        Self {
+            pid,
+            window,
+            window_start: None,
+            duty: 0.0,
+        }
+    }
+
+    /// Get the state the relay should be in at `now`, starting a new window
+    /// with an updated duty cycle when the previous window has ended.
+    pub fn state(&mut self, error: f64, now: Instant) -> HeaterState {
+        let window_ended = match self.window_start {
+            Some(start) => now >= start + self.window,
+            None => true,
+        };
+        if window_ended {
+            self.window_start = Some(now);
+            self.duty = self.pid.update(error, self.window.as_secs_f64());
+            tracing::debug!(error, duty = self.duty, "Started new PID window");
+        }
+
+        if now < self.on_until() {
+            HeaterState::On
+        } else {
+            HeaterState::Off
+        }
+    }
+
+    /// When the relay next has to be switched, which is either the end of the
+    /// on period or the start of the next window.
+    pub fn next_switch(&self, now: Instant) -> Option<Instant> {
+        let start = self.window_start?;
+        let on_until = self.on_until();
+        Some(if now < on_until {
+            on_until
+        } else {
+            start + self.window
+        })
+    }
+
+    fn on_until(&self) -> Instant {
+        let start = self.window_start.unwrap_or_else(Instant::now);
+        start + self.window.mul_f64(self.duty)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "expected {expected}, got {actual}"
+        );
+    }
+
+    #[test]
+    fn proportional_output() {
+        let mut pid = PidController::new(0.5, 0.0, 0.0);
+
+        assert_close(pid.update(1.0, 60.0), 0.5);
+        assert_close(pid.update(0.5, 60.0), 0.25);
+        assert_close(pid.update(-1.0, 60.0), 0.0);
+        assert_close(pid.update(4.0, 60.0), 1.0);
+    }
+
+    #[test]
+    fn integral_accumulates_error() {
+        let mut pid = PidController::new(0.0, 0.001, 0.0);
+
+        assert_close(pid.update(1.0, 100.0), 0.1);
+        assert_close(pid.update(1.0, 100.0), 0.2);
+        assert_close(pid.update(-0.5, 100.0), 0.15);
+    }
+
+    #[test]
+    fn integral_does_not_wind_up() {
+        let mut pid = PidController::new(0.0, 0.01, 0.0);
+        for _ in 0..10 {
+            pid.update(5.0, 100.0);
+        }
+
+        assert_close(pid.update(-1.0, 10.0), 0.9);
+    }
+
+    #[test]
+    fn derivative_dampens_change() {
+        let mut pid = PidController::new(0.5, 0.0, 10.0);
+
+        assert_close(pid.update(1.0, 60.0), 0.5);
+        // Error decreasing by 0.6 over 60 s gives a derivative of -0.01.
+        assert_close(pid.update(0.4, 60.0), 0.1);
+    }
+
+    #[test]
+    fn time_proportional_on_for_duty_of_window() {
+        let window = Duration::from_secs(600);
+        let mut relay = TimeProportional::new(PidController::new(0.25, 0.0, 0.0), window);
+        let start = Instant::now();
+
+        assert_eq!(relay.state(2.0, start), HeaterState::On);
+        assert_eq!(relay.next_switch(start), Some(start + window / 2));
+        assert_eq!(
+            relay.state(0.0, start + Duration::from_secs(299)),
+            HeaterState::On
+        );
+        assert_eq!(
+            relay.state(0.0, start + Duration::from_secs(300)),
+            HeaterState::Off
+        );
+        assert_eq!(
+            relay.next_switch(start + Duration::from_secs(300)),
+            Some(start + window)
+        );
+
+        // A new window starts with the error at that time.
+        assert_eq!(relay.state(0.0, start + window), HeaterState::Off);
+    }
+}
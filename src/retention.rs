@@ -0,0 +1,167 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Context, Result};
+use tokio::{
+    sync::Mutex,
+    time::{Instant, MissedTickBehavior},
+};
+
+use crate::db::Database;
+
+const DEFAULT_RETENTION_DAYS: u64 = 90;
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// The longest history is kept, so the retention in seconds does not overflow
+/// and the time before which history is pruned can be represented.
+const MAX_RETENTION_DAYS: u64 = 100 * 365;
+
+/// How often old history is pruned.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How often the database is vacuumed, to shrink it after pruning.
+const VACUUM_INTERVAL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Configuration of how long history is kept.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct RetentionConfig {
+    pub days: u64,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self {
+            days: DEFAULT_RETENTION_DAYS,
+        }
+    }
+}
+
+impl RetentionConfig {
+    /// Override the configuration with the `HISTORY_RETENTION_DAYS`
+    /// environment variable found by `lookup`.
+    pub fn with_env_overrides(self, lookup: &impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let days = lookup("HISTORY_RETENTION_DAYS")
+            .map(|days| {
+                days.parse::<u64>()
+                    .context("HISTORY_RETENTION_DAYS is not a valid number of days")
+            })
+            .transpose()?
+            .unwrap_or(self.days);
+        if days == 0 {
+            return Err(anyhow!("History must be kept for at least 1 day"));
+        }
+        if days > MAX_RETENTION_DAYS {
+            return Err(anyhow!(
+                "History can be kept for at most {MAX_RETENTION_DAYS} days"
+            ));
+        }
+
+        Ok(Self { days })
+    }
+
+    /// How long history is kept.
+    pub fn retention(&self) -> Duration {
+        Duration::from_secs(self.days.saturating_mul(SECS_PER_DAY))
+    }
+}
+
+/// Periodically delete history older than `retention`, and vacuum the
+/// database every `VACUUM_INTERVAL`.
+pub async fn run(db: Arc<Mutex<Database>>, retention: Duration) -> Result<()> {
+    let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    let mut vacuum_interval =
+        tokio::time::interval_at(Instant::now() + VACUUM_INTERVAL, VACUUM_INTERVAL);
+    vacuum_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                if let Err(e) = prune(&db, retention).await {
+                    tracing::error!(error = %e, "Failed to prune history");
+                }
+            }
+            _ = vacuum_interval.tick() => {
+                if let Err(e) = vacuum(&db).await {
+                    tracing::error!(error = %e, "Failed to vacuum database");
+                }
+            }
+        }
+    }
+}
+
+#[tracing::instrument(skip(db))]
+pub async fn prune(db: &Mutex<Database>, retention: Duration) -> Result<()> {
+    let db = db.lock().await;
+    let readings = db.prune_history_older_than(retention).await?;
+    let heater_states = db.prune_heater_history_older_than(retention).await?;
+    tracing::info!(readings, heater_states, "Pruned old history");
+
+    Ok(())
+}
+
+/// Vacuum the database, logging its size before and after.
+#[tracing::instrument(skip(db))]
+pub async fn vacuum(db: &Mutex<Database>) -> Result<()> {
+    let db = db.lock().await;
+    let size_before = db.size().await?;
+    db.vacuum().await?;
+    let size_after = db.size().await?;
+    tracing::info!(size_before, size_after, "Vacuumed database");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn lookup(days: &str) -> impl Fn(&str) -> Option<String> + '_ {
+        move |key| (key == "HISTORY_RETENTION_DAYS").then(|| days.to_string())
+    }
+
+    #[test]
+    fn retention_defaults_to_ninety_days() {
+        assert_eq!(
+            RetentionConfig::default().retention(),
+            Duration::from_secs(90 * 24 * 60 * 60)
+        );
+    }
+
+    #[test]
+    fn retention_from_env() {
+        let config = RetentionConfig::default()
+            .with_env_overrides(&lookup("7"))
+            .unwrap();
+
+        assert_eq!(config.retention(), Duration::from_secs(7 * 24 * 60 * 60));
+        assert!(RetentionConfig::default()
+            .with_env_overrides(&lookup("a week"))
+            .is_err());
+    }
+
+    #[test]
+    fn retention_rejects_zero_days() {
+        assert!(RetentionConfig::default()
+            .with_env_overrides(&lookup("0"))
+            .is_err());
+        assert!(RetentionConfig { days: 0 }
+            .with_env_overrides(&|_| None)
+            .is_err());
+    }
+
+    #[test]
+    fn retention_rejects_overflowing_days() {
+        let days = (u64::MAX / SECS_PER_DAY + 1).to_string();
+
+        assert!(RetentionConfig::default()
+            .with_env_overrides(&lookup(&days))
+            .is_err());
+        assert!(RetentionConfig::default()
+            .with_env_overrides(&lookup(&(MAX_RETENTION_DAYS + 1).to_string()))
+            .is_err());
+        assert!(RetentionConfig::default()
+            .with_env_overrides(&lookup(&MAX_RETENTION_DAYS.to_string()))
+            .is_ok());
+    }
+}
@@ -0,0 +1,120 @@
+use chrono::{DateTime, NaiveTime, TimeZone};
+use derive_getters::Getters;
+
+/// An entry in the heating schedule, setting the desired temperature from the
+/// given time of day until the next entry.
+#[derive(Debug, Clone, PartialEq, Getters)]
+pub struct ScheduleEntry {
+    time_of_day: NaiveTime,
+    desired_temperature: f64,
+}
+
+impl ScheduleEntry {
+    pub fn new(time_of_day: NaiveTime, desired_temperature: f64) -> Self {
+        Self {
+            time_of_day,
+            desired_temperature,
+        }
+    }
+
+    /// When the entry last started at or before `now`, which is the day
+    /// before if its time of day has not been reached yet today.
+    pub fn last_start<Tz: TimeZone>(&self, now: &DateTime<Tz>) -> Option<DateTime<Tz>> {
+        let date = if self.time_of_day <= now.time() {
+            now.date_naive()
+        } else {
+            now.date_naive().pred_opt()?
+        };
+        date.and_time(self.time_of_day)
+            .and_local_timezone(now.timezone())
+            .earliest()
+    }
+}
+
+/// Daily heating schedule with the entries sorted by their time of day.
+#[derive(Debug, Clone, Default)]
+pub struct Schedule {
+    entries: Vec<ScheduleEntry>,
+}
+
+impl Schedule {
+    pub fn new(mut entries: Vec<ScheduleEntry>) -> Self {
+        entries.sort_by_key(|entry| entry.time_of_day);
+        Self { entries }
+    }
+
+    /// Find the entry active at `time`, which is the latest entry starting at
+    /// or before it. Before the first entry of the day, the last entry of the
+    /// previous day is still active.
+    pub fn active_entry(&self, time: NaiveTime) -> Option<&ScheduleEntry> {
+        self.entries
+            .iter()
+            .rev()
+            .find(|entry| entry.time_of_day <= time)
+            .or_else(|| self.entries.last())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::Utc;
+
+    use super::*;
+
+    fn time(hour: u32, minute: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    fn schedule() -> Schedule {
+        Schedule::new(vec![
+            ScheduleEntry::new(time(22, 0), 17.0),
+            ScheduleEntry::new(time(6, 30), 21.0),
+            ScheduleEntry::new(time(9, 0), 19.0),
+        ])
+    }
+
+    #[test]
+    fn active_entry_is_latest_started_entry() {
+        let schedule = schedule();
+
+        assert_eq!(
+            schedule.active_entry(time(7, 15)),
+            Some(&ScheduleEntry::new(time(6, 30), 21.0))
+        );
+        assert_eq!(
+            schedule.active_entry(time(9, 0)),
+            Some(&ScheduleEntry::new(time(9, 0), 19.0))
+        );
+    }
+
+    #[test]
+    fn active_entry_wraps_around_midnight() {
+        let schedule = schedule();
+
+        assert_eq!(
+            schedule.active_entry(time(2, 0)),
+            Some(&ScheduleEntry::new(time(22, 0), 17.0))
+        );
+        assert_eq!(
+            schedule.active_entry(time(23, 59)),
+            Some(&ScheduleEntry::new(time(22, 0), 17.0))
+        );
+    }
+
+    #[test]
+    fn last_start_is_today_or_yesterday() {
+        let entry = ScheduleEntry::new(time(6, 30), 21.0);
+        let at = |day: u32, hour: u32, minute: u32| {
+            Utc.with_ymd_and_hms(2024, 1, day, hour, minute, 0).unwrap()
+        };
+
+        assert_eq!(entry.last_start(&at(10, 7, 0)), Some(at(10, 6, 30)));
+        assert_eq!(entry.last_start(&at(10, 6, 30)), Some(at(10, 6, 30)));
+        assert_eq!(entry.last_start(&at(10, 5, 0)), Some(at(9, 6, 30)));
+    }
+
+    #[test]
+    fn active_entry_without_entries() {
+        assert_eq!(Schedule::default().active_entry(time(12, 0)), None);
+    }
+}
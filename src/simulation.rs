@@ -0,0 +1,424 @@
+use std::{collections::HashMap, io::Read, path::Path, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{NaiveDateTime, Timelike};
+use rumqttc::v5::{AsyncClient, MqttOptions, Request};
+use tokio::{
+    sync::{
+        mpsc::{Sender, WeakSender},
+        watch, Mutex,
+    },
+    time::Instant,
+};
+
+use crate::{
+    config::Config,
+    controller::{self, Action, ControlConfig},
+    db::{Database, DbConfig},
+    models::{Heater, HeaterState, Measurement},
+};
+
+/// How many times faster than recorded the readings are replayed by default,
+/// which is an hour per second.
+pub const DEFAULT_SPEED: f64 = 3600.0;
+
+/// How far the paused clock is advanced at most at once.
+const CLOCK_STEP: Duration = Duration::from_millis(100);
+
+/// A reading recorded at `timestamp`, in the format of the exported history.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+pub struct Reading {
+    pub timestamp: NaiveDateTime,
+    pub location: String,
+    pub temperature: f64,
+    /// Empty for readings recorded without a humidity.
+    pub humidity: Option<f64>,
+}
+
+/// A heater being switched by the control, at the time of the recording.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Decision {
+    pub timestamp: NaiveDateTime,
+    pub heater_id: String,
+    pub state: HeaterState,
+}
+
+/// Read the readings of the CSV file at `path`, ordered by their timestamp.
+pub fn read_readings(path: &Path) -> Result<Vec<Reading>> {
+    let file =
+        std::fs::File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+
+    parse_readings(file)
+}
+
+fn parse_readings(reader: impl Read) -> Result<Vec<Reading>> {
+    let mut readings = csv::Reader::from_reader(reader)
+        .deserialize()
+        .collect::<Result<Vec<Reading>, _>>()
+        .context("Failed to read readings")?;
+    readings.sort_by_key(|reading| reading.timestamp);
+
+    Ok(readings)
+}
+
+/// Replay `readings` `speed` times faster than they were recorded, on a
+/// runtime of its own.
+pub fn run(config: &Config, readings: Vec<Reading>, speed: f64) -> Result<Vec<Decision>> {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .context("Failed to start the simulation runtime")?
+        .block_on(replay(config, readings, speed))
+}
+
+/// Feed `readings` to an executor running the control of `config` against an
+/// in-memory database, with the relays of its heaters mocked, and return the
+/// decisions it made. The clock of the runtime is paused and advanced to the
+/// timestamp of each reading, so the timers of the control, like the minimum
+/// dwell time, follow the time of the recording.
+async fn replay(config: &Config, readings: Vec<Reading>, speed: f64) -> Result<Vec<Decision>> {
+    if speed.is_nan() || speed <= 0.0 {
+        return Err(anyhow!("The speed of the simulation must be positive"));
+    }
+    let Some(start) = readings.first().map(|reading| reading.timestamp) else {
+        return Ok(Vec::new());
+    };
+
+    let db = Database::connect(&DbConfig {
+        url: "sqlite::memory:".to_string(),
+        ..Default::default()
+    })
+    .await?;
+    for heater in config.heaters.iter() {
+        db.upsert_heater(heater).await?;
+    }
+    let heaters = db.get_heaters().await?;
+    let db = Arc::new(Mutex::new(db));
+    let (request_tx, requests) = flume::unbounded();
+    let (_, eventloop) = AsyncClient::new(MqttOptions::new("simulation", "localhost", 1883), 10);
+    let control_config = ControlConfig {
+        dry_run: false,
+        ..config.control.clone()
+    };
+    let (controller, executor) = controller::create(
+        AsyncClient::from_senders(request_tx),
+        eventloop,
+        db.clone(),
+        &config.mqtt,
+        &control_config,
+    )
+    .await?;
+    let actions = controller
+        .actions()
+        .upgrade()
+        .context("The executor does not receive actions")?;
+    drop(controller);
+
+    tokio::time::pause();
+    let clock = Clock {
+        start,
+        started: Instant::now(),
+    };
+    tokio::spawn(run_clock(db, actions.downgrade(), requests.clone(), speed));
+    let relays = tokio::spawn(mock_relays(
+        requests,
+        actions.downgrade(),
+        heaters,
+        config.mqtt.topic_prefix.clone(),
+        clock,
+    ));
+    let executor = tokio::spawn(executor.run_until_completion(watch::channel(true).1));
+
+    // The control starts out disabled until it is enabled over MQTT.
+    actions.send(Action::EnableController(true)).await?;
+    for reading in readings {
+        tokio::time::sleep_until(clock.instant(reading.timestamp)).await;
+        let measurement = match reading.humidity {
+            Some(humidity) => Measurement::new(reading.temperature, humidity),
+            None => Measurement::from_temperature(reading.temperature),
+        };
+        if actions
+            .send(Action::RegisterMeasurement(reading.location, measurement))
+            .await
+            .is_err()
+        {
+            break;
+        }
+    }
+    drop(actions);
+
+    executor.await??;
+    Ok(relays.await?)
+}
+
+/// The time of the recording, given by the paused clock of the runtime.
+#[derive(Debug, Clone, Copy)]
+struct Clock {
+    start: NaiveDateTime,
+    started: Instant,
+}
+
+impl Clock {
+    /// The current time of the recording, to the second like the readings,
+    /// as the timers of the runtime fire up to a millisecond late.
+    fn now(&self) -> NaiveDateTime {
+        let now = self.start
+            + chrono::Duration::from_std(self.started.elapsed())
+                .unwrap_or(chrono::Duration::zero());
+        now.with_nanosecond(0).unwrap_or(now)
+    }
+
+    fn instant(&self, timestamp: NaiveDateTime) -> Instant {
+        self.started + (timestamp - self.start).to_std().unwrap_or_default()
+    }
+}
+
+/// Advance the paused clock by up to `CLOCK_STEP` at a time, `speed` times
+/// faster than real time, while the executor is idle. The runtime advances a
+/// paused clock to the next timer whenever it has nothing to do, which is
+/// also the case while the executor waits for SQLite, which runs on a thread
+/// of its own. So the clock is held until the database is unlocked and no
+/// actions or requests are queued. Stops once the executor no longer
+/// receives actions.
+async fn run_clock(
+    db: Arc<Mutex<Database>>,
+    actions: WeakSender<Action>,
+    requests: flume::Receiver<Request>,
+    speed: f64,
+) {
+    let is_idle = |actions: &Sender<Action>| {
+        db.try_lock().is_ok() && actions.capacity() == actions.max_capacity() && requests.is_empty()
+    };
+    loop {
+        let Some(sender) = actions.upgrade() else {
+            return;
+        };
+        if !is_idle(&sender) {
+            tokio::task::yield_now().await;
+            continue;
+        }
+        // Let the tasks that were just woken up start their work.
+        tokio::task::yield_now().await;
+        if !is_idle(&sender) {
+            continue;
+        }
+        drop(sender);
+
+        // Blocks the runtime, and with it the control, to pace the replay.
+        std::thread::sleep(CLOCK_STEP.div_f64(speed));
+        tokio::time::sleep(CLOCK_STEP).await;
+    }
+}
+
+/// Answer the requests of the executor like the relays of `heaters` would,
+/// which start out off and report every state they are commanded to, and log
+/// each change of state as a decision, until the executor disconnects.
+async fn mock_relays(
+    requests: flume::Receiver<Request>,
+    actions: WeakSender<Action>,
+    heaters: Vec<Heater>,
+    topic_prefix: String,
+    clock: Clock,
+) -> Vec<Decision> {
+    let mut states: HashMap<String, HeaterState> = HashMap::new();
+    let mut decisions = Vec::new();
+    while let Ok(request) = requests.recv_async().await {
+        let Request::Publish(publish) = request else {
+            continue;
+        };
+        let Some((heater, command)) = heaters.iter().find_map(|heater| {
+            relay_command(heater, &topic_prefix, &publish.topic, &publish.payload)
+                .map(|command| (heater, command))
+        }) else {
+            continue;
+        };
+
+        let current = states.get(heater.id()).copied().unwrap_or(HeaterState::Off);
+        let state = match command {
+            Some(state) if state != current => {
+                let decision = Decision {
+                    timestamp: clock.now(),
+                    heater_id: heater.id().clone(),
+                    state,
+                };
+                tracing::info!(
+                    timestamp = %decision.timestamp,
+                    heater_id = decision.heater_id,
+                    state = %decision.state,
+                    "Switched heater"
+                );
+                decisions.push(decision);
+                states.insert(heater.id().clone(), state);
+                state
+            }
+            Some(state) => state,
+            None => current,
+        };
+        let Some(actions) = actions.upgrade() else {
+            continue;
+        };
+        let _ = actions
+            .send(Action::RegisterHeaterStateChange(
+                heater.id().clone(),
+                state,
+            ))
+            .await;
+    }
+
+    decisions
+}
+
+/// The command to the relay of `heater` published to `topic`, which is the
+/// state it is switched to, or `None` when it is asked to report its state.
+fn relay_command(
+    heater: &Heater,
+    topic_prefix: &str,
+    topic: &[u8],
+    payload: &[u8],
+) -> Option<Option<HeaterState>> {
+    let firmware = heater.firmware();
+    let (request_topic, request_payload) = firmware.state_request(heater.id());
+    if topic == format!("{topic_prefix}{request_topic}").as_bytes() {
+        return (payload == request_payload.as_bytes()).then_some(None);
+    }
+    if topic != format!("{topic_prefix}{}", firmware.command_topic(heater.id())).as_bytes() {
+        return None;
+    }
+
+    [HeaterState::On, HeaterState::Off]
+        .into_iter()
+        .find(|state| payload == firmware.command_payload(*state).as_bytes())
+        .map(Some)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const HEATER_ID: &str = "C4402D";
+
+    const READINGS: &str = "\
+timestamp,location,temperature,humidity
+2024-01-05T07:20:00,inside,21.0,45.0
+2024-01-05T07:00:00,inside,18.0,45.0
+2024-01-05T07:10:00,inside,19.8,45.0
+2024-01-05T07:30:00,inside,20.2,45.0
+2024-01-05T07:40:00,inside,19.0,45.0
+";
+
+    fn timestamp(time: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(&format!("2024-01-05 {time}"), "%F %T").unwrap()
+    }
+
+    #[test]
+    fn parse_readings_in_order_of_timestamp() {
+        // Act
+        let readings = parse_readings(READINGS.as_bytes()).unwrap();
+
+        // Assert
+        assert_eq!(readings.len(), 5);
+        assert_eq!(
+            readings[0],
+            Reading {
+                timestamp: timestamp("07:00:00"),
+                location: "inside".to_string(),
+                temperature: 18.0,
+                humidity: Some(45.0),
+            }
+        );
+        assert!(readings
+            .windows(2)
+            .all(|pair| pair[0].timestamp <= pair[1].timestamp));
+        assert!(parse_readings("timestamp,location\nyesterday,inside\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn parse_readings_without_humidity() {
+        let readings = parse_readings(
+            "timestamp,location,temperature,humidity\n2024-01-05T07:00:00,garage,12.5,\n"
+                .as_bytes(),
+        )
+        .unwrap();
+
+        assert_eq!(readings[0].humidity, None);
+    }
+
+    #[tokio::test]
+    async fn replay_switches_heater_by_the_readings() {
+        // Arrange
+        let config = Config {
+            heaters: vec![Heater::new(
+                HEATER_ID.to_string(),
+                "Spisebord".to_string(),
+                "inside".to_string(),
+            )],
+            ..Config::default()
+        };
+        let readings = parse_readings(READINGS.as_bytes()).unwrap();
+
+        // Act
+        let decisions = replay(&config, readings, f64::INFINITY).await.unwrap();
+
+        // Assert
+        let switches: Vec<_> = decisions
+            .iter()
+            .filter(|decision| decision.heater_id == HEATER_ID)
+            .map(|decision| (decision.timestamp, decision.state))
+            .collect();
+        assert_eq!(
+            switches,
+            vec![
+                (timestamp("07:00:00"), HeaterState::On),
+                (timestamp("07:20:00"), HeaterState::Off),
+                (timestamp("07:40:00"), HeaterState::On),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn replay_defers_switch_by_the_minimum_dwell_time() {
+        // Arrange
+        let config = Config {
+            heaters: vec![Heater::new(
+                HEATER_ID.to_string(),
+                "Spisebord".to_string(),
+                "inside".to_string(),
+            )],
+            ..Config::default()
+        };
+        let readings = parse_readings(
+            "\
+timestamp,location,temperature,humidity
+2024-01-05T07:00:00,inside,18.0,45.0
+2024-01-05T07:00:30,inside,21.0,45.0
+2024-01-05T07:10:00,inside,21.0,45.0
+"
+            .as_bytes(),
+        )
+        .unwrap();
+
+        // Act
+        let decisions = replay(&config, readings, f64::INFINITY).await.unwrap();
+
+        // Assert
+        let switches: Vec<_> = decisions
+            .iter()
+            .filter(|decision| decision.heater_id == HEATER_ID)
+            .map(|decision| (decision.timestamp, decision.state))
+            .collect();
+        assert_eq!(
+            switches,
+            vec![
+                (timestamp("07:00:00"), HeaterState::On),
+                (timestamp("07:02:00"), HeaterState::Off),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn replay_rejects_speed_that_is_not_positive() {
+        let readings = parse_readings(READINGS.as_bytes()).unwrap();
+
+        assert!(replay(&Config::default(), readings, 0.0).await.is_err());
+    }
+}
@@ -5,26 +5,178 @@ use tracing_subscriber::{
     registry::LookupSpan, Registry,
 };
 
-/// Setup telemetry and output it to a given sink.
+/// Log level used for the application itself when `LOG_LEVEL` is not set.
+pub const DEFAULT_LOG_LEVEL: Level = Level::DEBUG;
+
+/// Format of the log output.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, strum::EnumString)]
+#[strum(serialize_all = "lowercase", ascii_case_insensitive)]
+pub enum LogFormat {
+    /// Bunyan formatted JSON, used in production.
+    #[default]
+    Json,
+    /// Human readable lines, for tailing the output during development.
+    Pretty,
+}
+
+/// Read the log format from the `LOG_FORMAT` environment variable. Returns
+/// the invalid value as the error if it cannot be parsed.
+pub fn log_format_from_env() -> Result<LogFormat, String> {
+    parse_log_format(std::env::var("LOG_FORMAT").ok().as_deref())
+}
+
+fn parse_log_format(value: Option<&str>) -> Result<LogFormat, String> {
+    match value {
+        Some(value) => value.parse::<LogFormat>().map_err(|_| value.to_string()),
+        None => Ok(LogFormat::default()),
+    }
+}
+
+/// Read the log level for the application from the `LOG_LEVEL` environment
+/// variable. Returns the invalid value as the error if it cannot be parsed.
+pub fn log_level_from_env() -> Result<Level, String> {
+    parse_log_level(std::env::var("LOG_LEVEL").ok().as_deref())
+}
+
+fn parse_log_level(value: Option<&str>) -> Result<Level, String> {
+    match value {
+        Some(value) => value.parse::<Level>().map_err(|_| value.to_string()),
+        None => Ok(DEFAULT_LOG_LEVEL),
+    }
+}
+
+/// Setup telemetry and output it to a given sink in the given format. Events
+/// from the application are logged from `level`, while everything else only
+/// from `WARN`.
 pub fn create_minimal_subscriber<Sink>(
     name: String,
+    level: Level,
+    format: LogFormat,
     sink: Sink,
 ) -> impl Subscriber + Send + Sync + for<'span> LookupSpan<'span>
 where
     Sink: for<'a> MakeWriter<'a> + Send + Sync + 'static,
 {
     let filter = Targets::new()
-        .with_target(&name, Level::DEBUG)
+        .with_target(&name, level)
         .with_default(Level::WARN);
 
-    let formatting_layer = BunyanFormattingLayer::new(name, sink);
+    let (bunyan_layer, pretty_layer) = match format {
+        LogFormat::Json => (Some(BunyanFormattingLayer::new(name, sink)), None),
+        LogFormat::Pretty => (
+            None,
+            Some(tracing_subscriber::fmt::layer().with_writer(sink)),
+        ),
+    };
 
     Registry::default()
         .with(filter)
-        .with(JsonStorageLayer)
-        .with(formatting_layer)
+        .with(bunyan_layer.is_some().then_some(JsonStorageLayer))
+        .with(bunyan_layer)
+        .with(pretty_layer)
 }
 
 pub fn init_subscriber(subscriber: impl Subscriber + Send + Sync) {
     set_global_default(subscriber).expect("Failed to setup log subscriber");
 }
+
+#[cfg(test)]
+pub mod test {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    #[test]
+    fn parse_log_level_defaults_when_unset() {
+        assert_eq!(parse_log_level(None), Ok(DEFAULT_LOG_LEVEL));
+    }
+
+    #[test]
+    fn parse_log_level_accepts_any_case() {
+        assert_eq!(parse_log_level(Some("trace")), Ok(Level::TRACE));
+        assert_eq!(parse_log_level(Some("INFO")), Ok(Level::INFO));
+    }
+
+    #[test]
+    fn parse_log_level_rejects_invalid_value() {
+        assert_eq!(parse_log_level(Some("loud")), Err("loud".to_string()));
+    }
+
+    #[test]
+    fn parse_log_format_defaults_to_json() {
+        assert_eq!(parse_log_format(None), Ok(LogFormat::Json));
+    }
+
+    #[test]
+    fn parse_log_format_accepts_known_formats() {
+        assert_eq!(parse_log_format(Some("json")), Ok(LogFormat::Json));
+        assert_eq!(parse_log_format(Some("Pretty")), Ok(LogFormat::Pretty));
+        assert_eq!(parse_log_format(Some("xml")), Err("xml".to_string()));
+    }
+
+    /// Sink collecting the log output in memory.
+    #[derive(Clone, Default)]
+    pub struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Buffer {
+        /// The output logged so far.
+        pub fn contents(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    impl std::io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'a> MakeWriter<'a> for Buffer {
+        type Writer = Self;
+
+        fn make_writer(&'a self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
+    fn log_with_format(format: LogFormat) -> String {
+        let buffer = Buffer::default();
+        let subscriber =
+            create_minimal_subscriber("app".to_string(), Level::INFO, format, buffer.clone());
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!(target: "app", heater = "C4402D", "Heater turned on");
+            tracing::debug!(target: "app", "Filtered by level");
+            tracing::info!(target: "other", "Filtered by target");
+        });
+
+        buffer.contents()
+    }
+
+    #[test]
+    fn json_format_logs_bunyan_lines() {
+        let output = log_with_format(LogFormat::Json);
+
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 1);
+        let line: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(line["msg"], "Heater turned on");
+        assert_eq!(line["heater"], "C4402D");
+    }
+
+    #[test]
+    fn pretty_format_logs_plain_lines() {
+        let output = log_with_format(LogFormat::Pretty);
+
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].contains("Heater turned on"));
+        assert!(lines[0].contains("C4402D"));
+        assert!(serde_json::from_str::<serde_json::Value>(lines[0]).is_err());
+    }
+}
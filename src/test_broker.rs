@@ -0,0 +1,332 @@
+//! A minimal MQTT broker for tests, so the flow from a published message to
+//! the database can be tested without an external broker.
+//!
+//! Published messages are forwarded with QoS 0 to the clients subscribed to a
+//! matching filter. Retained messages, sessions, and authentication are not
+//! supported.
+
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use bytes::BytesMut;
+use rumqttc::v5::mqttbytes::{
+    matches,
+    v5::{
+        ConnAck, ConnectReturnCode, Packet, PingResp, PubAck, PubComp, PubRec, Publish, SubAck,
+        SubscribeReasonCode,
+    },
+    Error, QoS,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::mpsc::{unbounded_channel, UnboundedSender},
+    task::JoinHandle,
+};
+
+use crate::controller::MqttConfig;
+
+/// How often `subscribed` checks for the subscription.
+const SUBSCRIPTION_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A subscription of a connected client.
+#[derive(Debug)]
+struct Subscription {
+    filter: String,
+    tx: UnboundedSender<Packet>,
+}
+
+type Subscriptions = Arc<Mutex<Vec<Subscription>>>;
+
+/// A broker listening on a random local port, which stops when dropped.
+#[derive(Debug)]
+pub struct TestBroker {
+    address: SocketAddr,
+    subscriptions: Subscriptions,
+    task: JoinHandle<()>,
+}
+
+impl TestBroker {
+    /// Start the broker and accept connections in the background.
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Failed to bind test broker");
+        let address = listener.local_addr().expect("Test broker has no address");
+        let subscriptions = Subscriptions::default();
+        let task = tokio::spawn(accept(listener, subscriptions.clone()));
+
+        Self {
+            address,
+            subscriptions,
+            task,
+        }
+    }
+
+    /// The configuration of a client connecting to the broker.
+    pub fn mqtt_config(&self, client_id: &str) -> MqttConfig {
+        let mut config = MqttConfig::default();
+        config.host = self.address.ip().to_string();
+        config.port = self.address.port();
+        config.client_id = client_id.to_string();
+        config
+    }
+
+    /// Wait until a client has subscribed to `filter`, as messages published
+    /// before are not delivered to it.
+    pub async fn subscribed(&self, filter: &str) {
+        loop {
+            let subscribed = self
+                .subscriptions
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|subscription| subscription.filter == filter);
+            if subscribed {
+                return;
+            }
+            tokio::time::sleep(SUBSCRIPTION_POLL_INTERVAL).await;
+        }
+    }
+}
+
+impl Drop for TestBroker {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Serve each client connecting to `listener` in its own task.
+async fn accept(listener: TcpListener, subscriptions: Subscriptions) {
+    while let Ok((stream, _)) = listener.accept().await {
+        let subscriptions = subscriptions.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve(stream, subscriptions).await {
+                tracing::debug!(error = %e, "Test broker connection failed");
+            }
+        });
+    }
+}
+
+/// Read the packets of a client and write the packets sent to it, until it
+/// disconnects.
+async fn serve(stream: TcpStream, subscriptions: Subscriptions) -> Result<()> {
+    let (mut reader, mut writer) = stream.into_split();
+    let (tx, mut rx) = unbounded_channel();
+    let mut incoming = BytesMut::new();
+    loop {
+        tokio::select! {
+            read = reader.read_buf(&mut incoming) => {
+                if read? == 0 {
+                    return Ok(());
+                }
+                loop {
+                    match Packet::read(&mut incoming, None) {
+                        Ok(Packet::Disconnect(_)) => return Ok(()),
+                        Ok(packet) => handle(packet, &tx, &subscriptions),
+                        Err(Error::InsufficientBytes(_)) => break,
+                        Err(e) => return Err(anyhow!("Failed to read packet: {e:?}")),
+                    }
+                }
+            }
+            Some(packet) = rx.recv() => {
+                let mut outgoing = BytesMut::new();
+                packet
+                    .write(&mut outgoing)
+                    .map_err(|e| anyhow!("Failed to write packet: {e:?}"))?;
+                writer.write_all(&outgoing).await?;
+            }
+        }
+    }
+}
+
+/// Respond to a packet of the client sending to `tx`, and forward its
+/// publishes to the subscribers.
+fn handle(packet: Packet, tx: &UnboundedSender<Packet>, subscriptions: &Subscriptions) {
+    let response = match packet {
+        Packet::Connect(..) => Packet::ConnAck(ConnAck {
+            session_present: false,
+            code: ConnectReturnCode::Success,
+            properties: None,
+        }),
+        Packet::Subscribe(subscribe) => {
+            let mut subscriptions = subscriptions.lock().unwrap();
+            let return_codes = subscribe
+                .filters
+                .into_iter()
+                .map(|filter| {
+                    subscriptions.push(Subscription {
+                        filter: filter.path,
+                        tx: tx.clone(),
+                    });
+                    SubscribeReasonCode::Success(filter.qos)
+                })
+                .collect();
+            Packet::SubAck(SubAck {
+                pkid: subscribe.pkid,
+                return_codes,
+                properties: None,
+            })
+        }
+        Packet::Publish(publish) => {
+            let topic = String::from_utf8_lossy(&publish.topic).to_string();
+            let mut subscriptions = subscriptions.lock().unwrap();
+            subscriptions.retain(|subscription| !subscription.tx.is_closed());
+            for subscription in subscriptions.iter() {
+                if matches(&topic, &subscription.filter) {
+                    let forwarded = Publish::new(
+                        topic.as_str(),
+                        QoS::AtMostOnce,
+                        publish.payload.clone(),
+                        None,
+                    );
+                    // The subscriber may have disconnected since.
+                    let _ = subscription.tx.send(Packet::Publish(forwarded));
+                }
+            }
+            match publish.qos {
+                QoS::AtMostOnce => return,
+                QoS::AtLeastOnce => Packet::PubAck(PubAck::new(publish.pkid, None)),
+                QoS::ExactlyOnce => Packet::PubRec(PubRec::new(publish.pkid, None)),
+            }
+        }
+        Packet::PubRel(release) => Packet::PubComp(PubComp::new(release.pkid, None)),
+        Packet::PingReq(_) => Packet::PingResp(PingResp),
+        _ => return,
+    };
+    // Only fails when the connection is closing.
+    let _ = tx.send(response);
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::sync::watch;
+
+    use super::*;
+    use crate::{
+        controller::{self, ControlConfig, Event},
+        db::{Database, DbConfig},
+    };
+
+    /// Time the end-to-end flow may take before the test fails.
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    #[tokio::test]
+    async fn broker_forwards_publish_to_subscriber() {
+        // Arrange
+        let broker = TestBroker::start().await;
+        let (subscriber, mut subscriber_loop) =
+            controller::create_mqtt_handler(&broker.mqtt_config("subscriber")).unwrap();
+        subscriber
+            .subscribe("measurement/#", QoS::AtLeastOnce)
+            .await
+            .unwrap();
+        let (publisher, mut publisher_loop) =
+            controller::create_mqtt_handler(&broker.mqtt_config("publisher")).unwrap();
+        tokio::spawn(async move { while publisher_loop.poll().await.is_ok() {} });
+
+        // Act
+        let received = tokio::time::timeout(TIMEOUT, async {
+            loop {
+                match subscriber_loop.poll().await.unwrap() {
+                    rumqttc::v5::Event::Incoming(Packet::SubAck(_)) => publisher
+                        .publish("measurement/garage", QoS::AtLeastOnce, false, "42")
+                        .await
+                        .unwrap(),
+                    rumqttc::v5::Event::Incoming(Packet::Publish(publish)) => return publish,
+                    _ => {}
+                }
+            }
+        })
+        .await
+        .unwrap();
+
+        // Assert
+        assert_eq!(received.topic, "measurement/garage");
+        assert_eq!(received.payload, "42");
+    }
+
+    #[tokio::test]
+    async fn published_measurement_is_stored() {
+        // Arrange
+        let broker = TestBroker::start().await;
+        let mqtt_config = broker.mqtt_config("hub");
+        let db = Database::connect(&DbConfig {
+            url: "sqlite::memory:".to_string(),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let db = Arc::new(tokio::sync::Mutex::new(db));
+        let (mqtt_client, mqtt_eventloop) = controller::create_mqtt_handler(&mqtt_config).unwrap();
+        // No relays are connected to report the states of the seeded heaters.
+        let control_config = ControlConfig {
+            relay_state_timeout_secs: 0,
+            ..Default::default()
+        };
+        let (controller, executor) = controller::create(
+            mqtt_client,
+            mqtt_eventloop,
+            db.clone(),
+            &mqtt_config,
+            &control_config,
+        )
+        .await
+        .unwrap();
+        let mut events = executor.events().subscribe();
+        let (shutdown_tx, shutdown) = watch::channel(false);
+        let executor_task = tokio::spawn(executor.run_until_completion(shutdown.clone()));
+        let controller_task = tokio::spawn(controller.run_until_completion(shutdown));
+        let (sensor, mut sensor_loop) =
+            controller::create_mqtt_handler(&broker.mqtt_config("sensor")).unwrap();
+        tokio::spawn(async move { while sensor_loop.poll().await.is_ok() {} });
+        tokio::time::timeout(TIMEOUT, broker.subscribed("measurement/#"))
+            .await
+            .unwrap();
+
+        // Act
+        sensor
+            .publish(
+                "measurement/inside",
+                QoS::AtLeastOnce,
+                false,
+                r#"{"temperature":21.5,"humidity":45.0}"#,
+            )
+            .await
+            .unwrap();
+        tokio::time::timeout(TIMEOUT, async {
+            while !matches!(
+                events.recv().await.unwrap(),
+                Event::Reading { place, .. } if place == "inside"
+            ) {}
+        })
+        .await
+        .unwrap();
+        shutdown_tx.send(true).unwrap();
+        tokio::time::timeout(TIMEOUT, executor_task)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        tokio::time::timeout(TIMEOUT, controller_task)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+
+        // Assert
+        let reading = db
+            .lock()
+            .await
+            .get_latest_reading("inside")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(*reading.temperature(), 21.5);
+        assert_eq!(*reading.humidity(), Some(45.0));
+    }
+}